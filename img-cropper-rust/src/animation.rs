@@ -0,0 +1,305 @@
+//! Animated images, decoded into frames that are cropped one by one.
+
+use crate::{
+    encode::{self, EncodeSettings, Metadata},
+    icc, quantize,
+};
+use image::{
+    codecs::{
+        gif::{GifDecoder, GifEncoder, Repeat},
+        png::PngDecoder,
+        webp::WebPDecoder,
+    },
+    metadata::Orientation,
+    AnimationDecoder, Delay, DynamicImage, Frames, ImageDecoder, ImageFormat, RgbaImage,
+};
+use std::{
+    fs::{self, File},
+    io::{BufReader, BufWriter},
+    path::Path,
+};
+
+/// GIF quantization speed (1-30). The image crate's default of 1 is too slow
+/// for animations with many frames.
+const GIF_SPEED: i32 = 10;
+
+/// One frame of an animation, composited onto the full canvas.
+#[derive(Clone)]
+pub struct Frame {
+    pub image: DynamicImage,
+    /// How long the frame is shown; zero for still images
+    pub delay: Delay,
+}
+
+impl Frame {
+    pub fn still(image: DynamicImage) -> Frame {
+        Frame {
+            image,
+            delay: Delay::from_numer_denom_ms(0, 1),
+        }
+    }
+}
+
+/// Applies `f` to every frame's image, keeping the frame delays.
+pub fn map(frames: &[Frame], f: impl Fn(&DynamicImage) -> DynamicImage) -> Vec<Frame> {
+    frames
+        .iter()
+        .map(|frame| Frame {
+            image: f(&frame.image),
+            delay: frame.delay,
+        })
+        .collect()
+}
+
+/// Whether an animation can be written to `path`.
+pub fn supports_animation(path: &Path) -> bool {
+    matches!(
+        ImageFormat::from_path(path),
+        Ok(ImageFormat::Gif | ImageFormat::WebP | ImageFormat::Png)
+    )
+}
+
+/// Decodes every frame of the GIF, WebP or APNG animation at `path`. Still
+/// WebP and PNG images come back as a single frame.
+pub fn decode(path: &Path) -> Result<(Vec<Frame>, Metadata), Box<dyn std::error::Error>> {
+    let reader = BufReader::new(File::open(path)?);
+    let (frames, metadata, orientation) = match ImageFormat::from_path(path)? {
+        ImageFormat::WebP => {
+            let mut decoder = WebPDecoder::new(reader)?;
+            if !decoder.has_animation() {
+                return decode_still(decoder);
+            }
+            let (metadata, orientation) = read_metadata(&mut decoder);
+            (decoder.into_frames(), metadata, orientation)
+        }
+        ImageFormat::Png => {
+            let mut decoder = PngDecoder::new(reader)?;
+            if !decoder.is_apng()? {
+                return decode_still(decoder);
+            }
+            let (metadata, orientation) = read_metadata(&mut decoder);
+            (decoder.apng()?.into_frames(), metadata, orientation)
+        }
+        // GIF has no EXIF
+        _ => (GifDecoder::new(reader)?.into_frames(), Metadata::default(), Orientation::NoTransforms),
+    };
+    Ok((collect_frames(frames, orientation, path)?, metadata))
+}
+
+/// Decodes a still image as a single upright frame.
+pub fn decode_still(mut decoder: impl ImageDecoder) -> Result<(Vec<Frame>, Metadata), Box<dyn std::error::Error>> {
+    let (metadata, orientation) = read_metadata(&mut decoder);
+    let mut image = DynamicImage::from_decoder(decoder)?;
+    image.apply_orientation(orientation);
+    Ok((vec![Frame::still(image)], metadata))
+}
+
+/// Reads the metadata `decoder` exposes and the orientation its pixels need.
+/// Unreadable metadata is dropped rather than failing the whole image.
+pub(crate) fn read_metadata(decoder: &mut impl ImageDecoder) -> (Metadata, Orientation) {
+    let orientation = decoder.orientation().unwrap_or(Orientation::NoTransforms);
+    let mut exif = decoder.exif_metadata().unwrap_or(None);
+    // The pixels are turned upright on decode, so viewers mustn't rotate them again
+    if let Some(exif) = &mut exif {
+        let _ = Orientation::remove_from_exif_chunk(exif);
+    }
+    let icc = decoder.icc_profile().unwrap_or(None);
+    (Metadata { exif, icc }, orientation)
+}
+
+fn collect_frames(
+    frames: Frames,
+    orientation: Orientation,
+    path: &Path,
+) -> Result<Vec<Frame>, Box<dyn std::error::Error>> {
+    let frames = frames
+        .map(|frame| {
+            let frame = frame?;
+            let delay = frame.delay();
+            let mut image = DynamicImage::ImageRgba8(frame.into_buffer());
+            image.apply_orientation(orientation);
+            Ok(Frame { image, delay })
+        })
+        .collect::<Result<Vec<_>, image::ImageError>>()?;
+
+    if frames.is_empty() {
+        return Err(format!("{:?} has no frames", path).into());
+    }
+    Ok(frames)
+}
+
+/// Encodes `frames` as an animation that loops forever, in the format
+/// implied by `path`'s extension.
+pub fn save(
+    frames: Vec<Frame>,
+    path: &Path,
+    settings: &EncodeSettings,
+    metadata: &Metadata,
+) -> Result<(), Box<dyn std::error::Error>> {
+    match ImageFormat::from_path(path)? {
+        ImageFormat::WebP => save_webp(&frames, path, settings, metadata),
+        ImageFormat::Png => save_apng(&frames, path, settings, metadata),
+        _ => save_gif(frames, path, settings, metadata),
+    }
+}
+
+/// GIF has no color profiles, so profiled frames are converted to sRGB.
+fn save_gif(
+    frames: Vec<Frame>,
+    path: &Path,
+    settings: &EncodeSettings,
+    metadata: &Metadata,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let writer = BufWriter::new(File::create(path)?);
+    let mut encoder = GifEncoder::new_with_speed(writer, GIF_SPEED);
+    encoder.set_repeat(Repeat::Infinite)?;
+    encoder.encode_frames(frames.into_iter().map(|frame| {
+        let image = match &metadata.icc {
+            Some(icc) => icc::to_srgb(frame.image, icc),
+            None => frame.image,
+        };
+        image::Frame::from_parts(reduced(&image, settings), 0, 0, frame.delay)
+    }))?;
+    Ok(())
+}
+
+/// The RGBA pixels of `image`, limited to `settings.colors` colors if set.
+/// Each frame gets a palette of its own.
+fn reduced(image: &DynamicImage, settings: &EncodeSettings) -> RgbaImage {
+    match settings.colors {
+        Some(colors) => quantize::reduce(&image.to_rgba8(), colors, settings.dither),
+        None => image.to_rgba8(),
+    }
+}
+
+/// Encodes `frames` with libwebp, using the same quality settings as still WebP.
+fn save_webp(
+    frames: &[Frame],
+    path: &Path,
+    settings: &EncodeSettings,
+    metadata: &Metadata,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let mut config = webp::WebPConfig::new().map_err(|_| "Failed to configure the WebP encoder")?;
+    match settings.webp_quality {
+        Some(quality) => config.quality = quality,
+        None => config.lossless = 1,
+    }
+
+    let (width, height) = (frames[0].image.width(), frames[0].image.height());
+    let pixels: Vec<Vec<u8>> = frames
+        .iter()
+        .map(|frame| frame.image.to_rgba8().into_raw())
+        .collect();
+    let mut encoder = webp::AnimEncoder::new(width, height, &config);
+    encoder.set_loop_count(0);
+
+    // libwebp takes each frame's start time rather than its delay
+    let mut timestamp = 0;
+    for (frame, pixels) in frames.iter().zip(&pixels) {
+        encoder.add_frame(webp::AnimFrame::from_rgba(pixels, width, height, timestamp));
+        let (numerator, denominator) = frame.delay.numer_denom_ms();
+        timestamp += (numerator / denominator.max(1)) as i32;
+    }
+
+    let mut encoded = encoder
+        .try_encode()
+        .map_err(|e| format!("WebP encoding failed: {:?}", e))?
+        .to_vec();
+    set_last_webp_duration(&mut encoded, timestamp.max(0) as u32);
+    fs::write(path, encode::add_webp_metadata(encoded, metadata, (width, height), true))?;
+    Ok(())
+}
+
+/// The webp crate ends animations without a timestamp, so libwebp gives the
+/// last frame the average duration. Rewrites that frame's duration so the
+/// animation runs for `total_ms`.
+fn set_last_webp_duration(data: &mut [u8], total_ms: u32) {
+    // Offsets of each ANMF chunk's 24-bit duration field
+    let mut durations = Vec::new();
+    let mut offset = 12;
+    while offset + 8 <= data.len() {
+        let size = u32::from_le_bytes([
+            data[offset + 4],
+            data[offset + 5],
+            data[offset + 6],
+            data[offset + 7],
+        ]) as usize;
+        if &data[offset..offset + 4] == b"ANMF" && size >= 16 && offset + 24 <= data.len() {
+            durations.push(offset + 20);
+        }
+        offset += 8 + size + size % 2;
+    }
+
+    let read = |at: usize| u32::from_le_bytes([data[at], data[at + 1], data[at + 2], 0]);
+    if let Some((&last, rest)) = durations.split_last() {
+        let elapsed: u32 = rest.iter().map(|&at| read(at)).sum();
+        let duration = total_ms.saturating_sub(elapsed).min(0xFF_FFFF);
+        data[last..last + 3].copy_from_slice(&duration.to_le_bytes()[..3]);
+    }
+}
+
+/// Encodes `frames` as APNG, using the same compression settings as still PNG.
+fn save_apng(
+    frames: &[Frame],
+    path: &Path,
+    settings: &EncodeSettings,
+    metadata: &Metadata,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let (width, height) = (frames[0].image.width(), frames[0].image.height());
+    let mut info = png::Info::with_size(width, height);
+    info.exif_metadata = metadata.exif.clone().map(Into::into);
+    info.icc_profile = metadata.icc.clone().map(Into::into);
+    let mut encoder = png::Encoder::with_info(BufWriter::new(File::create(path)?), info)?;
+    encoder.set_color(png::ColorType::Rgba);
+    encoder.set_depth(png::BitDepth::Eight);
+    encoder.set_compression(encode::png_compression(settings.png_compression));
+    encoder.set_filter(encode::png_filter(settings.png_filter));
+    encoder.set_animated(frames.len() as u32, 0)?;
+
+    let mut writer = encoder.write_header()?;
+    for frame in frames {
+        // APNG delays are fractions of a second with 16-bit parts, so round
+        // to whole milliseconds when the exact fraction doesn't fit
+        let (numerator, denominator) = frame.delay.numer_denom_ms();
+        let (numerator, denominator) =
+            match (u16::try_from(numerator), u16::try_from(denominator as u64 * 1000)) {
+                (Ok(numerator), Ok(denominator)) => (numerator, denominator),
+                _ => ((numerator / denominator.max(1)).min(u16::MAX as u32) as u16, 1000),
+            };
+        writer.set_frame_delay(numerator, denominator)?;
+        writer.write_image_data(reduced(&frame.image, settings).as_raw())?;
+    }
+    writer.finish()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::Rgba;
+
+    #[test]
+    fn webp_keeps_every_frame_duration() {
+        let path = std::env::temp_dir().join(format!("img-cropper-test-{}.webp", std::process::id()));
+        let delays = [100, 250, 650];
+        // Distinct colors, since libwebp merges identical frames
+        let frames: Vec<_> = delays
+            .iter()
+            .enumerate()
+            .map(|(index, &delay)| Frame {
+                image: DynamicImage::ImageRgba8(RgbaImage::from_pixel(6, 4, Rgba([index as u8 * 100, 0, 0, 255]))),
+                delay: Delay::from_numer_denom_ms(delay, 1),
+            })
+            .collect();
+        let metadata = Metadata { exif: None, icc: Some(vec![7; 41]) };
+        save(frames, &path, &EncodeSettings::default(), &metadata).unwrap();
+
+        let (decoded, decoded_metadata) = decode(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        let decoded_delays: Vec<_> = decoded.iter().map(|frame| frame.delay.numer_denom_ms()).collect();
+        assert_eq!(decoded_delays, delays.map(|delay| (delay, 1)));
+        assert_eq!(decoded_metadata.icc, metadata.icc);
+        // The last frame is still the last one, give or take libwebp's rounding
+        assert!(decoded[2].image.to_rgba8().get_pixel(0, 0)[0].abs_diff(200) <= 2);
+    }
+}
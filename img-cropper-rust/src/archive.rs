@@ -0,0 +1,172 @@
+//! ZIP archives, read from when given as input directories and written to
+//! with `--output-zip`. Images are copied out of an input archive one at a
+//! time as they're needed, so it's never extracted whole; S3 input folders
+//! are read the same way.
+
+use crate::{lowercase_extension, CropError};
+use std::{
+    cell::RefCell,
+    collections::{hash_map::Entry, HashMap},
+    ffi::OsStr,
+    fs::{self, File},
+    io::{self, BufReader, BufWriter},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Mutex,
+    },
+};
+use zip::{result::ZipError, write::SimpleFileOptions, CompressionMethod, ZipArchive, ZipWriter};
+
+/// Numbers the scratch folders of the extracted images.
+static EXTRACTED_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+/// Whether the input directory `path` is actually a ZIP archive.
+pub(crate) fn is_archive(path: &Path) -> bool {
+    lowercase_extension(path) == "zip" && path.is_file()
+}
+
+/// What an archive's index or an S3 listing says about a file in it, known
+/// without copying it out.
+pub(crate) struct Listed {
+    pub(crate) size: u64,
+    /// Changes along with the contents: the CRC-32 of an archive entry, or
+    /// the ETag of an S3 object
+    pub(crate) checksum: Option<String>,
+}
+
+/// The files in `archive`, relative to its root and sorted by name. Entries
+/// whose names would point outside the archive are left out.
+pub(crate) fn list_files(archive: &Path) -> Result<Vec<(PathBuf, Listed)>, CropError> {
+    let mut zip = open(archive)?;
+    let mut files = Vec::new();
+    for index in 0..zip.len() {
+        let entry = zip.by_index(index).map_err(|e| zip_error(archive, e))?;
+        if let Some(name) = entry.enclosed_name().filter(|_| entry.is_file()) {
+            let listed = Listed { size: entry.size(), checksum: Some(format!("crc32:{:08x}", entry.crc32())) };
+            files.push((name, listed));
+        }
+    }
+    files.sort_by(|(a, _), (b, _)| a.cmp(b));
+    Ok(files)
+}
+
+/// A file to read an input image from: the image itself, or a copy of an
+/// archived one that's removed when dropped.
+pub(crate) struct LocalFile {
+    path: PathBuf,
+    scratch_dir: Option<PathBuf>,
+}
+
+impl LocalFile {
+    pub(crate) fn existing(path: &Path) -> LocalFile {
+        LocalFile { path: path.to_path_buf(), scratch_dir: None }
+    }
+
+    /// Copies the file at `entry` out of `archive` into a scratch folder of
+    /// its own, keeping its name. Each thread opens the archive once, rather
+    /// than reading its index again for every entry.
+    pub(crate) fn extract(archive: &Path, entry: &Path) -> Result<LocalFile, CropError> {
+        thread_local! {
+            static OPEN: RefCell<HashMap<PathBuf, ZipArchive<BufReader<File>>>> = RefCell::new(HashMap::new());
+        }
+        OPEN.with_borrow_mut(|open_archives| {
+            let zip = match open_archives.entry(archive.to_path_buf()) {
+                Entry::Occupied(zip) => zip.into_mut(),
+                Entry::Vacant(zip) => zip.insert(open(archive)?),
+            };
+            let name = entry.iter().map(|part| part.to_string_lossy()).collect::<Vec<_>>().join("/");
+            let index = zip
+                .index_for_name(&name)
+                .ok_or_else(|| CropError::Other(format!("{:?} isn't in {:?}", entry, archive)))?;
+            let mut reader = zip.by_index(index).map_err(|e| zip_error(archive, e))?;
+            let (local, mut file) = LocalFile::create(entry.file_name().unwrap_or_default())?;
+            io::copy(&mut reader, &mut file)?;
+            Ok(local)
+        })
+    }
+
+    /// An empty file named `file_name` in a new scratch folder, to copy an
+    /// input image into.
+    pub(crate) fn create(file_name: &OsStr) -> io::Result<(LocalFile, File)> {
+        let scratch_dir = std::env::temp_dir().join(format!(
+            "img-cropper-input-{}-{}",
+            std::process::id(),
+            EXTRACTED_COUNT.fetch_add(1, Ordering::Relaxed)
+        ));
+        let local = LocalFile {
+            path: scratch_dir.join(file_name),
+            scratch_dir: Some(scratch_dir.clone()),
+        };
+        fs::create_dir_all(&scratch_dir)?;
+        let file = File::create(&local.path)?;
+        Ok((local, file))
+    }
+
+    pub(crate) fn path(&self) -> &Path {
+        &self.path
+    }
+}
+
+impl Drop for LocalFile {
+    fn drop(&mut self) {
+        if let Some(scratch_dir) = &self.scratch_dir {
+            let _ = fs::remove_dir_all(scratch_dir);
+        }
+    }
+}
+
+/// An archive being filled with outputs as they're written.
+pub struct ArchiveWriter {
+    path: PathBuf,
+    zip: Mutex<ZipWriter<BufWriter<File>>>,
+}
+
+impl ArchiveWriter {
+    /// Creates the archive at `path`, replacing any file already there.
+    pub fn create(path: &Path) -> Result<ArchiveWriter, CropError> {
+        Ok(ArchiveWriter {
+            path: path.to_path_buf(),
+            zip: Mutex::new(ZipWriter::new(BufWriter::new(File::create(path)?))),
+        })
+    }
+
+    /// Adds the file at `file` to the archive as `name`, a path relative to
+    /// the archive's root. Formats that are compressed already are stored
+    /// as they are.
+    pub fn add(&self, name: &Path, file: &Path) -> Result<(), CropError> {
+        let name = name.iter().map(|part| part.to_string_lossy()).collect::<Vec<_>>().join("/");
+        let method = match lowercase_extension(file).as_str() {
+            "bmp" | "tif" | "tiff" => CompressionMethod::Deflated,
+            _ => CompressionMethod::Stored,
+        };
+        let mut source = File::open(file)?;
+        let options = SimpleFileOptions::default()
+            .compression_method(method)
+            .large_file(source.metadata()?.len() >= u32::MAX as u64);
+        let mut zip = self.zip.lock().unwrap_or_else(|e| e.into_inner());
+        zip.start_file(name, options).map_err(|e| zip_error(&self.path, e))?;
+        io::copy(&mut source, &mut *zip)?;
+        Ok(())
+    }
+
+    /// Writes the archive's index, without which it can't be read.
+    pub fn finish(self) -> Result<(), CropError> {
+        let zip = self.zip.into_inner().unwrap_or_else(|e| e.into_inner());
+        let mut file = zip.finish().map_err(|e| zip_error(&self.path, e))?;
+        io::Write::flush(&mut file)?;
+        Ok(())
+    }
+}
+
+fn open(archive: &Path) -> Result<ZipArchive<BufReader<File>>, CropError> {
+    let file = File::open(archive)?;
+    ZipArchive::new(BufReader::new(file)).map_err(|e| zip_error(archive, e))
+}
+
+fn zip_error(archive: &Path, error: ZipError) -> CropError {
+    match error {
+        ZipError::Io(e) => CropError::Io(e),
+        e => CropError::Other(format!("ZIP error in {:?}: {}", archive, e)),
+    }
+}
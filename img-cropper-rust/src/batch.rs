@@ -0,0 +1,762 @@
+//! Cropping every image of a run in parallel.
+
+use crate::{
+    archive::{self, Listed, LocalFile},
+    cache::{Cache, Version},
+    checkpoint::Checkpoint,
+    crop_named,
+    filter::{self, DimensionFilter, PathFilter},
+    is_already_sized, is_supported_image_extension, is_temp_path, lowercase_extension,
+    memory::{self, MemoryBudget, Reservation},
+    naming, output_file_path, planned_outputs, suffixed_path, AlreadySized, Collision, CropError, CropOptions, Cropped,
+    IfExists, Output, OutputFile, OutputFormat,
+};
+#[cfg(feature = "s3")]
+use crate::s3::{self, S3Folder};
+use rayon::prelude::*;
+use std::{
+    collections::HashMap,
+    fmt,
+    fs::{self, File, FileTimes},
+    io,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        mpsc::{self, RecvTimeoutError},
+        Arc,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+use walkdir::WalkDir;
+
+/// Finds the images of a run, filters them and crops each one with
+/// `options`. Images are processed on the global rayon thread pool.
+pub struct BatchProcessor {
+    pub options: CropOptions,
+    /// Folders searched for images, or ZIP archives read like folders. With
+    /// several, each one's images are written into a subfolder of
+    /// `output_dir` named after it
+    pub input_dirs: Vec<PathBuf>,
+    /// Single images, written straight into `output_dir`
+    pub files: Vec<PathBuf>,
+    /// Where outputs go, mirroring the subfolders of the input directory;
+    /// unused with `options.in_place`
+    pub output_dir: PathBuf,
+    /// How many folder levels to search; 1 is only the input directory itself
+    pub max_depth: usize,
+    pub follow_links: bool,
+    /// Which files under the input directories are processed
+    pub path_filter: PathFilter,
+    pub dimension_filter: DimensionFilter,
+    pub min_bytes: Option<u64>,
+    pub max_bytes: Option<u64>,
+    /// Write every output directly into `output_dir`
+    pub flatten: bool,
+    /// Convert every image to this format instead of keeping its own
+    pub format: Option<OutputFormat>,
+    pub on_collision: Collision,
+    /// Give outputs the source file's timestamps
+    pub preserve_times: bool,
+    /// Only report where each image would go, without decoding or writing
+    /// anything
+    pub dry_run: bool,
+    /// Stop starting images after the first failure, skipping the rest
+    pub fail_fast: bool,
+    /// How many more times to try an image after a transient error, waiting
+    /// longer before each attempt
+    pub retries: u32,
+    /// Give up on an image that takes longer than this, counting it as
+    /// failed. Its work can't be interrupted, so it carries on in the
+    /// background while the run moves on, keeping its share of
+    /// `memory_budget` until it's done, but writes nothing more
+    pub timeout: Option<Duration>,
+    /// Skip the inputs it has as up to date, and note the ones cropped
+    pub cache: Option<Cache>,
+    /// Skip the inputs an interrupted run finished, and note each one done
+    pub checkpoint: Option<Checkpoint>,
+    /// Hold images back while those being cropped are estimated to need
+    /// more memory than this between them
+    pub memory_budget: Option<MemoryBudget>,
+}
+
+/// What happened during a run, reported as it happens. After
+/// [`Event::Found`], every input is reported exactly once as cropped,
+/// skipped or failed, or as planned in a dry run.
+#[derive(Debug)]
+pub enum Event<'a> {
+    /// The search for inputs is done and found this many images
+    Found { total: usize },
+    /// A symbolic link pointing back to one of its parent folders, which
+    /// wasn't followed
+    SymlinkLoop { path: &'a Path, ancestor: &'a Path },
+    /// `outputs` were written for `input` (any others were left alone),
+    /// taking `elapsed`
+    Cropped {
+        input: &'a Path,
+        source_dimensions: Option<(u32, u32)>,
+        /// Size of the input file before cropping
+        source_bytes: u64,
+        outputs: &'a [OutputFile],
+        elapsed: Duration,
+    },
+    /// Cropping `input` failed with a transient `error` and will be tried
+    /// again; `attempt` counts the retries so far
+    Retrying { input: &'a Path, attempt: u32, error: &'a CropError },
+    /// A dry run would write `outputs` for `input`
+    Planned { input: &'a Path, outputs: &'a [PathBuf] },
+    Skipped { input: &'a Path, reason: SkipReason },
+    Failed { input: &'a Path, error: &'a CropError },
+}
+
+/// Why an image produced no output.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum SkipReason {
+    /// The file size in bytes is outside the size limits
+    FileSize(u64),
+    /// The pixel size is outside the dimension limits
+    Dimensions(u32, u32),
+    /// The image already has the target size and format
+    AlreadySized,
+    /// An earlier input already claimed the output path
+    Collision { first: PathBuf },
+    /// Every target size would have needed upscaling
+    TooSmall,
+    /// The output already exists and was left alone
+    Exists(PathBuf),
+    /// An earlier image failed with `fail_fast` set
+    Aborted,
+    /// The cache has the input and its outputs unchanged since they were written
+    UpToDate,
+    /// The interrupted run being resumed already finished the input
+    Resumed,
+}
+
+/// Completes "Skipped: <input> ...".
+impl fmt::Display for SkipReason {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            SkipReason::FileSize(bytes) => write!(f, "is {} bytes, outside the file size limits", bytes),
+            SkipReason::Dimensions(width, height) => write!(f, "is {}x{}, outside the size limits", width, height),
+            SkipReason::AlreadySized => write!(f, "already has the target size"),
+            SkipReason::Collision { first } => write!(f, "would overwrite the output of {:?}", first),
+            SkipReason::TooSmall => write!(f, "is smaller than the target size"),
+            SkipReason::Exists(output) => write!(f, "would overwrite the existing {:?}", output),
+            SkipReason::Aborted => write!(f, "wasn't started after an earlier image failed"),
+            SkipReason::UpToDate => write!(f, "hasn't changed since it was last cropped"),
+            SkipReason::Resumed => write!(f, "was finished before the run was interrupted"),
+        }
+    }
+}
+
+/// How many images a run processed, skipped and failed on.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct BatchSummary {
+    pub processed: usize,
+    pub skipped: usize,
+    pub failed: usize,
+}
+
+impl BatchProcessor {
+    /// A batch without inputs that searches folders recursively, doesn't
+    /// filter anything and numbers colliding outputs.
+    pub fn new(options: CropOptions, output_dir: impl Into<PathBuf>) -> Self {
+        BatchProcessor {
+            options,
+            input_dirs: Vec::new(),
+            files: Vec::new(),
+            output_dir: output_dir.into(),
+            max_depth: usize::MAX,
+            follow_links: false,
+            path_filter: PathFilter::default(),
+            dimension_filter: DimensionFilter::default(),
+            min_bytes: None,
+            max_bytes: None,
+            flatten: false,
+            format: None,
+            on_collision: Collision::Suffix,
+            preserve_times: false,
+            dry_run: false,
+            fail_fast: false,
+            retries: 0,
+            timeout: None,
+            cache: None,
+            checkpoint: None,
+            memory_budget: None,
+        }
+    }
+
+    /// Crops every image, calling `on_event` from the worker threads as each
+    /// one is done. Fails before cropping anything if the input directories
+    /// can't be read or `on_collision` is [`Collision::Error`] and two
+    /// inputs would share an output.
+    pub fn run(&self, on_event: impl Fn(Event) + Sync) -> Result<BatchSummary, CropError> {
+        let inputs = self.find_inputs(&on_event)?;
+        self.crop_all(inputs, on_event)
+    }
+
+    /// Like [`BatchProcessor::run`], for just the images among `paths`,
+    /// such as the files that changed since the last run.
+    pub fn run_on(&self, paths: &[PathBuf], on_event: impl Fn(Event) + Sync) -> Result<BatchSummary, CropError> {
+        let inputs = self.find_images_among(paths)?.into_iter().map(|(path, output)| Input::new(path, output));
+        self.crop_all(inputs.collect(), on_event)
+    }
+
+    fn crop_all(&self, inputs: Vec<Input>, on_event: impl Fn(Event) + Sync) -> Result<BatchSummary, CropError> {
+        let processed_count = AtomicUsize::new(0);
+        let skipped_count = AtomicUsize::new(0);
+        let failed_count = AtomicUsize::new(0);
+        let aborted = AtomicBool::new(false);
+        // Failing to note an input only means a resumed run crops it again
+        let mark_done = |input: &Path| {
+            if let Some(checkpoint) = &self.checkpoint {
+                let _ = checkpoint.mark_done(input);
+            }
+        };
+        let skip = |input: &Path, reason: SkipReason| {
+            if !matches!(reason, SkipReason::Aborted | SkipReason::Resumed) {
+                mark_done(input);
+            }
+            on_event(Event::Skipped { input, reason });
+            skipped_count.fetch_add(1, Ordering::Relaxed);
+        };
+        let fail = |input: &Path, error: &CropError| {
+            on_event(Event::Failed { input, error });
+            failed_count.fetch_add(1, Ordering::Relaxed);
+            if self.fail_fast {
+                aborted.store(true, Ordering::Relaxed);
+            }
+        };
+
+        on_event(Event::Found { total: inputs.len() });
+        // Filtered before naming, so skipped images don't claim output names
+        let inputs: Vec<_> = inputs
+            .into_par_iter()
+            .filter_map(|mut input| {
+                let _span = image_span(&input.path).entered();
+                match self.filter(&mut input) {
+                    Some(reason) => {
+                        skip(&input.path, reason);
+                        None
+                    }
+                    None => Some(input),
+                }
+            })
+            .collect();
+        let inputs: Vec<_> = match &self.options.name_template {
+            Some(template) => inputs
+                .into_par_iter()
+                .filter_map(|mut input| match self.render_name(template, &mut input) {
+                    Ok(()) => Some(input),
+                    Err(e) => {
+                        let _span = image_span(&input.path).entered();
+                        fail(&input.path, &e);
+                        None
+                    }
+                })
+                .collect(),
+            None => inputs,
+        };
+        let inputs = resolve_collisions(inputs, self.on_collision, |input, first| {
+            let _span = image_span(input).entered();
+            skip(input, SkipReason::Collision { first: first.to_path_buf() })
+        })?;
+
+        inputs.into_par_iter().for_each(|mut input| {
+            let (input_file_path, output_file_path) = (&input.path, &input.output);
+            let _span = image_span(input_file_path).entered();
+            // Images already being cropped are finished, so no output is left half written
+            if aborted.load(Ordering::Relaxed) {
+                return skip(input_file_path, SkipReason::Aborted);
+            }
+            if self.checkpoint.as_ref().is_some_and(|checkpoint| checkpoint.is_done(input_file_path)) {
+                return skip(input_file_path, SkipReason::Resumed);
+            }
+            if let Some(cache) = &self.cache {
+                let version = input.version();
+                if version.is_some_and(|version| cache.is_up_to_date(input_file_path, &version, output_file_path)) {
+                    return skip(input_file_path, SkipReason::UpToDate);
+                }
+            }
+            if self.dry_run {
+                let outputs = planned_outputs(output_file_path, &self.options);
+                // Settled the way --if-exists would settle it, without touching anything
+                let existing = outputs.iter().find(|output| output.exists());
+                match (existing, self.options.if_exists) {
+                    (Some(output), IfExists::Skip) if outputs.iter().all(|output| output.exists()) => {
+                        skip(input_file_path, SkipReason::Exists(output.clone()))
+                    }
+                    (Some(output), IfExists::Error) => {
+                        fail(input_file_path, &format!("{:?} already exists", output).into())
+                    }
+                    _ => {
+                        on_event(Event::Planned { input: input_file_path, outputs: &outputs });
+                        processed_count.fetch_add(1, Ordering::Relaxed);
+                    }
+                }
+                return;
+            }
+
+            let start_time = Instant::now();
+            let source = match input.local.take() {
+                Some(source) => source,
+                None => match self.local_file(input_file_path) {
+                    Ok(source) => source,
+                    Err(e) => return fail(input_file_path, &e),
+                },
+            };
+            // Kept through every attempt, so a retry doesn't queue behind newer images
+            let mut reservation = self
+                .memory_budget
+                .as_ref()
+                .map(|budget| budget.reserve(memory::estimate(source.path(), &self.options)));
+            // Read before cropping, since in-place cropping may replace the source
+            let source_metadata = fs::metadata(source.path());
+            let source_bytes = source_metadata.as_ref().map_or(0, |metadata| metadata.len());
+            let mut crop = || {
+                let cropped = self.crop_image(source.path(), input_file_path, output_file_path, &mut reservation)?;
+                let (mut written, mut kept) = (Vec::new(), Vec::new());
+                for output in cropped.outputs {
+                    match output {
+                        Output::Written(file) => written.push(file),
+                        Output::Kept(path) => kept.push(path),
+                    }
+                }
+                if self.preserve_times {
+                    match &source_metadata {
+                        Ok(source_metadata) => copy_times(source_metadata, &written)?,
+                        Err(e) => return Err(io::Error::new(e.kind(), e.to_string()).into()),
+                    }
+                }
+                Ok::<_, CropError>((cropped.source_dimensions, written, kept))
+            };
+            let mut attempt = 0;
+            let result = loop {
+                match crop() {
+                    Err(e) if attempt < self.retries && e.is_transient() => {
+                        attempt += 1;
+                        on_event(Event::Retrying { input: input_file_path, attempt, error: &e });
+                        thread::sleep(RETRY_DELAY * 2u32.saturating_pow(attempt - 1).min(64));
+                    }
+                    result => break result,
+                }
+            };
+            match result {
+                Ok((_, written, kept)) if written.is_empty() => {
+                    let reason = match kept.into_iter().next() {
+                        None => SkipReason::TooSmall,
+                        Some(output) if output == source.path() => SkipReason::AlreadySized,
+                        Some(output) => SkipReason::Exists(output),
+                    };
+                    skip(input_file_path, reason);
+                }
+                Ok((source_dimensions, written, _)) => {
+                    // Read again, since cropping in place replaces the file
+                    if let Some((cache, version)) = self.cache.as_ref().zip(input.version()) {
+                        cache.record(input_file_path, version, output_file_path, &written);
+                    }
+                    mark_done(input_file_path);
+                    on_event(Event::Cropped {
+                        input: input_file_path,
+                        source_dimensions,
+                        source_bytes,
+                        outputs: &written,
+                        elapsed: start_time.elapsed(),
+                    });
+                    processed_count.fetch_add(1, Ordering::Relaxed);
+                }
+                Err(e) => fail(input_file_path, &e),
+            }
+        });
+
+        Ok(BatchSummary {
+            processed: processed_count.into_inner(),
+            skipped: skipped_count.into_inner(),
+            failed: failed_count.into_inner(),
+        })
+    }
+
+    /// Crops the image known as `name` from the file at `input_path`, on a
+    /// thread of its own when there's a timeout so a worker of the pool isn't
+    /// stuck with an image that never finishes. That thread holds the
+    /// image's `reservation` until it's done, handing it back unless it
+    /// timed out.
+    fn crop_image(
+        &self,
+        input_path: &Path,
+        name: &Path,
+        output_path: &Path,
+        reservation: &mut Option<Reservation>,
+    ) -> Result<Cropped, CropError> {
+        let Some(timeout) = self.timeout else {
+            return crop_named(input_path, name, output_path, &self.options, &AtomicBool::new(false));
+        };
+        let (sender, receiver) = mpsc::channel();
+        let (input, name, output) = (input_path.to_path_buf(), name.to_path_buf(), output_path.to_path_buf());
+        let (options, cancelled) = (self.options.clone(), Arc::new(AtomicBool::new(false)));
+        let (mut held, given_up) = (reservation.take(), cancelled.clone());
+        thread::Builder::new().name("crop".to_string()).spawn(move || {
+            if let Some(held) = &mut held {
+                held.take_over();
+            }
+            let result = crop_named(&input, &name, &output, &options, &given_up);
+            let _ = sender.send((result, held));
+        })?;
+        match receiver.recv_timeout(timeout) {
+            Ok((result, held)) => {
+                *reservation = held;
+                if let Some(reservation) = reservation {
+                    reservation.take_over();
+                }
+                result
+            }
+            Err(RecvTimeoutError::Timeout) => {
+                cancelled.store(true, Ordering::Relaxed);
+                Err(format!("Timed out after {:?}", timeout).into())
+            }
+            Err(RecvTimeoutError::Disconnected) => Err("Cropping the image crashed".to_string().into()),
+        }
+    }
+
+    /// Lists every input image with its output path: the images under each
+    /// input directory that pass the path filter, followed by `files`.
+    pub fn find_images(&self, on_event: impl Fn(Event)) -> Result<Vec<(PathBuf, PathBuf)>, CropError> {
+        Ok(self.find_inputs(on_event)?.into_iter().map(|input| (input.path, input.output)).collect())
+    }
+
+    /// Like [`BatchProcessor::find_images`], keeping what the listings of
+    /// archives and S3 folders say about their images.
+    fn find_inputs(&self, on_event: impl Fn(Event)) -> Result<Vec<Input>, CropError> {
+        let mut inputs = Vec::new();
+        let in_place = self.options.in_place;
+
+        for input_dir in &self.input_dirs {
+            let output_dir = self.root_output_dir(input_dir)?;
+            // Archives and S3 folders can't be walked, so they're listed
+            let mut listed = None;
+            if archive::is_archive(input_dir) {
+                listed = Some(archive::list_files(input_dir)?);
+            }
+            #[cfg(feature = "s3")]
+            if is_s3(input_dir) {
+                listed = Some(S3Folder::connect(input_dir)?.list_images()?);
+            }
+            if let Some(files) = listed {
+                for (relative_path, listed) in files {
+                    if self.in_excluded_folder(&relative_path) || relative_path.components().count() > self.max_depth {
+                        continue;
+                    }
+                    let input_path = input_dir.join(&relative_path);
+                    if let Some((path, output)) = self.image_paths(&input_path, &relative_path, &output_dir) {
+                        inputs.push(Input { listed: Some(listed), ..Input::new(path, output) });
+                    }
+                }
+                continue;
+            }
+            let nested_output_dir = self.nested_output_dir(input_dir);
+            // Sorted, so colliding outputs are numbered the same way on every run
+            let entries = WalkDir::new(input_dir)
+                .max_depth(self.max_depth)
+                .follow_links(self.follow_links)
+                .sort_by_file_name()
+                .into_iter()
+                .filter_entry(|entry| {
+                    entry.depth() == 0
+                        || entry
+                            .path()
+                            .strip_prefix(input_dir)
+                            .is_ok_and(|relative_path| {
+                                !self.path_filter.excludes(relative_path)
+                                    && Some(relative_path) != nested_output_dir.as_deref()
+                            })
+                })
+                .filter_map(|entry| match entry {
+                    Ok(entry) => Some(entry),
+                    Err(e) => {
+                        if let (Some(path), Some(ancestor)) = (e.path(), e.loop_ancestor()) {
+                            on_event(Event::SymlinkLoop { path, ancestor });
+                        }
+                        None
+                    }
+                })
+                .filter(|e| e.file_type().is_file());
+            for entry in entries {
+                let Ok(relative_path) = entry.path().strip_prefix(input_dir) else {
+                    continue;
+                };
+                let image_paths = self.image_paths(entry.path(), relative_path, &output_dir);
+                inputs.extend(image_paths.map(|(path, output)| Input::new(path, output)));
+            }
+        }
+
+        // Single files skip the path filter and are never mirrored
+        for file in &self.files {
+            let output_dir = match file.parent() {
+                Some(parent) if in_place => parent,
+                _ => &self.output_dir,
+            };
+            let output_path = output_dir.join(file.file_name().unwrap_or_default());
+            let output_path = output_file_path(file, &output_path, self.format, self.options.mask.is_some());
+            inputs.push(Input::new(file.clone(), output_path));
+        }
+
+        Ok(inputs)
+    }
+
+    /// Like [`BatchProcessor::find_images`], for just the `paths` inside the
+    /// input directories instead of everything in them.
+    pub fn find_images_among(&self, paths: &[PathBuf]) -> Result<Vec<(PathBuf, PathBuf)>, CropError> {
+        let mut image_paths = Vec::new();
+        for input_dir in &self.input_dirs {
+            let output_dir = self.root_output_dir(input_dir)?;
+            // File watchers report absolute paths, whatever the input directory looks like
+            let absolute_dir = input_dir.canonicalize()?;
+            for path in paths {
+                let relative_path = path.strip_prefix(input_dir).or_else(|_| path.strip_prefix(&absolute_dir));
+                let Ok(relative_path) = relative_path else {
+                    continue;
+                };
+                if self.in_excluded_folder(relative_path) || relative_path.components().count() > self.max_depth {
+                    continue;
+                }
+                image_paths.extend(self.image_paths(&input_dir.join(relative_path), relative_path, &output_dir));
+            }
+        }
+        Ok(image_paths)
+    }
+
+    /// The output directory relative to `input_dir`, if it's a folder inside
+    /// it. Its outputs are left out of the walk so they aren't cropped again
+    /// on every run.
+    fn nested_output_dir(&self, input_dir: &Path) -> Option<PathBuf> {
+        if self.options.in_place {
+            return None;
+        }
+        let output_dir = self.output_dir.canonicalize().ok()?;
+        let relative_path = output_dir.strip_prefix(input_dir.canonicalize().ok()?).ok()?;
+        (!relative_path.as_os_str().is_empty()).then(|| relative_path.to_path_buf())
+    }
+
+    /// Whether the walk of an input directory leaves out the file at
+    /// `relative_path`, being below an excluded folder.
+    fn in_excluded_folder(&self, relative_path: &Path) -> bool {
+        relative_path
+            .ancestors()
+            .skip(1)
+            .filter(|folder| !folder.as_os_str().is_empty())
+            .any(|folder| self.path_filter.excludes(folder))
+    }
+
+    /// Where everything found in `input_dir` is written. Several roots are
+    /// kept apart by their names: `-i /a/photos -i /b/scans.zip` writes to
+    /// `out/photos/...` and `out/scans/...`.
+    fn root_output_dir(&self, input_dir: &Path) -> io::Result<PathBuf> {
+        let canonical_dir = match is_s3(input_dir) {
+            true => input_dir.to_path_buf(),
+            false => input_dir.canonicalize()?,
+        };
+        let root_name = if archive::is_archive(input_dir) {
+            canonical_dir.file_stem()
+        } else {
+            canonical_dir.file_name()
+        };
+        Ok(match root_name {
+            _ if self.options.in_place => input_dir.to_path_buf(),
+            Some(root_name) if self.input_dirs.len() > 1 && !self.flatten => self.output_dir.join(root_name),
+            _ => self.output_dir.clone(),
+        })
+    }
+
+    /// The input and output path of the file at `relative_path` in an input
+    /// directory, if it's an image the path filter lets through.
+    fn image_paths(&self, input_path: &Path, relative_path: &Path, output_dir: &Path) -> Option<(PathBuf, PathBuf)> {
+        if self.path_filter.excludes(relative_path)
+            || !is_supported_image_extension(&lowercase_extension(input_path))
+            || is_temp_path(input_path)
+            || !self.path_filter.includes(relative_path)
+        {
+            return None;
+        }
+        let relative_path = match input_path.file_name() {
+            Some(file_name) if self.flatten => Path::new(file_name),
+            _ => relative_path,
+        };
+        let output_path = output_file_path(
+            input_path,
+            &output_dir.join(relative_path),
+            self.format,
+            self.options.mask.is_some(),
+        );
+        Some((input_path.to_path_buf(), output_path))
+    }
+
+    /// A file to read the image at `input_path` from, copied out of its
+    /// archive or downloaded from S3 if it's in one.
+    fn local_file(&self, input_path: &Path) -> Result<LocalFile, CropError> {
+        for input_dir in &self.input_dirs {
+            if let Ok(entry) = input_path.strip_prefix(input_dir) {
+                if archive::is_archive(input_dir) {
+                    return LocalFile::extract(input_dir, entry);
+                }
+                #[cfg(feature = "s3")]
+                if is_s3(input_dir) {
+                    return s3::fetch(input_dir, entry);
+                }
+            }
+        }
+        Ok(LocalFile::existing(input_path))
+    }
+
+    /// Names the output of `input` after `template`, fetching its file first
+    /// if the name needs its contents. The file is kept to crop it from.
+    fn render_name(&self, template: &str, input: &mut Input) -> Result<(), CropError> {
+        if naming::reads_source(template) && input.local.is_none() {
+            input.local = Some(self.local_file(&input.path)?);
+        }
+        let source = input.local.as_ref().map_or(input.path.as_path(), LocalFile::path);
+        input.output = naming::render(template, &input.path, source, &input.output)?;
+        Ok(())
+    }
+
+    /// Why `input` shouldn't be cropped, if it shouldn't. The file size is
+    /// checked first, from the listing of an archived or S3 image, since
+    /// it's cheaper than the header. An image fetched to read its header is
+    /// kept to crop it from.
+    fn filter(&self, input: &mut Input) -> Option<SkipReason> {
+        if self.min_bytes.is_some() || self.max_bytes.is_some() {
+            let bytes = match &input.listed {
+                Some(listed) => listed.size,
+                None => fs::metadata(&input.path).map_or(0, |metadata| metadata.len()),
+            };
+            if self.min_bytes.is_some_and(|min| bytes < min) || self.max_bytes.is_some_and(|max| bytes > max) {
+                return Some(SkipReason::FileSize(bytes));
+            }
+        }
+        let skip_sized = self.options.already_sized == Some(AlreadySized::Skip);
+        if !skip_sized && self.dimension_filter.is_empty() {
+            return None;
+        }
+        // A dry run touches nothing, so S3 objects are only checked once downloaded
+        if self.dry_run && is_s3(&input.path) {
+            return None;
+        }
+        // Left to cropping to report, like any other unreadable image
+        if input.local.is_none() {
+            input.local = Some(self.local_file(&input.path).ok()?);
+        }
+        let input_path = input.local.as_ref()?.path();
+        if skip_sized && is_already_sized(input_path, &input.output, &self.options) {
+            return Some(SkipReason::AlreadySized);
+        }
+        if self.dimension_filter.is_empty() {
+            return None;
+        }
+        match filter::read_dimensions(input_path) {
+            Some((width, height)) if !self.dimension_filter.allows((width, height)) => {
+                Some(SkipReason::Dimensions(width, height))
+            }
+            _ => None,
+        }
+    }
+}
+
+/// An image of the run on its way to being cropped.
+struct Input {
+    path: PathBuf,
+    output: PathBuf,
+    /// What the listing of its archive or S3 folder says about it
+    listed: Option<Listed>,
+    /// The file it's read from, if it was already fetched to be filtered or
+    /// named. Archived and S3 images stay in their scratch folders until
+    /// they're cropped, rather than being fetched again
+    local: Option<LocalFile>,
+}
+
+impl Input {
+    fn new(path: PathBuf, output: PathBuf) -> Input {
+        Input { path, output, listed: None, local: None }
+    }
+
+    /// What the cache tells this version of the input by. An S3 object
+    /// listed without an ETag has none, so it's always cropped.
+    fn version(&self) -> Option<Version> {
+        match &self.listed {
+            Some(listed) => {
+                let checksum = Some(listed.checksum.clone()?);
+                Some(Version { size: listed.size, modified: None, checksum })
+            }
+            None => Version::of_file(&self.path),
+        }
+    }
+}
+
+/// Whether `path` is an S3 folder, or an object in one.
+fn is_s3(path: &Path) -> bool {
+    cfg!(feature = "s3") && path.to_str().is_some_and(|path| path.starts_with("s3://"))
+}
+
+/// Handles inputs that would be written to the same output path, such as
+/// same-named files from different folders with `flatten`, or `a.jpg` and
+/// `a.png` with `format`. The first input keeps the path; `on_skip` is told
+/// about inputs dropped by [`Collision::Skip`].
+fn resolve_collisions(
+    inputs: Vec<Input>,
+    strategy: Collision,
+    on_skip: impl Fn(&Path, &Path),
+) -> Result<Vec<Input>, CropError> {
+    let mut claimed: HashMap<PathBuf, PathBuf> = HashMap::new();
+    let mut resolved = Vec::with_capacity(inputs.len());
+    for mut input in inputs {
+        let (input_path, output_path) = (&input.path, &input.output);
+        let output_path = match claimed.get(output_path) {
+            None => output_path.clone(),
+            Some(first) => match strategy {
+                Collision::Error => {
+                    return Err(CropError::Other(format!(
+                        "{:?} and {:?} would both be written to {:?}",
+                        first, input_path, output_path
+                    )));
+                }
+                Collision::Suffix => (2..)
+                    .map(|counter| suffixed_path(output_path, &counter.to_string()))
+                    .find(|candidate| !claimed.contains_key(candidate))
+                    .ok_or_else(|| CropError::Other("Ran out of collision suffixes".to_string()))?,
+                Collision::Skip => {
+                    on_skip(input_path, first);
+                    continue;
+                }
+            },
+        };
+        claimed.insert(output_path.clone(), input_path.clone());
+        input.output = output_path;
+        resolved.push(input);
+    }
+    Ok(resolved)
+}
+
+/// How long to wait before the first retry; each later one waits twice as
+/// long as the one before.
+const RETRY_DELAY: Duration = Duration::from_millis(250);
+
+/// The span an image's events are reported in, so logs can tell which
+/// image they're about.
+fn image_span(input: &Path) -> tracing::Span {
+    tracing::info_span!("image", input = %input.display())
+}
+
+/// Gives every output the source's access and modification times, and its
+/// creation time on platforms that can set it.
+fn copy_times(source: &fs::Metadata, outputs: &[OutputFile]) -> io::Result<()> {
+    let times = FileTimes::new()
+        .set_accessed(source.accessed()?)
+        .set_modified(source.modified()?);
+    #[cfg(windows)]
+    let times = std::os::windows::fs::FileTimesExt::set_created(times, source.created()?);
+    #[cfg(target_os = "macos")]
+    let times = std::os::macos::fs::FileTimesExt::set_created(times, source.created()?);
+
+    for output in outputs {
+        File::options().write(true).open(&output.path)?.set_times(times)?;
+    }
+    Ok(())
+}
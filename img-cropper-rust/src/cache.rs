@@ -0,0 +1,143 @@
+//! A record of the inputs a run cropped, so the next run with the same
+//! options can skip the ones that haven't changed since.
+//!
+//! An input is up to date while its [`Version`], the options key and its
+//! output path are what they were when it was cropped, and every output it
+//! wrote still exists.
+
+use crate::{CropError, OutputFile};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    fs::{self, File},
+    io::{self, BufReader, BufWriter, Write},
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::SystemTime,
+};
+
+/// The inputs cropped so far, read from and written back to one file.
+pub struct Cache {
+    path: PathBuf,
+    key: String,
+    entries: Mutex<HashMap<PathBuf, Entry>>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Entry {
+    /// The options key the input was cropped with
+    key: String,
+    size: u64,
+    modified: Option<SystemTime>,
+    #[serde(default)]
+    checksum: Option<String>,
+    output: PathBuf,
+    outputs: Vec<PathBuf>,
+}
+
+/// What tells one version of an input from the next: the size and
+/// modification time of a file, or the size and checksum an archive or S3
+/// listing has for an image in it.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Version {
+    pub size: u64,
+    pub modified: Option<SystemTime>,
+    pub checksum: Option<String>,
+}
+
+impl Version {
+    /// The version of the file at `path` as it is now.
+    pub fn of_file(path: &Path) -> Option<Version> {
+        let metadata = fs::metadata(path).ok()?;
+        Some(Version { size: metadata.len(), modified: Some(metadata.modified().ok()?), checksum: None })
+    }
+}
+
+impl Cache {
+    /// Reads the cache at `path`, if there is one, for a run whose options
+    /// come down to `key`. A file that isn't a cache is started over.
+    pub fn open(path: &Path, key: impl Into<String>) -> Result<Cache, CropError> {
+        let entries = match File::open(path) {
+            Ok(file) => serde_json::from_reader(BufReader::new(file)).unwrap_or_default(),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => HashMap::new(),
+            Err(e) => return Err(e.into()),
+        };
+        Ok(Cache { path: path.to_path_buf(), key: key.into(), entries: Mutex::new(entries) })
+    }
+
+    /// Whether `input`, now at `version`, was cropped to `output` with the
+    /// same options and neither it nor its outputs changed since.
+    pub fn is_up_to_date(&self, input: &Path, version: &Version, output: &Path) -> bool {
+        let entries = self.entries.lock().unwrap_or_else(|e| e.into_inner());
+        entries.get(input).is_some_and(|entry| {
+            entry.key == self.key
+                && entry.output == output
+                && entry.size == version.size
+                && entry.modified == version.modified
+                && entry.checksum == version.checksum
+                && entry.outputs.iter().all(|output| output.exists())
+        })
+    }
+
+    /// Notes that `input`, at `version`, was just cropped to `output`,
+    /// writing `outputs`. A file cropped in place is at the version it was
+    /// replaced with.
+    pub fn record(&self, input: &Path, version: Version, output: &Path, outputs: &[OutputFile]) {
+        let entry = Entry {
+            key: self.key.clone(),
+            size: version.size,
+            modified: version.modified,
+            checksum: version.checksum,
+            output: output.to_path_buf(),
+            outputs: outputs.iter().map(|output| output.path.clone()).collect(),
+        };
+        self.entries.lock().unwrap_or_else(|e| e.into_inner()).insert(input.to_path_buf(), entry);
+    }
+
+    /// Writes the cache back to its file, replacing it only once it's
+    /// written in full.
+    pub fn save(&self) -> io::Result<()> {
+        let entries = self.entries.lock().unwrap_or_else(|e| e.into_inner());
+        let mut temp_path = self.path.clone().into_os_string();
+        temp_path.push(".tmp");
+        let mut writer = BufWriter::new(File::create(&temp_path)?);
+        serde_json::to_writer(&mut writer, &*entries)?;
+        writer.flush()?;
+        fs::rename(&temp_path, &self.path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Rect;
+
+    #[test]
+    fn listed_inputs_are_up_to_date_until_their_checksum_changes() {
+        let dir = std::env::temp_dir().join(format!("img-cropper-cache-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let output = dir.join("a.jpg");
+        fs::write(&output, b"jpeg").unwrap();
+        let written = OutputFile {
+            path: output.clone(),
+            dimensions: (1, 1),
+            region: Rect { x: 0, y: 0, width: 1, height: 1 },
+            bytes: 4,
+        };
+        let listed = |checksum: &str| Version { size: 10, modified: None, checksum: Some(checksum.to_string()) };
+        let input = Path::new("photos.zip/a.jpg");
+
+        let cache = Cache::open(&dir.join("cache.json"), "key").unwrap();
+        cache.record(input, listed("crc32:00000001"), &output, &[written]);
+        cache.save().unwrap();
+        let cache = Cache::open(&dir.join("cache.json"), "key").unwrap();
+        let fresh = cache.is_up_to_date(input, &listed("crc32:00000001"), &output);
+        let changed = cache.is_up_to_date(input, &listed("crc32:00000002"), &output);
+        let other_options = Cache::open(&dir.join("cache.json"), "other").unwrap();
+        let stale = other_options.is_up_to_date(input, &listed("crc32:00000001"), &output);
+        fs::remove_dir_all(&dir).unwrap();
+        assert!(fresh);
+        assert!(!changed);
+        assert!(!stale);
+    }
+}
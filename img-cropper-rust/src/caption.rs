@@ -0,0 +1,117 @@
+//! Text drawn onto the outputs with `--caption`, in the bundled DejaVu Sans
+//! or a font file of the user's.
+
+use crate::{effects::Watermark, svg::pixmap_image, CropError};
+use resvg::{
+    tiny_skia::{Pixmap, Transform},
+    usvg::{fontdb, Options, Tree},
+};
+use std::{
+    path::Path,
+    sync::{Arc, OnceLock},
+};
+
+const BUNDLED_FONT: &[u8] = include_bytes!("../fonts/DejaVuSans.ttf");
+const BUNDLED_FAMILY: &str = "DejaVu Sans";
+
+/// A caption template and how to draw it.
+#[derive(Clone, Debug)]
+pub struct Caption {
+    /// Text with the placeholders of [`crate::naming::CAPTION_PLACEHOLDERS`]
+    pub template: String,
+    /// Font size in pixels; by default a sixteenth of the output's height
+    pub size: Option<f32>,
+    /// Where the caption sits, as fractions of the space around it
+    pub position: (f64, f64),
+    fonts: Arc<fontdb::Database>,
+    family: String,
+}
+
+impl Caption {
+    /// A caption in the bundled font.
+    pub fn new(template: &str, size: Option<f32>, position: (f64, f64)) -> Caption {
+        Caption {
+            template: template.to_string(),
+            size,
+            position,
+            fonts: bundled_font(),
+            family: BUNDLED_FAMILY.to_string(),
+        }
+    }
+
+    /// Draws the caption in the first font of the file at `font` instead.
+    pub fn with_font(self, font: &Path) -> Result<Caption, CropError> {
+        let mut fonts = fontdb::Database::new();
+        fonts.load_font_file(font)?;
+        let family = fonts
+            .faces()
+            .next()
+            .and_then(|face| face.families.first())
+            .map(|(family, _)| family.clone())
+            .ok_or_else(|| CropError::Other(format!("No font found in {:?}", font)))?;
+        Ok(Caption { fonts: Arc::new(fonts), family, ..self })
+    }
+
+    /// Renders `text` as a watermark: a strip as wide as a `dimensions`-sized
+    /// output, white with a black outline so it reads on any image.
+    pub(crate) fn render(
+        &self,
+        text: &str,
+        (width, height): (u32, u32),
+    ) -> Result<Watermark, Box<dyn std::error::Error>> {
+        let size = self.size.unwrap_or((height as f32 / 16.0).max(10.0));
+        let strip_height = (size * 1.5).ceil() as u32;
+        let margin = size / 2.0;
+        let (x, anchor) = match self.position.0 {
+            left if left < 0.25 => (margin, "start"),
+            right if right > 0.75 => (width as f32 - margin, "end"),
+            _ => (width as f32 / 2.0, "middle"),
+        };
+        let svg = format!(
+            concat!(
+                r#"<svg xmlns="http://www.w3.org/2000/svg" width="{}" height="{}">"#,
+                r#"<text x="{}" y="{}" font-family="{}" font-size="{}" text-anchor="{}" fill="white" "#,
+                r#"stroke="black" stroke-width="{}" stroke-linejoin="round" paint-order="stroke">{}</text></svg>"#
+            ),
+            width,
+            strip_height,
+            x,
+            size * 1.1,
+            escape(&self.family),
+            size,
+            anchor,
+            size / 8.0,
+            escape(text)
+        );
+        let options = Options { fontdb: self.fonts.clone(), ..Options::default() };
+        let tree = Tree::from_str(&svg, &options)?;
+        let mut pixmap = Pixmap::new(width, strip_height)
+            .ok_or_else(|| format!("Can't draw a caption on a {}x{} image", width, height))?;
+        resvg::render(&tree, Transform::default(), &mut pixmap.as_mut());
+        let strip = pixmap_image(&pixmap).ok_or("Rendered caption has the wrong size")?;
+        Ok(Watermark {
+            image: Arc::new(image::DynamicImage::ImageRgba8(strip).into_rgba32f()),
+            position: (0.0, self.position.1),
+            opacity: 1.0,
+        })
+    }
+}
+
+/// Parsing the bundled font takes a moment, so it happens once.
+fn bundled_font() -> Arc<fontdb::Database> {
+    static FONTS: OnceLock<Arc<fontdb::Database>> = OnceLock::new();
+    FONTS
+        .get_or_init(|| {
+            let mut fonts = fontdb::Database::new();
+            fonts.load_font_data(BUNDLED_FONT.to_vec());
+            Arc::new(fonts)
+        })
+        .clone()
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+}
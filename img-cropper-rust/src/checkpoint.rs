@@ -0,0 +1,83 @@
+//! The inputs a run has finished with, written down as it goes so a run
+//! that's interrupted can be resumed where it stopped.
+//!
+//! The file starts with a line identifying the run, followed by one input
+//! path per line. Each line is written as soon as its input is done, so a
+//! crash loses at most the images being cropped at the time.
+
+use crate::CropError;
+use std::{
+    collections::HashSet,
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+pub struct Checkpoint {
+    path: PathBuf,
+    file: Mutex<File>,
+    /// The inputs finished before the run was resumed
+    done: HashSet<PathBuf>,
+}
+
+impl Checkpoint {
+    /// Starts a checkpoint at `path` for the run identified by `key`,
+    /// replacing any earlier one. Its folder is created if needed.
+    pub fn start(path: &Path, key: &str) -> Result<Checkpoint, CropError> {
+        if let Some(parent) = path.parent().filter(|parent| !parent.as_os_str().is_empty()) {
+            fs::create_dir_all(parent)?;
+        }
+        let mut file = File::create(path)?;
+        writeln!(file, "{}", key)?;
+        Ok(Checkpoint { path: path.to_path_buf(), file: Mutex::new(file), done: HashSet::new() })
+    }
+
+    /// Picks up the checkpoint at `path` left by an interrupted run, which
+    /// must be the run identified by `key`. Without one, starts over.
+    pub fn resume(path: &Path, key: &str) -> Result<Checkpoint, CropError> {
+        let contents = match fs::read(path) {
+            Ok(contents) => contents,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Checkpoint::start(path, key),
+            Err(e) => return Err(e.into()),
+        };
+        let mut lines: Vec<_> = contents.split(|&byte| byte == b'\n').collect();
+        if lines.first() != Some(&key.as_bytes()) {
+            return Err(CropError::Other(format!(
+                "{:?} is the checkpoint of a run with other options or inputs",
+                path
+            )));
+        }
+        // What follows the last line break is empty, or a line cut short by a crash
+        lines.pop();
+        let done = lines[1..]
+            .iter()
+            .map(|line| PathBuf::from(String::from_utf8_lossy(line).into_owned()))
+            .collect();
+        let file = OpenOptions::new().append(true).open(path)?;
+        Ok(Checkpoint { path: path.to_path_buf(), file: Mutex::new(file), done })
+    }
+
+    /// Whether `input` was finished before the run was resumed.
+    pub fn is_done(&self, input: &Path) -> bool {
+        self.done.contains(input)
+    }
+
+    /// How many inputs were finished before the run was resumed.
+    pub fn done_count(&self) -> usize {
+        self.done.len()
+    }
+
+    /// Writes down that `input` is done.
+    pub fn mark_done(&self, input: &Path) -> io::Result<()> {
+        let line = format!("{}\n", input.to_string_lossy());
+        self.file.lock().unwrap_or_else(|e| e.into_inner()).write_all(line.as_bytes())
+    }
+
+    /// Removes the checkpoint once the run is over, as there's nothing left
+    /// to resume.
+    pub fn finish(self) -> io::Result<()> {
+        drop(self.file);
+        fs::remove_file(&self.path)
+    }
+}
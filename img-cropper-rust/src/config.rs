@@ -0,0 +1,247 @@
+//! Options read from a TOML file, given with `--config` or found as
+//! `img-cropper.toml` in the working directory.
+//!
+//! Keys are the long option names, with dashes or underscores:
+//!
+//! ```toml
+//! input-dir = ["photos", "scans"]
+//! output_dir = "cropped"
+//! size = "400x300,200x200"
+//! smart = true
+//! jpeg-quality = 85
+//! ```
+//!
+//! `[preset.<name>]` tables hold more options, used with `--preset <name>`:
+//!
+//! ```toml
+//! [preset.thumbnails]
+//! size = "320x240"
+//! format = "webp"
+//! webp-quality = 70
+//! ```
+//!
+//! Options given on the command line or as environment variables win over
+//! the preset's, and the preset's over the rest of the file's. The options that conflict with
+//! them are left out too: `-q` silences a file's `verbose = 2`.
+
+use clap::{builder::ArgAction, Arg, Command};
+use std::{
+    ffi::OsString,
+    fs,
+    path::{Path, PathBuf},
+};
+use toml::{Table, Value};
+
+/// Read when there's no `--config`, if it exists.
+const DEFAULT_CONFIG: &str = "img-cropper.toml";
+
+/// `argv` with the options of the config file inserted after the program
+/// name, leaving out those `argv` sets itself or conflicts with.
+pub fn with_config(command: &Command, argv: Vec<OsString>) -> Result<Vec<OsString>, String> {
+    let preset = option_value(command, &argv, "preset");
+    let config_path = match option_value(command, &argv, "config") {
+        Some(config_path) => PathBuf::from(config_path),
+        None if Path::new(DEFAULT_CONFIG).is_file() => PathBuf::from(DEFAULT_CONFIG),
+        None if preset.is_some() => return Err(format!("--preset needs --config or an {}", DEFAULT_CONFIG)),
+        None => return Ok(argv),
+    };
+    let text = fs::read_to_string(&config_path)
+        .map_err(|e| format!("Failed to read the config file {:?}: {}", config_path, e))?;
+    let mut table: Table = text
+        .parse()
+        .map_err(|e| format!("Invalid config file {:?}: {}", config_path, e))?;
+    let presets = table.remove("preset");
+    let preset_table = match (&preset, &presets) {
+        (None, _) => None,
+        (Some(name), Some(Value::Table(presets))) => match presets.get(name) {
+            Some(Value::Table(preset_table)) => Some(preset_table),
+            _ => return Err(format!("No preset {:?} in the config file {:?}", name, config_path)),
+        },
+        (Some(name), _) => return Err(format!("No preset {:?} in the config file {:?}", name, config_path)),
+    };
+
+    // Each layer is left out where a layer above it sets the same option or a
+    // conflicting one: the command line, then the preset, then the file
+    let mut given: Vec<&Arg> = command.get_arguments().filter(|arg| sets(&argv, arg)).collect();
+    let mut config_args = Vec::new();
+    for layer in preset_table.into_iter().chain([&table]) {
+        let mut layer_args = Vec::new();
+        for (key, value) in layer {
+            let long = key.replace('_', "-");
+            let arg = command
+                .get_arguments()
+                .find(|arg| arg.get_long() == Some(long.as_str()))
+                .filter(|arg| !matches!(arg.get_id().as_str(), "config" | "preset" | "help" | "version"))
+                .ok_or_else(|| format!("Unknown option {:?} in the config file {:?}", key, config_path))?;
+            let overridden = given
+                .iter()
+                .any(|other| other.get_id() == arg.get_id() || conflict(command, arg, other));
+            if !overridden {
+                config_args.extend(option_args(arg, value).map_err(|e| format!("{} in {:?}", e, config_path))?);
+                layer_args.push(arg);
+            }
+        }
+        given.extend(layer_args);
+    }
+    let mut argv = argv.into_iter();
+    Ok(argv.next().into_iter().chain(config_args).chain(argv).collect())
+}
+
+/// The value given to `--<long>` in `argv` or else its environment
+/// variable, if it's given.
+fn option_value(command: &Command, argv: &[OsString], long: &str) -> Option<String> {
+    let mut args = argv.iter().skip(1);
+    while let Some(arg) = args.next() {
+        let arg = arg.to_string_lossy();
+        let Some(rest) = arg.strip_prefix("--").and_then(|rest| rest.strip_prefix(long)) else {
+            continue;
+        };
+        if rest.is_empty() {
+            return args.next().map(|value| value.to_string_lossy().into_owned());
+        }
+        if let Some(value) = rest.strip_prefix('=') {
+            return Some(value.to_string());
+        }
+    }
+    let arg = command.get_arguments().find(|arg| arg.get_long() == Some(long))?;
+    arg.get_env().and_then(std::env::var_os).map(|value| value.to_string_lossy().into_owned())
+}
+
+/// Whether `argv` gives `arg`, by its long or short name, or its
+/// environment variable is set.
+fn sets(argv: &[OsString], arg: &Arg) -> bool {
+    if arg.get_env().is_some_and(|name| std::env::var_os(name).is_some()) {
+        return true;
+    }
+    argv.iter().skip(1).any(|token| {
+        let token = token.to_string_lossy();
+        let long = arg.get_long().is_some_and(|long| {
+            let rest = token.strip_prefix("--").and_then(|rest| rest.strip_prefix(long));
+            rest.is_some_and(|rest| rest.is_empty() || rest.starts_with('='))
+        });
+        let short = arg.get_short().is_some_and(|short| {
+            !token.starts_with("--") && token.strip_prefix('-').is_some_and(|rest| rest.starts_with(short))
+        });
+        long || short
+    })
+}
+
+/// Whether `a` and `b` can't be used together, whichever one declares it.
+fn conflict(command: &Command, a: &Arg, b: &Arg) -> bool {
+    let declares = |arg: &Arg, other: &Arg| {
+        command.get_arg_conflicts_with(arg).iter().any(|conflicting| conflicting.get_id() == other.get_id())
+    };
+    declares(a, b) || declares(b, a)
+}
+
+/// The command line arguments that give `arg` the config file's `value`.
+fn option_args(arg: &Arg, value: &Value) -> Result<Vec<OsString>, String> {
+    let key = arg.get_long().unwrap_or_default();
+    let flag = || OsString::from(format!("--{}", key));
+    let with_value = |value: String| OsString::from(format!("--{}={}", key, value));
+    match (arg.get_action(), value) {
+        (ArgAction::SetTrue, Value::Boolean(set)) => Ok(if *set { vec![flag()] } else { Vec::new() }),
+        (ArgAction::Count, Value::Integer(count)) => Ok((0..*count).map(|_| flag()).collect()),
+        (ArgAction::SetTrue, _) => Err(format!("{:?} takes true or false", key)),
+        (ArgAction::Count, _) => Err(format!("{:?} takes a number", key)),
+        (ArgAction::Append, Value::Array(values)) => {
+            values.iter().map(|value| scalar(key, value).map(with_value)).collect()
+        }
+        // A list for an option taking one value, such as several sizes
+        (_, Value::Array(values)) => {
+            let values = values.iter().map(|value| scalar(key, value)).collect::<Result<Vec<_>, _>>()?;
+            Ok(vec![with_value(values.join(","))])
+        }
+        (_, value) => Ok(vec![with_value(scalar(key, value)?)]),
+    }
+}
+
+fn scalar(key: &str, value: &Value) -> Result<String, String> {
+    match value {
+        Value::String(text) => Ok(text.clone()),
+        Value::Integer(number) => Ok(number.to_string()),
+        Value::Float(number) => Ok(number.to_string()),
+        Value::Boolean(set) => Ok(set.to_string()),
+        _ => Err(format!("{:?} takes a string, a number or a list of them", key)),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Args;
+    use clap::FromArgMatches;
+
+    /// `argv` after the program name, merged with `config` as the config
+    /// file, then parsed.
+    fn merged(name: &str, config: &str, argv: &[&str]) -> Result<Args, String> {
+        let path = std::env::temp_dir().join(format!("img-cropper-config-{}-{}.toml", name, std::process::id()));
+        fs::write(&path, config).unwrap();
+        let command = crate::command();
+        let argv = ["img-cropper", "-i", "in", "-o", "out", "--config", path.to_str().unwrap()]
+            .iter()
+            .chain(argv)
+            .map(OsString::from)
+            .collect();
+        let merged = with_config(&command, argv);
+        fs::remove_file(&path).unwrap();
+        let matches = command.try_get_matches_from(merged?).map_err(|e| e.to_string())?;
+        Args::from_arg_matches(&matches).map_err(|e| e.to_string())
+    }
+
+    const CONFIG: &str = r#"
+        size = "100x100"
+        jpeg-quality = 60
+        png_compression = "best"
+
+        [preset.small]
+        size = ["50x50", "20x20"]
+        jpeg-quality = 70
+    "#;
+
+    #[test]
+    fn preset_wins_over_file() {
+        let args = merged("preset", CONFIG, &["--preset", "small"]).unwrap();
+        assert_eq!(args.size.as_deref(), Some("50x50,20x20"));
+        assert_eq!(args.jpeg_quality, 70);
+        // What the preset leaves alone still comes from the file
+        assert_eq!(args.png_compression, img_cropper_rust::PngCompression::Best);
+    }
+
+    #[test]
+    fn command_line_wins_over_preset_and_file() {
+        let args = merged("cli", CONFIG, &["--preset", "small", "--jpeg-quality=90", "--size", "10x10"]).unwrap();
+        assert_eq!(args.size.as_deref(), Some("10x10"));
+        assert_eq!(args.jpeg_quality, 90);
+        let args = merged("file", CONFIG, &["--jpeg-quality", "80"]).unwrap();
+        assert_eq!((args.size.as_deref(), args.jpeg_quality), (Some("100x100"), 80));
+    }
+
+    #[test]
+    fn environment_wins_over_preset() {
+        // No other test sets the AVIF speed
+        std::env::set_var("IMG_CROPPER_AVIF_SPEED", "9");
+        let args = merged("env", "size = \"10x10\"\n[preset.fast]\navif-speed = 2\n", &["--preset", "fast"]);
+        std::env::remove_var("IMG_CROPPER_AVIF_SPEED");
+        assert_eq!(args.unwrap().avif_speed, 9);
+    }
+
+    #[test]
+    fn conflicting_options_are_left_out() {
+        let config = "size = \"10x10\"\nwebp-quality = 60\nverbose = 2\n[preset.lossy]\nwebp-quality = 80\n";
+        let args = merged("conflict", config, &["--preset", "lossy", "--webp-lossless", "-q"]).unwrap();
+        assert!(args.webp_lossless && args.quiet);
+        assert_eq!((args.webp_quality, args.verbose), (None, 0));
+        // A preset's option conflicting with the file's leaves out the file's
+        let config = "size = \"10x10\"\nwebp-lossless = true\n[preset.lossy]\nwebp-quality = 80\n";
+        let args = merged("preset-conflict", config, &["--preset", "lossy"]).unwrap();
+        assert_eq!((args.webp_quality, args.webp_lossless), (Some(80.0), false));
+    }
+
+    #[test]
+    fn unknown_preset_and_option_fail() {
+        assert!(merged("no-preset", CONFIG, &["--preset", "large"]).unwrap_err().contains("No preset \"large\""));
+        let error = merged("unknown", "sise = \"10x10\"\n", &[]).unwrap_err();
+        assert!(error.contains("Unknown option \"sise\""), "{}", error);
+    }
+}
@@ -0,0 +1,57 @@
+//! Fetching images over HTTP(S) for `--urls`.
+
+use crate::{is_supported_image_extension, lowercase_extension, sniff_extension, CropError};
+use rayon::prelude::*;
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+use ureq::Agent;
+
+/// Largest image accepted, so a wrong URL can't fill the disk.
+const MAX_DOWNLOAD_BYTES: u64 = 256 * 1024 * 1024;
+
+/// How long one download may take before it fails.
+const DOWNLOAD_TIMEOUT: Duration = Duration::from_secs(120);
+
+/// Downloads `urls` in parallel, each into its own numbered folder in `dir` so
+/// that images with the same name don't overwrite each other. Returns the
+/// file or error for each URL, in order.
+pub fn download_all(urls: &[String], dir: &Path) -> Vec<Result<PathBuf, CropError>> {
+    let agent: Agent = Agent::config_builder()
+        .timeout_global(Some(DOWNLOAD_TIMEOUT))
+        .build()
+        .into();
+    urls.par_iter()
+        .enumerate()
+        .map(|(index, url)| download(&agent, url, &dir.join(index.to_string())))
+        .collect()
+}
+
+/// Downloads `url` into `dir`, named after the last part of its path. Names
+/// without an image extension get one from the data instead.
+fn download(agent: &Agent, url: &str, dir: &Path) -> Result<PathBuf, CropError> {
+    let data = agent
+        .get(url)
+        .call()
+        .and_then(|mut response| response.body_mut().with_config().limit(MAX_DOWNLOAD_BYTES).read_to_vec())
+        .map_err(|e| CropError::Other(e.to_string()))?;
+    let name = url
+        .split(['?', '#'])
+        .next()
+        .and_then(|path| path.split_once("://"))
+        .and_then(|(_, path)| path.split_once('/'))
+        .and_then(|(_, path)| path.rsplit('/').next())
+        .filter(|name| !name.is_empty() && *name != "." && *name != "..")
+        .unwrap_or("download");
+    let mut path = dir.join(name);
+    if !is_supported_image_extension(&lowercase_extension(&path)) {
+        let extension = sniff_extension(&data)
+            .ok_or_else(|| CropError::Other("Not a supported image format".to_string()))?;
+        path = dir.join(format!("{}.{}", name, extension));
+    }
+    fs::create_dir_all(dir)?;
+    fs::write(&path, data)?;
+    Ok(path)
+}
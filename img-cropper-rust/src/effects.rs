@@ -0,0 +1,253 @@
+//! Pixel adjustments applied to the resized image before it is encoded.
+
+use crate::{convert_color, BorderPlacement};
+use image::{
+    imageops::{self, FilterType},
+    ColorType, DynamicImage, GenericImageView, Rgba, Rgba32FImage,
+};
+use std::{borrow::Cow, sync::Arc};
+
+/// Unsharp mask settings.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Sharpen {
+    /// How much of the detail lost to blurring is added back
+    pub amount: f32,
+    /// Standard deviation of the blur, in pixels
+    pub radius: f32,
+    /// Minimum difference (0-255) from the blurred image to sharpen a pixel
+    pub threshold: u8,
+}
+
+/// Color adjustments, each a factor where 1 leaves the image unchanged.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Adjust {
+    /// Multiplies every channel: 1.2 is 20% brighter
+    pub brightness: f32,
+    /// Scales the distance from mid-gray: 0 is flat gray
+    pub contrast: f32,
+    /// Scales the distance from the pixel's own luma: 0 is grayscale
+    pub saturation: f32,
+}
+
+impl Default for Adjust {
+    fn default() -> Self {
+        Adjust { brightness: 1.0, contrast: 1.0, saturation: 1.0 }
+    }
+}
+
+/// Per-channel black and white points found by [`find_levels`].
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Levels {
+    pub low: [f32; 3],
+    pub high: [f32; 3],
+}
+
+/// A solid frame around every output.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Border {
+    /// Thickness in pixels
+    pub width: u32,
+    pub color: Rgba<u8>,
+    pub placement: BorderPlacement,
+}
+
+/// A shape cut out of every output, leaving the rest transparent.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Mask {
+    /// Rounded corners of this radius in pixels
+    Rounded(u32),
+    /// The largest circle that fits, centered
+    Circle,
+}
+
+/// An image composited onto every output.
+#[derive(Clone, Debug)]
+pub struct Watermark {
+    pub image: Arc<Rgba32FImage>,
+    /// Where the watermark sits, as fractions of the space around it
+    pub position: (f64, f64),
+    /// From 0 (invisible) to 1 (as opaque as the watermark image itself)
+    pub opacity: f32,
+}
+
+/// Sharpens `img` by boosting its difference from a blurred copy.
+pub fn sharpen(img: &DynamicImage, settings: Sharpen) -> DynamicImage {
+    let blurred = img.blur(settings.radius).into_rgba32f();
+    let mut sharpened = img.to_rgba32f();
+    let threshold = settings.threshold as f32 / 255.0;
+
+    for (pixel, blurred) in sharpened.pixels_mut().zip(blurred.pixels()) {
+        for (channel, blurred) in pixel.0[..3].iter_mut().zip(&blurred.0[..3]) {
+            let detail = *channel - blurred;
+            if detail.abs() >= threshold {
+                *channel = (*channel + detail * settings.amount).clamp(0.0, 1.0);
+            }
+        }
+    }
+
+    convert_color(DynamicImage::ImageRgba32F(sharpened), img.color())
+}
+
+/// Converts `img` to luma, keeping its alpha channel and bit depth. Float
+/// images become 16-bit, the deepest luma the encoders take.
+pub fn grayscale(img: &DynamicImage) -> DynamicImage {
+    let color = img.color();
+    let deep = color.bytes_per_pixel() > color.channel_count();
+    let color = match (deep, color.has_alpha()) {
+        (false, false) => ColorType::L8,
+        (false, true) => ColorType::La8,
+        (true, false) => ColorType::L16,
+        (true, true) => ColorType::La16,
+    };
+    convert_color(img.clone(), color)
+}
+
+/// Draws the watermark over `img`, shrunk to fit if it's larger.
+pub fn watermark(img: &DynamicImage, watermark: &Watermark) -> DynamicImage {
+    let (width, height) = img.dimensions();
+    let mut mark = Cow::Borrowed(&*watermark.image);
+    if mark.width() > width || mark.height() > height {
+        let shrunk = DynamicImage::ImageRgba32F(mark.into_owned()).resize(width, height, FilterType::Triangle);
+        mark = Cow::Owned(shrunk.into_rgba32f());
+    }
+    let x = ((width - mark.width()) as f64 * watermark.position.0).round() as u32;
+    let y = ((height - mark.height()) as f64 * watermark.position.1).round() as u32;
+
+    let mut canvas = img.to_rgba32f();
+    for (mark_x, mark_y, pixel) in mark.enumerate_pixels() {
+        let under = canvas.get_pixel_mut(x + mark_x, y + mark_y);
+        let alpha = pixel.0[3] * watermark.opacity;
+        let under_alpha = under.0[3] * (1.0 - alpha);
+        let out_alpha = alpha + under_alpha;
+        if out_alpha > 0.0 {
+            for (channel, over) in under.0[..3].iter_mut().zip(&pixel.0[..3]) {
+                *channel = (over * alpha + *channel * under_alpha) / out_alpha;
+            }
+        }
+        under.0[3] = out_alpha;
+    }
+
+    convert_color(DynamicImage::ImageRgba32F(canvas), img.color())
+}
+
+/// Frames `img` with `border`, over its edges or around them.
+pub fn border(img: &DynamicImage, border: Border) -> DynamicImage {
+    let (width, height) = img.dimensions();
+    let thickness = border.width;
+    let color = Rgba(border.color.0.map(|channel| channel as f32 / 255.0));
+    let framed = match border.placement {
+        BorderPlacement::Outside => {
+            let mut canvas = Rgba32FImage::from_pixel(width + 2 * thickness, height + 2 * thickness, color);
+            imageops::replace(&mut canvas, &img.to_rgba32f(), thickness as i64, thickness as i64);
+            canvas
+        }
+        BorderPlacement::Inside => {
+            let mut canvas = img.to_rgba32f();
+            for (x, y, pixel) in canvas.enumerate_pixels_mut() {
+                let inside = x >= thickness && y >= thickness && x + thickness < width && y + thickness < height;
+                if !inside {
+                    *pixel = color;
+                }
+            }
+            canvas
+        }
+    };
+
+    convert_color(DynamicImage::ImageRgba32F(framed), img.color())
+}
+
+/// Makes everything outside `mask` transparent, with smooth edges. Images
+/// without an alpha channel get one.
+pub fn mask(img: &DynamicImage, mask: Mask) -> DynamicImage {
+    let (width, height) = img.dimensions();
+    let (width_f, height_f) = (width as f32, height as f32);
+    let mut masked = img.to_rgba32f();
+    for (x, y, pixel) in masked.enumerate_pixels_mut() {
+        let (x, y) = (x as f32 + 0.5, y as f32 + 0.5);
+        // Distance past the shape's edge, from the nearest point a circle
+        // of `radius` around it stays inside the shape
+        let (radius, distance) = match mask {
+            Mask::Rounded(radius) => {
+                let radius = (radius as f32).min(width_f / 2.0).min(height_f / 2.0);
+                let nearest_x = x.clamp(radius, width_f - radius);
+                let nearest_y = y.clamp(radius, height_f - radius);
+                (radius, (x - nearest_x).hypot(y - nearest_y))
+            }
+            Mask::Circle => (width_f.min(height_f) / 2.0, (x - width_f / 2.0).hypot(y - height_f / 2.0)),
+        };
+        pixel.0[3] *= (radius - distance + 0.5).clamp(0.0, 1.0);
+    }
+
+    let color = match img.color() {
+        ColorType::L8 => ColorType::La8,
+        ColorType::Rgb8 => ColorType::Rgba8,
+        ColorType::L16 => ColorType::La16,
+        ColorType::Rgb16 => ColorType::Rgba16,
+        ColorType::Rgb32F => ColorType::Rgba32F,
+        color => color,
+    };
+    convert_color(DynamicImage::ImageRgba32F(masked), color)
+}
+
+/// Applies the brightness, contrast and saturation of `adjust`, in that order.
+pub fn adjust(img: &DynamicImage, adjust: Adjust) -> DynamicImage {
+    let mut adjusted = img.to_rgba32f();
+    for pixel in adjusted.pixels_mut() {
+        let [red, green, blue, _] = &mut pixel.0;
+        let luma = 0.2126 * *red + 0.7152 * *green + 0.0722 * *blue;
+        let luma = (luma * adjust.brightness - 0.5) * adjust.contrast + 0.5;
+        for channel in [red, green, blue] {
+            let value = (*channel * adjust.brightness - 0.5) * adjust.contrast + 0.5;
+            *channel = (luma + (value - luma) * adjust.saturation).clamp(0.0, 1.0);
+        }
+    }
+
+    convert_color(DynamicImage::ImageRgba32F(adjusted), img.color())
+}
+
+/// The darkest and brightest values of each channel, ignoring the outer
+/// 0.5% of pixels so a few specks don't hold the stretch back. Stretching
+/// each channel on its own also neutralizes color casts.
+pub fn find_levels(img: &DynamicImage) -> Levels {
+    let mut histograms = [[0u64; 256]; 3];
+    for pixel in img.to_rgb8().pixels() {
+        for (histogram, &value) in histograms.iter_mut().zip(&pixel.0) {
+            histogram[value as usize] += 1;
+        }
+    }
+    let clipped = (img.width() as u64 * img.height() as u64) / 200;
+    let mut levels = Levels { low: [0.0; 3], high: [1.0; 3] };
+    for (channel, histogram) in histograms.iter().enumerate() {
+        let low = clip_point(histogram, clipped, 0..256).unwrap_or(0);
+        let high = clip_point(histogram, clipped, (0..256).rev()).unwrap_or(255);
+        if high > low {
+            levels.low[channel] = low as f32 / 255.0;
+            levels.high[channel] = high as f32 / 255.0;
+        }
+    }
+    levels
+}
+
+/// The first of `values` with more than `clipped` pixels up to it.
+fn clip_point(histogram: &[u64; 256], clipped: u64, mut values: impl Iterator<Item = usize>) -> Option<usize> {
+    let mut count = 0;
+    values.find(|&value| {
+        count += histogram[value];
+        count > clipped
+    })
+}
+
+/// Stretches each channel of `img` from `levels` to the full range, blended
+/// with the original by `strength` from 0 to 1.
+pub fn apply_levels(img: &DynamicImage, levels: Levels, strength: f32) -> DynamicImage {
+    let mut stretched = img.to_rgba32f();
+    for pixel in stretched.pixels_mut() {
+        for (channel, value) in pixel.0[..3].iter_mut().enumerate() {
+            let (low, high) = (levels.low[channel], levels.high[channel]);
+            let full = ((*value - low) / (high - low)).clamp(0.0, 1.0);
+            *value += (full - *value) * strength;
+        }
+    }
+
+    convert_color(DynamicImage::ImageRgba32F(stretched), img.color())
+}
@@ -0,0 +1,454 @@
+//! Writing processed images with per-format encoder settings.
+
+use crate::{icc, quantize, JpegSubsampling};
+use image::{
+    codecs::{
+        avif::AvifEncoder,
+        png::{CompressionType, FilterType, PngEncoder},
+    },
+    ColorType, DynamicImage, ImageEncoder, ImageFormat,
+};
+use jpeg_encoder::SamplingFactor;
+use zune_core::{bit_depth::BitDepth, colorspace::ColorSpace, options::EncoderOptions};
+use zune_jpegxl::JxlSimpleEncoder;
+use std::{
+    fs::{self, File},
+    io::BufWriter,
+    path::Path,
+};
+
+/// Encoder settings shared by every output in a run.
+#[derive(Clone, Copy, Debug)]
+pub struct EncodeSettings {
+    /// JPEG quality from 1 (smallest) to 100 (best)
+    pub jpeg_quality: u8,
+    pub jpeg_subsampling: JpegSubsampling,
+    /// Write progressive JPEGs, which show a rough preview while loading
+    pub jpeg_progressive: bool,
+    /// Lossy WebP quality from 0 to 100, or `None` for lossless WebP
+    pub webp_quality: Option<f32>,
+    pub png_compression: CompressionType,
+    pub png_filter: FilterType,
+    /// AVIF quality from 1 (smallest) to 100 (best)
+    pub avif_quality: u8,
+    /// AVIF encoder speed from 1 (slowest) to 10 (fastest)
+    pub avif_speed: u8,
+    /// Palette size for PNG and GIF outputs, or `None` for full color
+    pub colors: Option<u16>,
+    /// Dither the palette colors of `colors`
+    pub dither: bool,
+}
+
+impl Default for EncodeSettings {
+    fn default() -> Self {
+        EncodeSettings {
+            jpeg_quality: 75,
+            jpeg_subsampling: JpegSubsampling::Yuv420,
+            jpeg_progressive: false,
+            webp_quality: None,
+            png_compression: CompressionType::Fast,
+            png_filter: FilterType::Adaptive,
+            avif_quality: 80,
+            avif_speed: 4,
+            colors: None,
+            dither: false,
+        }
+    }
+}
+
+/// Metadata carried over from the source image into every output written for it.
+#[derive(Clone, Debug, Default)]
+pub struct Metadata {
+    /// Raw EXIF data (the TIFF structure, without the `Exif\0\0` prefix)
+    pub exif: Option<Vec<u8>>,
+    /// Embedded ICC color profile
+    pub icc: Option<Vec<u8>>,
+}
+
+/// Saves `img` to `output_path` in the format implied by its extension.
+/// EXIF is kept for JPEG, PNG, WebP and AVIF and dropped elsewhere. The ICC
+/// profile is embedded where the format allows and applied to the pixels
+/// otherwise.
+pub fn save_image(
+    img: DynamicImage,
+    output_path: &Path,
+    settings: &EncodeSettings,
+    metadata: &Metadata,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let img = match &metadata.icc {
+        Some(icc) if !ImageFormat::from_path(output_path).is_ok_and(format_supports_icc) => {
+            icc::to_srgb(img, icc)
+        }
+        _ => img,
+    };
+
+    // JPEG XL isn't one of the image crate's formats, so it has its own encoder
+    let is_jxl = output_path
+        .extension()
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("jxl"));
+    if is_jxl {
+        fs::write(output_path, encode_jxl(&img)?)?;
+        return Ok(());
+    }
+
+    // Determine the image format based on the output file extension
+    let format = ImageFormat::from_path(output_path)
+        .unwrap_or(ImageFormat::Png); // Default to PNG if unknown
+
+    // Formats without an alpha channel can't store a transparent background
+    let img = if img.color().has_alpha() && !format_supports_alpha(format) {
+        DynamicImage::ImageRgb8(img.to_rgb8())
+    } else {
+        img
+    };
+
+    match format {
+        ImageFormat::Jpeg => save_jpeg(jpeg_compatible(img), output_path, settings, metadata)?,
+        ImageFormat::Png if settings.colors.is_some() => save_indexed_png(&img, output_path, settings, metadata)?,
+        ImageFormat::Png => {
+            let writer = BufWriter::new(File::create(output_path)?);
+            let encoder =
+                PngEncoder::new_with_quality(writer, settings.png_compression, settings.png_filter);
+            img.write_with_encoder(with_metadata(encoder, metadata)?)?;
+        }
+        ImageFormat::Avif => {
+            let writer = BufWriter::new(File::create(output_path)?);
+            let encoder =
+                AvifEncoder::new_with_speed_quality(writer, settings.avif_speed, settings.avif_quality);
+            eight_bit(img).write_with_encoder(with_metadata(encoder, metadata)?)?;
+        }
+        ImageFormat::WebP => {
+            let encoded = encode_webp(&img, settings.webp_quality);
+            let has_alpha = img.color().has_alpha();
+            fs::write(output_path, add_webp_metadata(encoded, metadata, (img.width(), img.height()), has_alpha))?
+        }
+        // The GIF encoder keeps the exact colors of images with few enough of them
+        ImageFormat::Gif if settings.colors.is_some() => {
+            let reduced = quantize::reduce(&img.to_rgba8(), settings.colors.unwrap_or(256), settings.dither);
+            reduced.save_with_format(output_path, format)?
+        }
+        _ => img.save_with_format(output_path, format)?,
+    }
+
+    Ok(())
+}
+
+/// Writes `img` as a JPEG, which the image crate's encoder can't do with a
+/// choice of chroma subsampling or progressive scans.
+fn save_jpeg(
+    img: DynamicImage,
+    output_path: &Path,
+    settings: &EncodeSettings,
+    metadata: &Metadata,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let (Ok(width), Ok(height)) = (u16::try_from(img.width()), u16::try_from(img.height())) else {
+        return Err(format!("A {}x{} image is too large for JPEG", img.width(), img.height()).into());
+    };
+    let mut encoder = jpeg_encoder::Encoder::new(BufWriter::new(File::create(output_path)?), settings.jpeg_quality);
+    encoder.set_sampling_factor(match settings.jpeg_subsampling {
+        JpegSubsampling::Yuv444 => SamplingFactor::R_4_4_4,
+        JpegSubsampling::Yuv422 => SamplingFactor::R_4_2_2,
+        JpegSubsampling::Yuv420 => SamplingFactor::R_4_2_0,
+    });
+    encoder.set_progressive(settings.jpeg_progressive);
+    // EXIF must fit in one 64 KiB segment, unlike a profile, which is split
+    if let Some(exif) = &metadata.exif {
+        if encoder.add_exif_metadata(exif).is_err() {
+            tracing::warn!("Dropped {} bytes of EXIF, too many for a JPEG: {:?}", exif.len(), output_path);
+        }
+    }
+    if let Some(icc) = &metadata.icc {
+        encoder.add_icc_profile(icc)?;
+    }
+    let color = match img.color() {
+        ColorType::L8 => jpeg_encoder::ColorType::Luma,
+        _ => jpeg_encoder::ColorType::Rgb,
+    };
+    encoder.encode(img.as_bytes(), width, height, color)?;
+    Ok(())
+}
+
+/// Writes `img` as a palette PNG of `settings.colors` colors, packing small
+/// palettes into fewer bits per pixel.
+fn save_indexed_png(
+    img: &DynamicImage,
+    output_path: &Path,
+    settings: &EncodeSettings,
+    metadata: &Metadata,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let quantize::Indexed { palette, indices } =
+        quantize::quantize(&img.to_rgba8(), settings.colors.unwrap_or(256), settings.dither);
+    let (width, height) = (img.width(), img.height());
+    let mut info = png::Info::with_size(width, height);
+    info.exif_metadata = metadata.exif.clone().map(Into::into);
+    info.icc_profile = metadata.icc.clone().map(Into::into);
+    let mut encoder = png::Encoder::with_info(BufWriter::new(File::create(output_path)?), info)?;
+    encoder.set_color(png::ColorType::Indexed);
+    encoder.set_palette(palette.iter().flat_map(|color| [color[0], color[1], color[2]]).collect::<Vec<_>>());
+    if palette.iter().any(|color| color[3] < 255) {
+        encoder.set_trns(palette.iter().map(|color| color[3]).collect::<Vec<_>>());
+    }
+    let bits = match palette.len() {
+        0..=2 => 1,
+        3..=4 => 2,
+        5..=16 => 4,
+        _ => 8,
+    };
+    encoder.set_depth(match bits {
+        1 => png::BitDepth::One,
+        2 => png::BitDepth::Two,
+        4 => png::BitDepth::Four,
+        _ => png::BitDepth::Eight,
+    });
+    encoder.set_compression(png_compression(settings.png_compression));
+    encoder.set_filter(png_filter(settings.png_filter));
+
+    // Each row starts on a byte of its own, its pixels packed from the high bits
+    let mut data = Vec::new();
+    for row in indices.chunks(width as usize) {
+        for pixels in row.chunks(8 / bits) {
+            let byte = pixels
+                .iter()
+                .enumerate()
+                .fold(0u8, |byte, (i, &index)| byte | index << (8 - bits * (i + 1)));
+            data.push(byte);
+        }
+    }
+    let mut writer = encoder.write_header()?;
+    writer.write_image_data(&data)?;
+    writer.finish()?;
+    Ok(())
+}
+
+/// The png crate's equivalent of the image crate's compression setting.
+pub(crate) fn png_compression(compression: CompressionType) -> png::Compression {
+    match compression {
+        CompressionType::Fast => png::Compression::Fast,
+        CompressionType::Best => png::Compression::High,
+        _ => png::Compression::Balanced,
+    }
+}
+
+/// The png crate's equivalent of the image crate's filter setting.
+pub(crate) fn png_filter(filter: FilterType) -> png::Filter {
+    match filter {
+        FilterType::NoFilter => png::Filter::NoFilter,
+        FilterType::Sub => png::Filter::Sub,
+        FilterType::Up => png::Filter::Up,
+        FilterType::Avg => png::Filter::Avg,
+        FilterType::Paeth => png::Filter::Paeth,
+        _ => png::Filter::Adaptive,
+    }
+}
+
+/// Attaches `metadata` to an image crate encoder.
+fn with_metadata<E: ImageEncoder>(mut encoder: E, metadata: &Metadata) -> Result<E, image::error::UnsupportedError> {
+    if let Some(exif) = &metadata.exif {
+        encoder.set_exif_metadata(exif.clone())?;
+    }
+    // Encoders without profile support were given sRGB pixels instead
+    if let Some(icc) = &metadata.icc {
+        let _ = encoder.set_icc_profile(icc.clone());
+    }
+    Ok(encoder)
+}
+
+/// Whether `format` can embed an ICC profile.
+pub fn format_supports_icc(format: ImageFormat) -> bool {
+    matches!(format, ImageFormat::Jpeg | ImageFormat::Png | ImageFormat::WebP)
+}
+
+pub fn format_supports_alpha(format: ImageFormat) -> bool {
+    matches!(
+        format,
+        ImageFormat::Png
+            | ImageFormat::WebP
+            | ImageFormat::Gif
+            | ImageFormat::Tiff
+            | ImageFormat::Avif
+    )
+}
+
+fn encode_webp(img: &DynamicImage, quality: Option<f32>) -> Vec<u8> {
+    let (width, height) = (img.width(), img.height());
+    let (pixels, has_alpha) = if img.color().has_alpha() {
+        (img.to_rgba8().into_raw(), true)
+    } else {
+        (img.to_rgb8().into_raw(), false)
+    };
+    let encoder = if has_alpha {
+        webp::Encoder::from_rgba(&pixels, width, height)
+    } else {
+        webp::Encoder::from_rgb(&pixels, width, height)
+    };
+
+    match quality {
+        Some(quality) => encoder.encode(quality).to_vec(),
+        None => encoder.encode_lossless().to_vec(),
+    }
+}
+
+/// Adds `metadata` to a WebP file from libwebp, converting a simple
+/// (VP8/VP8L only) file to the extended format that can hold it.
+pub(crate) fn add_webp_metadata(
+    webp: Vec<u8>,
+    metadata: &Metadata,
+    (width, height): (u32, u32),
+    has_alpha: bool,
+) -> Vec<u8> {
+    if metadata.exif.is_none() && metadata.icc.is_none() {
+        return webp;
+    }
+
+    // Everything after the `RIFF <size> WEBP` header
+    let mut chunks = webp[12..].to_vec();
+    if &chunks[..4] != b"VP8X" {
+        let mut vp8x = Vec::with_capacity(10);
+        vp8x.push(if has_alpha { VP8X_ALPHA } else { 0 });
+        vp8x.extend_from_slice(&[0; 3]);
+        vp8x.extend_from_slice(&(width - 1).to_le_bytes()[..3]);
+        vp8x.extend_from_slice(&(height - 1).to_le_bytes()[..3]);
+        let mut extended = Vec::with_capacity(chunks.len() + 18);
+        push_chunk(&mut extended, b"VP8X", &vp8x);
+        extended.extend_from_slice(&chunks);
+        chunks = extended;
+    }
+    // The ICC profile goes right after VP8X, EXIF after the image data
+    if let Some(icc) = &metadata.icc {
+        let mut iccp = Vec::with_capacity(icc.len() + 9);
+        push_chunk(&mut iccp, b"ICCP", icc);
+        chunks.splice(18..18, iccp);
+        chunks[8] |= VP8X_ICC;
+    }
+    if let Some(exif) = &metadata.exif {
+        push_chunk(&mut chunks, b"EXIF", exif);
+        chunks[8] |= VP8X_EXIF;
+    }
+
+    let mut riff = Vec::with_capacity(chunks.len() + 12);
+    riff.extend_from_slice(b"RIFF");
+    riff.extend_from_slice(&(chunks.len() as u32 + 4).to_le_bytes());
+    riff.extend_from_slice(b"WEBP");
+    riff.extend_from_slice(&chunks);
+    riff
+}
+
+/// VP8X feature flags.
+const VP8X_ICC: u8 = 0x20;
+const VP8X_ALPHA: u8 = 0x10;
+const VP8X_EXIF: u8 = 0x08;
+
+/// Appends a RIFF chunk, padded to an even length.
+fn push_chunk(data: &mut Vec<u8>, fourcc: &[u8; 4], payload: &[u8]) {
+    data.extend_from_slice(fourcc);
+    data.extend_from_slice(&(payload.len() as u32).to_le_bytes());
+    data.extend_from_slice(payload);
+    if payload.len() % 2 == 1 {
+        data.push(0);
+    }
+}
+
+/// Losslessly encodes `img` as JPEG XL, keeping 16-bit depth when present.
+fn encode_jxl(img: &DynamicImage) -> Result<Vec<u8>, Box<dyn std::error::Error>> {
+    let converted;
+    let (img, colorspace, depth) = match img {
+        DynamicImage::ImageLuma8(_) => (img, ColorSpace::Luma, BitDepth::Eight),
+        DynamicImage::ImageLumaA8(_) => (img, ColorSpace::LumaA, BitDepth::Eight),
+        DynamicImage::ImageRgb8(_) => (img, ColorSpace::RGB, BitDepth::Eight),
+        DynamicImage::ImageRgba8(_) => (img, ColorSpace::RGBA, BitDepth::Eight),
+        DynamicImage::ImageLuma16(_) => (img, ColorSpace::Luma, BitDepth::Sixteen),
+        DynamicImage::ImageLumaA16(_) => (img, ColorSpace::LumaA, BitDepth::Sixteen),
+        DynamicImage::ImageRgb16(_) => (img, ColorSpace::RGB, BitDepth::Sixteen),
+        DynamicImage::ImageRgba16(_) => (img, ColorSpace::RGBA, BitDepth::Sixteen),
+        _ if img.color().has_alpha() => {
+            converted = DynamicImage::ImageRgba16(img.to_rgba16());
+            (&converted, ColorSpace::RGBA, BitDepth::Sixteen)
+        }
+        _ => {
+            converted = DynamicImage::ImageRgb16(img.to_rgb16());
+            (&converted, ColorSpace::RGB, BitDepth::Sixteen)
+        }
+    };
+
+    let options = EncoderOptions::new(
+        img.width() as usize,
+        img.height() as usize,
+        colorspace,
+        depth,
+    );
+    let mut encoded = Vec::new();
+    JxlSimpleEncoder::new(img.as_bytes(), options)
+        .encode(&mut encoded)
+        .map_err(|e| format!("JPEG XL encoding failed: {:?}", e))?;
+    Ok(encoded)
+}
+
+/// 8-bit RGB or RGBA pixels, which every encoder accepts.
+fn eight_bit(img: DynamicImage) -> DynamicImage {
+    match img {
+        DynamicImage::ImageRgb8(_) | DynamicImage::ImageRgba8(_) => img,
+        _ if img.color().has_alpha() => DynamicImage::ImageRgba8(img.to_rgba8()),
+        _ => DynamicImage::ImageRgb8(img.to_rgb8()),
+    }
+}
+
+/// The JPEG encoder only accepts 8-bit grayscale and RGB pixels.
+fn jpeg_compatible(img: DynamicImage) -> DynamicImage {
+    match img {
+        DynamicImage::ImageLuma8(_) | DynamicImage::ImageRgb8(_) => img,
+        _ if !img.color().has_color() => DynamicImage::ImageLuma8(img.to_luma8()),
+        _ => DynamicImage::ImageRgb8(img.to_rgb8()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{codecs::webp::WebPDecoder, ImageDecoder, ImageReader, Rgb, RgbImage, Rgba, RgbaImage};
+    use std::io::Cursor;
+
+    /// Odd lengths, so both chunks need padding.
+    fn metadata() -> Metadata {
+        Metadata {
+            exif: Some(b"II*\0\x08\0\0\0\0\0test exif".to_vec()),
+            icc: Some((0..131).map(|byte| byte as u8).collect()),
+        }
+    }
+
+    #[test]
+    fn webp_metadata_reads_back() {
+        let opaque = RgbImage::from_fn(7, 5, |x, y| Rgb([x as u8 * 30, y as u8 * 40, 90]));
+        let transparent = RgbaImage::from_fn(7, 5, |x, y| Rgba([200, 10, 10, 50 + (x * y) as u8]));
+        // Lossy with alpha comes from libwebp as VP8X already; the rest are simple files.
+        // No pixel is fully transparent, as libwebp drops the color of those
+        for img in [DynamicImage::ImageRgb8(opaque), DynamicImage::ImageRgba8(transparent)] {
+            for quality in [None, Some(80.0)] {
+                let has_alpha = img.color().has_alpha();
+                let webp = add_webp_metadata(encode_webp(&img, quality), &metadata(), (7, 5), has_alpha);
+                assert_eq!(u32::from_le_bytes(webp[4..8].try_into().unwrap()) as usize, webp.len() - 8);
+
+                let mut decoder = WebPDecoder::new(Cursor::new(&webp)).unwrap();
+                assert_eq!(decoder.icc_profile().unwrap(), metadata().icc);
+                assert_eq!(decoder.exif_metadata().unwrap(), metadata().exif);
+                assert_eq!(decoder.dimensions(), (7, 5));
+                let decoded = DynamicImage::from_decoder(decoder).unwrap();
+                assert_eq!(decoded.color().has_alpha(), has_alpha);
+                if quality.is_none() {
+                    assert_eq!(decoded.to_rgba8(), img.to_rgba8());
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn jpeg_drops_exif_too_large_for_a_segment() {
+        let path = std::env::temp_dir().join(format!("img-cropper-test-{}.jpg", std::process::id()));
+        let img = DynamicImage::ImageRgb8(RgbImage::new(8, 8));
+        let metadata = Metadata { exif: Some(vec![0; 70_000]), icc: None };
+        save_image(img, &path, &EncodeSettings::default(), &metadata).unwrap();
+        let mut decoder = ImageReader::open(&path).unwrap().into_decoder().unwrap();
+        assert_eq!(decoder.exif_metadata().unwrap(), None);
+        assert_eq!(decoder.dimensions(), (8, 8));
+        fs::remove_file(&path).unwrap();
+    }
+}
@@ -0,0 +1,57 @@
+//! Face detection used to keep people inside the crop window.
+
+use image::DynamicImage;
+use rustface::{ImageData, Model};
+use std::{io, path::Path};
+
+/// Longest edge of the downscaled copy that faces are searched in.
+const DETECTION_SIZE: u32 = 1024;
+
+/// A loaded SeetaFace model, shared by every worker thread.
+pub struct FaceFinder {
+    model: Model,
+}
+
+impl FaceFinder {
+    pub fn load(model_path: &Path) -> Result<Self, io::Error> {
+        let model = rustface::load_model(&model_path.to_string_lossy())?;
+        Ok(FaceFinder { model })
+    }
+
+    /// Returns the center of the box enclosing every face in `img`, as
+    /// fractions of the width and height, or `None` if there are no faces.
+    pub fn find_focal_point(&self, img: &DynamicImage) -> Option<(f64, f64)> {
+        let gray = if img.width() > DETECTION_SIZE || img.height() > DETECTION_SIZE {
+            img.thumbnail(DETECTION_SIZE, DETECTION_SIZE).to_luma8()
+        } else {
+            img.to_luma8()
+        };
+        let (width, height) = gray.dimensions();
+
+        let mut detector = rustface::create_detector_with_model(self.model.clone());
+        detector.set_min_face_size(20);
+        detector.set_score_thresh(2.0);
+        detector.set_pyramid_scale_factor(0.8);
+        detector.set_slide_window_step(4, 4);
+
+        let faces = detector.detect(&ImageData::new(gray.as_raw(), width, height));
+        let (left, top, right, bottom) = faces.iter().map(|face| face.bbox()).fold(
+            (i64::MAX, i64::MAX, i64::MIN, i64::MIN),
+            |(left, top, right, bottom), bbox| {
+                (
+                    left.min(bbox.x() as i64),
+                    top.min(bbox.y() as i64),
+                    right.max(bbox.x() as i64 + bbox.width() as i64),
+                    bottom.max(bbox.y() as i64 + bbox.height() as i64),
+                )
+            },
+        );
+        if faces.is_empty() {
+            return None;
+        }
+
+        let center_x = (left + right) as f64 / 2.0 / width as f64;
+        let center_y = (top + bottom) as f64 / 2.0 / height as f64;
+        Some((center_x.clamp(0.0, 1.0), center_y.clamp(0.0, 1.0)))
+    }
+}
@@ -0,0 +1,43 @@
+//! A list of the images that failed, in the format --files-from reads, so a
+//! run can be retried on just those.
+
+use crate::Event;
+use std::{
+    fs::File,
+    io::{self, BufWriter, Write},
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+/// Collects the failed images from the events of a run.
+#[derive(Default)]
+pub struct FailedList {
+    failures: Mutex<Vec<(PathBuf, String)>>,
+}
+
+impl FailedList {
+    pub fn new() -> Self {
+        FailedList::default()
+    }
+
+    pub fn record(&self, event: &Event) {
+        if let Event::Failed { input, error } = event {
+            let mut failures = self.failures.lock().unwrap_or_else(|e| e.into_inner());
+            failures.push((input.to_path_buf(), error.to_string()));
+        }
+    }
+
+    /// Writes every failed path on its own line, after a `#` comment with
+    /// its error, sorted by path.
+    pub fn write(&self, path: &Path) -> io::Result<()> {
+        let mut failures = self.failures.lock().unwrap_or_else(|e| e.into_inner());
+        failures.sort();
+
+        let mut writer = BufWriter::new(File::create(path)?);
+        for (input, error) in failures.iter() {
+            writeln!(writer, "# {}", error.replace(['\n', '\r'], " "))?;
+            writeln!(writer, "{}", input.display())?;
+        }
+        writer.flush()
+    }
+}
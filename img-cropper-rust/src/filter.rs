@@ -0,0 +1,105 @@
+//! Choosing which files under the input directory get processed.
+
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use image::{metadata::Orientation, ImageDecoder, ImageReader};
+use regex::Regex;
+use std::path::Path;
+
+/// Patterns are matched against both the file name and the path relative to
+/// the input directory, so `*.png` and `IMG_*` work in any subfolder while
+/// `raw/*.dng` still only matches one folder.
+#[derive(Default)]
+pub struct PathFilter {
+    include: Option<GlobSet>,
+    include_regex: Option<Regex>,
+    exclude: Option<GlobSet>,
+}
+
+impl PathFilter {
+    /// Empty pattern lists don't filter anything.
+    pub fn new(
+        include: &[Glob],
+        include_regex: Option<Regex>,
+        exclude: &[Glob],
+    ) -> Result<PathFilter, globset::Error> {
+        Ok(PathFilter {
+            include: glob_set(include)?,
+            include_regex,
+            exclude: glob_set(exclude)?,
+        })
+    }
+
+    /// Whether the file or folder at `relative_path` is excluded. Excluded
+    /// folders aren't searched at all.
+    pub fn excludes(&self, relative_path: &Path) -> bool {
+        let file_name = Path::new(relative_path.file_name().unwrap_or_default());
+        self.exclude
+            .as_ref()
+            .is_some_and(|globs| globs.is_match(relative_path) || globs.is_match(file_name))
+    }
+
+    /// Whether the file at `relative_path` should be processed.
+    pub fn includes(&self, relative_path: &Path) -> bool {
+        let file_name = Path::new(relative_path.file_name().unwrap_or_default());
+        let relative_str = relative_path.to_string_lossy().replace('\\', "/");
+        let file_name_str = file_name.to_string_lossy();
+
+        self.include
+            .as_ref()
+            .is_none_or(|globs| globs.is_match(relative_path) || globs.is_match(file_name))
+            && self
+                .include_regex
+                .as_ref()
+                .is_none_or(|regex| regex.is_match(&relative_str) || regex.is_match(&file_name_str))
+    }
+}
+
+fn glob_set(globs: &[Glob]) -> Result<Option<GlobSet>, globset::Error> {
+    if globs.is_empty() {
+        return Ok(None);
+    }
+    let mut builder = GlobSetBuilder::new();
+    for glob in globs {
+        builder.add(glob.clone());
+    }
+    builder.build().map(Some)
+}
+
+/// Bounds on the pixel size of the images to process, in pixels.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct DimensionFilter {
+    pub min_width: Option<u32>,
+    pub min_height: Option<u32>,
+    pub max_width: Option<u32>,
+    pub max_height: Option<u32>,
+}
+
+impl DimensionFilter {
+    pub fn is_empty(&self) -> bool {
+        self.min_width.is_none() && self.min_height.is_none() && self.max_width.is_none() && self.max_height.is_none()
+    }
+
+    /// Whether an image of `width`x`height` is within every bound.
+    pub fn allows(&self, (width, height): (u32, u32)) -> bool {
+        self.min_width.is_none_or(|min| width >= min)
+            && self.min_height.is_none_or(|min| height >= min)
+            && self.max_width.is_none_or(|max| width <= max)
+            && self.max_height.is_none_or(|max| height <= max)
+    }
+}
+
+/// The upright size of the image at `path`, read from its header without
+/// decoding the pixels. `None` for files whose size isn't known until they're
+/// decoded or rendered (RAW, SVG and PDF) and for unreadable files.
+pub fn read_dimensions(path: &Path) -> Option<(u32, u32)> {
+    let mut decoder = ImageReader::open(path).ok()?.with_guessed_format().ok()?.into_decoder().ok()?;
+    let (width, height) = decoder.dimensions();
+    // Rotated images are cropped after being turned upright
+    match decoder.orientation().unwrap_or(Orientation::NoTransforms) {
+        Orientation::Rotate90
+        | Orientation::Rotate270
+        | Orientation::Rotate90FlipH
+        | Orientation::Rotate270FlipH => Some((height, width)),
+        _ => Some((width, height)),
+    }
+}
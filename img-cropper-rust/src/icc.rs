@@ -0,0 +1,57 @@
+//! ICC color profile conversion, for outputs whose format can't embed the
+//! source's profile.
+
+use image::DynamicImage;
+use moxcms::{CmsError, ColorProfile, DataColorSpace, Layout, TransformOptions};
+
+/// Converts `img` from the RGB color space described by `icc` to sRGB.
+/// Pixels are left as they are when the profile can't be used.
+pub fn to_srgb(img: DynamicImage, icc: &[u8]) -> DynamicImage {
+    let Ok(profile) = ColorProfile::new_from_slice(icc) else {
+        return img;
+    };
+    if profile.color_space != DataColorSpace::Rgb {
+        return img;
+    }
+
+    let eight_bit = matches!(
+        img,
+        DynamicImage::ImageLuma8(_)
+            | DynamicImage::ImageLumaA8(_)
+            | DynamicImage::ImageRgb8(_)
+            | DynamicImage::ImageRgba8(_)
+    );
+    let converted = match (eight_bit, img.color().has_alpha()) {
+        (true, false) => {
+            let mut pixels = img.to_rgb8();
+            transform_8bit(&profile, Layout::Rgb, &mut pixels).map(|_| DynamicImage::ImageRgb8(pixels))
+        }
+        (true, true) => {
+            let mut pixels = img.to_rgba8();
+            transform_8bit(&profile, Layout::Rgba, &mut pixels).map(|_| DynamicImage::ImageRgba8(pixels))
+        }
+        (false, false) => {
+            let mut pixels = img.to_rgb16();
+            transform_16bit(&profile, Layout::Rgb, &mut pixels).map(|_| DynamicImage::ImageRgb16(pixels))
+        }
+        (false, true) => {
+            let mut pixels = img.to_rgba16();
+            transform_16bit(&profile, Layout::Rgba, &mut pixels).map(|_| DynamicImage::ImageRgba16(pixels))
+        }
+    };
+    converted.unwrap_or(img)
+}
+
+fn transform_8bit(profile: &ColorProfile, layout: Layout, pixels: &mut [u8]) -> Result<(), CmsError> {
+    let transform =
+        profile.create_transform_8bit(layout, &ColorProfile::new_srgb(), layout, TransformOptions::default())?;
+    let source = pixels.to_vec();
+    transform.transform(&source, pixels)
+}
+
+fn transform_16bit(profile: &ColorProfile, layout: Layout, pixels: &mut [u16]) -> Result<(), CmsError> {
+    let transform =
+        profile.create_transform_16bit(layout, &ColorProfile::new_srgb(), layout, TransformOptions::default())?;
+    let source = pixels.to_vec();
+    transform.transform(&source, pixels)
+}
@@ -0,0 +1,271 @@
+//! A builder for cropping a single image from code.
+
+use crate::{
+    caption::Caption,
+    crop_image,
+    effects::{Adjust, Border, Mask, Sharpen, Watermark},
+    output_file_path,
+    strategy::Smart,
+    suffixed_path, CropError, CropOptions, CropStrategy, Cropped, Filter, Gravity, IfExists, Margins, Mode, NoUpscale,
+    OutputFormat, Rect, Rotate, Tiles,
+};
+use image::Rgba;
+use std::{path::PathBuf, sync::Arc};
+
+/// One image to crop, configured one setting at a time:
+///
+/// ```no_run
+/// use img_cropper_rust::{CropJob, Gravity, OutputFormat};
+///
+/// let outputs = CropJob::new("photo.jpg")
+///     .size(400, 300)
+///     .gravity(Gravity::North)
+///     .format(OutputFormat::Webp)
+///     .run()?;
+/// # Ok::<(), img_cropper_rust::CropError>(())
+/// ```
+///
+/// Anything not set keeps the [`CropOptions`] default.
+pub struct CropJob {
+    input: PathBuf,
+    output: Option<PathBuf>,
+    format: Option<OutputFormat>,
+    options: CropOptions,
+}
+
+impl CropJob {
+    pub fn new(input: impl Into<PathBuf>) -> Self {
+        CropJob {
+            input: input.into(),
+            output: None,
+            format: None,
+            options: CropOptions::default(),
+        }
+    }
+
+    /// Where to write the result. Defaults to `name_cropped.ext` next to the
+    /// input; the extension follows [`CropJob::format`] either way.
+    pub fn output(mut self, output: impl Into<PathBuf>) -> Self {
+        self.output = Some(output.into());
+        self
+    }
+
+    /// Adds a target size. Each size gets its own output, named `name_WxH.ext`
+    /// when there are several.
+    pub fn size(mut self, width: u32, height: u32) -> Self {
+        self.options.sizes.push((width, height));
+        self
+    }
+
+    pub fn mode(mut self, mode: Mode) -> Self {
+        self.options.mode = mode;
+        self
+    }
+
+    pub fn gravity(mut self, gravity: Gravity) -> Self {
+        self.options.focus = gravity.focal_point();
+        self.options.strategy = None;
+        self
+    }
+
+    /// Centers the crop on a point given as fractions of the width and height.
+    pub fn focus(mut self, x: f64, y: f64) -> Self {
+        self.options.focus = (x.clamp(0.0, 1.0), y.clamp(0.0, 1.0));
+        self.options.strategy = None;
+        self
+    }
+
+    /// Centers the crop on the most detailed region instead of the gravity.
+    pub fn smart(self) -> Self {
+        self.strategy(Smart)
+    }
+
+    /// Chooses the crop window with `strategy` instead of the gravity.
+    pub fn strategy(mut self, strategy: impl CropStrategy + 'static) -> Self {
+        self.options.strategy = Some(Arc::new(strategy));
+        self
+    }
+
+    pub fn filter(mut self, filter: Filter) -> Self {
+        self.options.filter = filter.into();
+        self
+    }
+
+    /// Resizes in linear light instead of sRGB.
+    pub fn linear(mut self) -> Self {
+        self.options.linear = true;
+        self
+    }
+
+    pub fn format(mut self, format: OutputFormat) -> Self {
+        self.format = Some(format);
+        self
+    }
+
+    /// Fill color for [`Mode::Pad`].
+    pub fn background(mut self, background: Rgba<u8>) -> Self {
+        self.options.background = background;
+        self
+    }
+
+    /// Fills the padding of [`Mode::Pad`] with a blurred copy of the image.
+    pub fn blur_background(mut self) -> Self {
+        self.options.blur_background = true;
+        self
+    }
+
+    pub fn sharpen(mut self, sharpen: Sharpen) -> Self {
+        self.options.sharpen = Some(sharpen);
+        self
+    }
+
+    /// Stretches the levels of the image, by a strength from 0 to 1.
+    pub fn auto_enhance(mut self, strength: f32) -> Self {
+        self.options.auto_enhance = Some(strength.clamp(0.0, 1.0));
+        self
+    }
+
+    pub fn adjust(mut self, adjust: Adjust) -> Self {
+        self.options.adjust = Some(adjust);
+        self
+    }
+
+    pub fn border(mut self, border: Border) -> Self {
+        self.options.border = Some(border);
+        self
+    }
+
+    /// Cuts the output to a shape, leaving the rest transparent.
+    pub fn mask(mut self, mask: Mask) -> Self {
+        self.options.mask = Some(mask);
+        self
+    }
+
+    pub fn watermark(mut self, watermark: Watermark) -> Self {
+        self.options.watermark = Some(watermark);
+        self
+    }
+
+    pub fn caption(mut self, caption: Caption) -> Self {
+        self.options.caption = Some(caption);
+        self
+    }
+
+    /// Converts the output to luma.
+    pub fn grayscale(mut self) -> Self {
+        self.options.grayscale = true;
+        self
+    }
+
+    pub fn no_upscale(mut self, policy: NoUpscale) -> Self {
+        self.options.no_upscale = Some(policy);
+        self
+    }
+
+    /// Turns the image clockwise before anything else.
+    pub fn rotate(mut self, rotate: Rotate) -> Self {
+        self.options.rotate = Some(rotate);
+        self
+    }
+
+    /// Mirrors the image left to right, once it's turned.
+    pub fn flip_h(mut self) -> Self {
+        self.options.flip_h = true;
+        self
+    }
+
+    /// Mirrors the image top to bottom, once it's turned.
+    pub fn flip_v(mut self) -> Self {
+        self.options.flip_v = true;
+        self
+    }
+
+    /// Cuts this region out of the image, once it's turned.
+    pub fn rect(mut self, rect: Rect) -> Self {
+        self.options.rect = Some(rect);
+        self
+    }
+
+    /// Crops to this width/height ratio before resizing.
+    pub fn ratio(mut self, ratio: f64) -> Self {
+        self.options.ratio = Some(ratio);
+        self
+    }
+
+    pub fn margins(mut self, margins: Margins) -> Self {
+        self.options.margins = Some(margins);
+        self
+    }
+
+    /// Trims solid borders within this per-channel tolerance.
+    pub fn trim_borders(mut self, tolerance: u8) -> Self {
+        self.options.trim_tolerance = Some(tolerance);
+        self
+    }
+
+    /// Removes black bars no brighter than this luma.
+    pub fn remove_bars(mut self, threshold: u8) -> Self {
+        self.options.bar_threshold = Some(threshold);
+        self
+    }
+
+    /// Fails images with more pixels than this before decoding them.
+    pub fn max_input_pixels(mut self, max: u64) -> Self {
+        self.options.max_input_pixels = Some(max);
+        self
+    }
+
+    /// Fails images wider or taller than this before decoding them.
+    pub fn max_input_dimension(mut self, max: u32) -> Self {
+        self.options.max_input_dimension = Some(max);
+        self
+    }
+
+    pub fn tiles(mut self, tiles: Tiles) -> Self {
+        self.options.tiles = Some(tiles);
+        self
+    }
+
+    pub fn jpeg_quality(mut self, quality: u8) -> Self {
+        self.options.encode.jpeg_quality = quality.clamp(1, 100);
+        self
+    }
+
+    /// Writes progressive JPEGs instead of baseline ones.
+    pub fn jpeg_progressive(mut self) -> Self {
+        self.options.encode.jpeg_progressive = true;
+        self
+    }
+
+    /// Writes lossy WebP at this quality instead of lossless WebP.
+    pub fn webp_quality(mut self, quality: f32) -> Self {
+        self.options.encode.webp_quality = Some(quality.clamp(0.0, 100.0));
+        self
+    }
+
+    pub fn avif_quality(mut self, quality: u8) -> Self {
+        self.options.encode.avif_quality = quality.clamp(1, 100);
+        self
+    }
+
+    /// Drops EXIF metadata from the output; the color profile is kept.
+    pub fn strip_metadata(mut self) -> Self {
+        self.options.strip_metadata = true;
+        self
+    }
+
+    pub fn if_exists(mut self, policy: IfExists) -> Self {
+        self.options.if_exists = policy;
+        self
+    }
+
+    /// Crops the image, returning every output it produced.
+    pub fn run(self) -> Result<Cropped, CropError> {
+        if self.options.sizes.iter().any(|&(width, height)| width == 0 || height == 0) {
+            return Err(CropError::Other("Width and height must be positive integers.".to_string()));
+        }
+        let output = self.output.unwrap_or_else(|| suffixed_path(&self.input, "cropped"));
+        let output = output_file_path(&self.input, &output, self.format, self.options.mask.is_some());
+        crop_image(&self.input, &output, &self.options)
+    }
+}
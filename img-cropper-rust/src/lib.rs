@@ -0,0 +1,1428 @@
+//! Image cropping and resizing, as used by the `img-cropper-rust` command.
+//!
+//! [`crop_image`] runs the whole pipeline for one file: decoding, region
+//! options, resizing to every target size, effects and encoding.
+//! [`CropJob`] configures and runs the same for a single file, and
+//! [`BatchProcessor`] finds the images of a run and crops them in parallel.
+
+use animation::Frame;
+use encode::Metadata;
+use clap::ValueEnum;
+use image::{
+    codecs::png::{CompressionType, FilterType as PngFilterType},
+    imageops::FilterType,
+    ColorType, DynamicImage, GenericImageView, ImageError, Rgba, RgbaImage,
+};
+use std::{
+    fmt, fs, io,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+};
+
+mod animation;
+pub mod archive;
+mod batch;
+pub mod cache;
+pub mod checkpoint;
+pub mod caption;
+pub mod download;
+pub mod effects;
+pub mod encode;
+#[cfg(feature = "faces")]
+pub mod faces;
+pub mod failures;
+pub mod filter;
+mod icc;
+mod job;
+mod linear;
+pub mod manifest;
+pub mod memory;
+pub mod naming;
+#[cfg(feature = "pdf")]
+mod pdf;
+mod quantize;
+mod raw;
+#[cfg(feature = "remote")]
+pub mod remote;
+pub mod report;
+#[cfg(feature = "s3")]
+pub mod s3;
+mod seam_carve;
+pub mod serve;
+mod smart;
+pub mod strategy;
+mod strips;
+mod svg;
+mod trim;
+pub mod watch;
+
+pub use batch::{BatchProcessor, BatchSummary, Event, SkipReason};
+pub use job::CropJob;
+pub use strategy::CropStrategy;
+
+/// Why an image couldn't be cropped.
+#[derive(Debug)]
+pub enum CropError {
+    /// Reading the input or writing an output failed
+    Io(io::Error),
+    /// The image couldn't be decoded or encoded
+    Image(ImageError),
+    /// Any other failure, such as an unsupported option for the image
+    Other(String),
+}
+
+impl CropError {
+    /// Whether trying again might work: reading or writing files can fail
+    /// for a moment on network drives or files locked by other programs,
+    /// but an image that doesn't decode never will.
+    pub fn is_transient(&self) -> bool {
+        matches!(self, CropError::Io(_) | CropError::Image(ImageError::IoError(_)))
+    }
+}
+
+impl fmt::Display for CropError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            CropError::Io(e) => e.fmt(f),
+            CropError::Image(e) => e.fmt(f),
+            CropError::Other(message) => f.write_str(message),
+        }
+    }
+}
+
+impl std::error::Error for CropError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            CropError::Io(e) => Some(e),
+            CropError::Image(e) => Some(e),
+            CropError::Other(_) => None,
+        }
+    }
+}
+
+impl From<io::Error> for CropError {
+    fn from(e: io::Error) -> Self {
+        CropError::Io(e)
+    }
+}
+
+impl From<ImageError> for CropError {
+    fn from(e: ImageError) -> Self {
+        CropError::Image(e)
+    }
+}
+
+impl From<String> for CropError {
+    fn from(message: String) -> Self {
+        CropError::Other(message)
+    }
+}
+
+/// The decoders and encoders report errors of many types; the common ones
+/// keep their type and the rest are kept as their message.
+impl From<Box<dyn std::error::Error>> for CropError {
+    fn from(e: Box<dyn std::error::Error>) -> Self {
+        match e.downcast::<io::Error>() {
+            Ok(e) => CropError::Io(*e),
+            Err(e) => match e.downcast::<ImageError>() {
+                Ok(e) => CropError::Image(*e),
+                Err(e) => CropError::Other(e.to_string()),
+            },
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum Mode {
+    /// Resize to cover the target size and crop the overflow
+    Crop,
+    /// Resize to fit inside the target size and fill the rest with the background
+    Pad,
+    /// Resize to fit inside the target size without cropping or padding
+    Fit,
+    /// Resize to cover the target size and remove low-detail seams instead of cropping
+    SeamCarve,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum Filter {
+    /// Sharpest and slowest
+    Lanczos3,
+    /// Cubic, nearly as sharp as Lanczos3
+    #[value(name = "catmullrom")]
+    CatmullRom,
+    /// Bilinear, fast with good quality for downscaling
+    #[value(alias = "bilinear")]
+    Triangle,
+    /// Fastest, but blocky
+    Nearest,
+    /// Smooth, slightly blurry
+    Gaussian,
+}
+
+impl From<Filter> for FilterType {
+    fn from(filter: Filter) -> Self {
+        match filter {
+            Filter::Lanczos3 => FilterType::Lanczos3,
+            Filter::CatmullRom => FilterType::CatmullRom,
+            Filter::Triangle => FilterType::Triangle,
+            Filter::Nearest => FilterType::Nearest,
+            Filter::Gaussian => FilterType::Gaussian,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum OutputFormat {
+    /// WebP, tuned with --webp-quality or --webp-lossless
+    Webp,
+    /// JPEG, tuned with --jpeg-quality
+    #[value(alias = "jpg")]
+    Jpeg,
+    /// PNG, tuned with --png-compression and --png-filter
+    Png,
+    /// AVIF, tuned with --avif-quality and --avif-speed
+    Avif,
+    /// Lossless JPEG XL
+    Jxl,
+}
+
+impl OutputFormat {
+    pub fn extension(self) -> &'static str {
+        match self {
+            OutputFormat::Webp => "webp",
+            OutputFormat::Jpeg => "jpg",
+            OutputFormat::Png => "png",
+            OutputFormat::Avif => "avif",
+            OutputFormat::Jxl => "jxl",
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum PngCompression {
+    /// Quick encoding with larger files
+    Fast,
+    /// Balanced encoding speed and size
+    Default,
+    /// Smallest files, slowest encoding
+    Best,
+}
+
+impl From<PngCompression> for CompressionType {
+    fn from(compression: PngCompression) -> Self {
+        match compression {
+            PngCompression::Fast => CompressionType::Fast,
+            PngCompression::Default => CompressionType::Default,
+            PngCompression::Best => CompressionType::Best,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum PngFilter {
+    /// No filtering, best for images with few colors
+    None,
+    /// Difference from the pixel to the left
+    Sub,
+    /// Difference from the pixel above
+    Up,
+    /// Difference from the average of the pixels to the left and above
+    Avg,
+    /// Difference from a prediction based on the left, above and upper-left pixels
+    Paeth,
+    /// Choose the best filter for each row
+    Adaptive,
+}
+
+impl From<PngFilter> for PngFilterType {
+    fn from(filter: PngFilter) -> Self {
+        match filter {
+            PngFilter::None => PngFilterType::NoFilter,
+            PngFilter::Sub => PngFilterType::Sub,
+            PngFilter::Up => PngFilterType::Up,
+            PngFilter::Avg => PngFilterType::Avg,
+            PngFilter::Paeth => PngFilterType::Paeth,
+            PngFilter::Adaptive => PngFilterType::Adaptive,
+        }
+    }
+}
+
+/// How much of the color detail JPEG outputs keep, next to the full brightness detail.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum JpegSubsampling {
+    /// Full color detail, for text and sharp edges
+    #[value(name = "444")]
+    Yuv444,
+    /// Half the color detail across
+    #[value(name = "422")]
+    Yuv422,
+    /// Half the color detail across and down, for the smallest photos
+    #[value(name = "420")]
+    Yuv420,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum NoUpscale {
+    /// Don't write any output for sizes that would need upscaling
+    Skip,
+    /// Crop or pad at the image's native resolution instead of upscaling
+    Native,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum AlreadySized {
+    /// Copy the file to the output as it is
+    Copy,
+    /// Don't write any output
+    Skip,
+}
+
+/// Where `--border` draws the frame.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum BorderPlacement {
+    /// Over the edges of the output, keeping the target size
+    Inside,
+    /// Around the output, making it larger than the target size
+    Outside,
+}
+
+/// Clockwise turn applied to every image before anything else.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum Rotate {
+    #[value(name = "90")]
+    Rotate90,
+    #[value(name = "180")]
+    Rotate180,
+    #[value(name = "270")]
+    Rotate270,
+    /// Turn 90 degrees when the image is portrait and the first target size
+    /// landscape, or the other way around
+    Auto,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum Collision {
+    /// Stop before processing anything
+    Error,
+    /// Number the later outputs: `name_2.ext`, `name_3.ext`, ...
+    Suffix,
+    /// Only process the first of the inputs
+    Skip,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum IfExists {
+    /// Replace the existing file
+    Overwrite,
+    /// Leave the existing file alone
+    Skip,
+    /// Fail the image
+    Error,
+    /// Number the new output: `name_2.ext`, `name_3.ext`, ...
+    Rename,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum Gravity {
+    Center,
+    North,
+    South,
+    East,
+    West,
+    Northeast,
+    Northwest,
+    Southeast,
+    Southwest,
+}
+
+impl Gravity {
+    /// Focal point equivalent to this gravity, as fractions of the image
+    /// width and height.
+    pub fn focal_point(self) -> (f64, f64) {
+        match self {
+            Gravity::Center => (0.5, 0.5),
+            Gravity::North => (0.5, 0.0),
+            Gravity::South => (0.5, 1.0),
+            Gravity::East => (1.0, 0.5),
+            Gravity::West => (0.0, 0.5),
+            Gravity::Northeast => (1.0, 0.0),
+            Gravity::Northwest => (0.0, 0.0),
+            Gravity::Southeast => (1.0, 1.0),
+            Gravity::Southwest => (0.0, 1.0),
+        }
+    }
+}
+
+/// A region of an image in pixels.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Rect {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
+/// Fractions of an image to remove from each side.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Margins {
+    pub top: f64,
+    pub right: f64,
+    pub bottom: f64,
+    pub left: f64,
+}
+
+/// One output file of an input image.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Output {
+    /// Written by this run
+    Written(OutputFile),
+    /// Already existed and was left alone (`--if-exists skip`)
+    Kept(PathBuf),
+}
+
+/// A file written for an input image.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct OutputFile {
+    pub path: PathBuf,
+    /// Pixel size of the written image
+    pub dimensions: (u32, u32),
+    /// Region of the upright source image the output shows, after any
+    /// [`CropOptions::rotate`] and flips
+    pub region: Rect,
+    /// Size of the written file
+    pub bytes: u64,
+}
+
+/// Everything [`crop_image`] produced for one input.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Cropped {
+    /// Pixel size of the upright source image (the first page of a PDF)
+    pub source_dimensions: Option<(u32, u32)>,
+    pub outputs: Vec<Output>,
+}
+
+/// How to split an output image into tiles.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Tiles {
+    /// A fixed number of columns and rows
+    Grid(u32, u32),
+    /// Tiles of a fixed size, dropping partial tiles at the edges
+    Size(u32, u32),
+}
+
+/// Settings shared by every image in a run. The defaults match the
+/// command's: nothing is trimmed, resized or renamed until a field says so.
+#[derive(Clone)]
+pub struct CropOptions {
+    /// Target sizes; each one gets its own output. Empty writes the
+    /// preprocessed image at its own size
+    pub sizes: Vec<(u32, u32)>,
+    pub mode: Mode,
+    pub filter: FilterType,
+    /// Resize in linear light instead of sRGB
+    pub linear: bool,
+    pub sharpen: Option<effects::Sharpen>,
+    pub no_upscale: Option<NoUpscale>,
+    pub already_sized: Option<AlreadySized>,
+    pub rotate: Option<Rotate>,
+    /// Mirror every image left to right, after `rotate`
+    pub flip_h: bool,
+    /// Mirror every image top to bottom, after `rotate`
+    pub flip_v: bool,
+    /// Stretch each image's levels before resizing, by this strength from 0 to 1
+    pub auto_enhance: Option<f32>,
+    pub adjust: Option<effects::Adjust>,
+    pub border: Option<effects::Border>,
+    /// Cut out once the overlays are drawn; see [`output_file_path`] for
+    /// the outputs that become PNGs to keep the transparency
+    pub mask: Option<effects::Mask>,
+    pub watermark: Option<effects::Watermark>,
+    pub caption: Option<caption::Caption>,
+    /// Convert the outputs to luma once they're cropped
+    pub grayscale: bool,
+    /// Fail images with more pixels than this, before decoding them
+    pub max_input_pixels: Option<u64>,
+    /// Fail images wider or taller than this, before decoding them
+    pub max_input_dimension: Option<u32>,
+    /// Exact region to cut out of every image first
+    pub rect: Option<Rect>,
+    /// Trim solid borders within this per-channel tolerance
+    pub trim_tolerance: Option<u8>,
+    /// Remove black bars no brighter than this luma
+    pub bar_threshold: Option<u8>,
+    pub margins: Option<Margins>,
+    /// Crop to this width/height ratio before resizing
+    pub ratio: Option<f64>,
+    /// Focal point the crop window is centered on, as fractions of the
+    /// image, and where padded images are placed
+    pub focus: (f64, f64),
+    /// Chooses the crop window instead of centering it on `focus`
+    pub strategy: Option<Arc<dyn CropStrategy>>,
+    pub tiles: Option<Tiles>,
+    /// Fill color for [`Mode::Pad`]
+    pub background: Rgba<u8>,
+    /// Fill the padding with a blurred copy of the image instead of `background`
+    pub blur_background: bool,
+    pub encode: encode::EncodeSettings,
+    /// Drop EXIF metadata from the outputs; color profiles are kept
+    pub strip_metadata: bool,
+    /// Output file name template; see [`naming::render`]
+    pub name_template: Option<String>,
+    pub if_exists: IfExists,
+    /// Outputs replace their inputs, once written in full
+    pub in_place: bool,
+    /// With `in_place`, keep each original under its name plus this suffix
+    pub backup_suffix: Option<String>,
+    /// Let SVGs draw images from other files, not just ones embedded as
+    /// `data:` URLs; off for SVGs from people who mustn't read those files
+    pub svg_linked_images: bool,
+}
+
+impl Default for CropOptions {
+    fn default() -> Self {
+        CropOptions {
+            sizes: Vec::new(),
+            mode: Mode::Crop,
+            filter: Filter::Lanczos3.into(),
+            linear: false,
+            sharpen: None,
+            no_upscale: None,
+            already_sized: None,
+            rotate: None,
+            flip_h: false,
+            flip_v: false,
+            auto_enhance: None,
+            adjust: None,
+            border: None,
+            mask: None,
+            watermark: None,
+            caption: None,
+            grayscale: false,
+            max_input_pixels: None,
+            max_input_dimension: None,
+            rect: None,
+            trim_tolerance: None,
+            bar_threshold: None,
+            margins: None,
+            ratio: None,
+            focus: Gravity::Center.focal_point(),
+            strategy: None,
+            tiles: None,
+            background: Rgba([0, 0, 0, 255]),
+            blur_background: false,
+            encode: encode::EncodeSettings::default(),
+            strip_metadata: false,
+            name_template: None,
+            if_exists: IfExists::Overwrite,
+            in_place: false,
+            backup_suffix: None,
+            svg_linked_images: true,
+        }
+    }
+}
+
+/// Parses a `WxH` size such as `400x300`.
+pub fn parse_size(size_str: &str) -> Result<(u32, u32), String> {
+    let parts: Vec<&str> = size_str.split('x').collect();
+    if parts.len() != 2 {
+        return Err("Invalid size format. Please use WxH (e.g., 400x300).".to_string());
+    }
+
+    let width = parts[0]
+        .parse::<u32>()
+        .map_err(|_| "Invalid width. Must be a positive integer.".to_string())?;
+    let height = parts[1]
+        .parse::<u32>()
+        .map_err(|_| "Invalid height. Must be a positive integer.".to_string())?;
+
+    if width == 0 || height == 0 {
+        return Err("Width and height must be positive integers.".to_string());
+    }
+
+    Ok((width, height))
+}
+
+/// Whether files with the lowercase extension `ext` can be cropped.
+pub fn is_supported_image_extension(ext: &str) -> bool {
+    matches!(ext, "jpg" | "jpeg" | "png" | "gif" | "webp" | "jxl" | "tif" | "tiff" | "bmp")
+        || (cfg!(feature = "heic") && matches!(ext, "heic" | "heif"))
+        || raw::is_raw_extension(ext)
+        || ext == "svg"
+        || (cfg!(feature = "pdf") && ext == "pdf")
+}
+
+/// Picks a file extension for image data without a file name, such as an
+/// image read from stdin, from its signature.
+pub fn sniff_extension(data: &[u8]) -> Option<String> {
+    if let Ok(format) = image::guess_format(data) {
+        return format.extensions_str().first().map(|ext| ext.to_string());
+    }
+    // Formats decoded outside the image crate
+    let extension = if data.starts_with(&[0xFF, 0x0A]) || data.starts_with(b"\0\0\0\x0cJXL \r\n\x87\n") {
+        "jxl"
+    } else if data.get(4..12).is_some_and(|ftyp| matches!(ftyp, b"ftypheic" | b"ftypheix" | b"ftypmif1")) {
+        "heic"
+    } else if data.starts_with(b"%PDF") {
+        "pdf"
+    } else if has_svg_root(&String::from_utf8_lossy(&data[..data.len().min(4096)])) {
+        "svg"
+    } else {
+        return None;
+    };
+    Some(extension.to_string())
+}
+
+/// Whether the XML document in `start` has an `<svg>` root element, after any
+/// declaration, processing instructions, comments and doctype.
+fn has_svg_root(start: &str) -> bool {
+    let mut rest = start.trim_start_matches('\u{feff}').trim_start();
+    loop {
+        let skipped = if rest.starts_with("<?") {
+            rest.find("?>").map(|end| end + 2)
+        } else if rest.starts_with("<!--") {
+            rest.find("-->").map(|end| end + 3)
+        } else if rest.starts_with("<!") {
+            // A doctype's internal subset may hold '>' of its own
+            let subset = rest.find('[').filter(|&open| rest.find('>').is_some_and(|end| open < end));
+            let from = subset.map_or(Some(0), |open| rest[open..].find(']').map(|close| open + close));
+            from.and_then(|from| rest[from..].find('>').map(|end| from + end + 1))
+        } else {
+            let name = rest.strip_prefix("<svg").and_then(|name| name.chars().next());
+            return name.is_some_and(|next| next.is_whitespace() || next == '>' || next == '/');
+        };
+        let Some(skipped) = skipped else {
+            return false;
+        };
+        rest = rest[skipped..].trim_start();
+    }
+}
+
+/// Crops the image at `input_path` to every target size in `options`,
+/// writing the results to `output_path` or paths derived from it (one per
+/// size, tile or page). Folders are created as needed.
+pub fn crop_image(
+    input_path: &Path,
+    output_path: &Path,
+    options: &CropOptions,
+) -> Result<Cropped, CropError> {
+    crop_named(input_path, input_path, output_path, options, &AtomicBool::new(false))
+}
+
+/// Like [`crop_image`], for an image read from `input_path` that's known as
+/// `name`, such as a copy of an archived one. Captions are filled in from
+/// `name`. Once `cancelled` is set, nothing more is written.
+pub(crate) fn crop_named(
+    input_path: &Path,
+    name: &Path,
+    output_path: &Path,
+    options: &CropOptions,
+    cancelled: &AtomicBool,
+) -> Result<Cropped, CropError> {
+    use image::ImageReader;
+    if let Some(parent) = output_path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    if options.already_sized == Some(AlreadySized::Copy) && is_already_sized(input_path, output_path, options) {
+        let dimensions = filter::read_dimensions(input_path).unwrap_or_default();
+        let region = Rect { x: 0, y: 0, width: dimensions.0, height: dimensions.1 };
+        // Replacing an original with a copy of itself would only churn backups
+        if options.in_place && output_path == input_path {
+            let outputs = vec![Output::Kept(output_path.to_path_buf())];
+            return Ok(Cropped { source_dimensions: Some(dimensions), outputs });
+        }
+        let output = write_output(output_path, dimensions, region, options, cancelled, |path| {
+            fs::copy(input_path, path)?;
+            Ok(())
+        })?;
+        return Ok(Cropped { source_dimensions: Some(dimensions), outputs: vec![output] });
+    }
+    check_input_size(input_path, options)?;
+    let caption = match &options.caption {
+        Some(caption) => Some(naming::render_caption(&caption.template, name, input_path)?),
+        None => None,
+    };
+    let caption = caption.as_deref();
+    let extension = lowercase_extension(input_path);
+    // Every page of a PDF is cropped on its own: `doc.pdf` -> `doc_p001.png`
+    #[cfg(feature = "pdf")]
+    if extension == "pdf" {
+        let (mut source_dimensions, mut outputs) = (None, Vec::new());
+        pdf::rasterize(input_path, &options.sizes, |index, page| {
+            source_dimensions.get_or_insert(page.dimensions());
+            let page_path = suffixed_path(output_path, &format!("p{:03}", index + 1));
+            let frames = vec![Frame::still(page)];
+            outputs.extend(crop_frames(frames, &Metadata::default(), &page_path, caption, options, cancelled)?);
+            Ok(())
+        })?;
+        return Ok(Cropped { source_dimensions, outputs });
+    }
+
+    // Huge images only shrunk are shrunk while they're decoded
+    let shrink_factor = strips::shrink_factor(input_path, options);
+    let full_dimensions = shrink_factor.and_then(|_| filter::read_dimensions(input_path));
+    let (frames, mut metadata) = if let Some(factor) = shrink_factor {
+        strips::decode(input_path, factor)?
+    } else if raw::is_raw_extension(&extension) {
+        (vec![Frame::still(raw::decode(input_path)?)], Metadata::default())
+    } else if extension == "svg" {
+        let img = svg::rasterize(input_path, &options.sizes, options.svg_linked_images)?;
+        (vec![Frame::still(img)], Metadata::default())
+    } else if matches!(extension.as_str(), "gif" | "webp" | "png")
+        && animation::supports_animation(output_path)
+    {
+        animation::decode(input_path)?
+    } else {
+        let mut reader = ImageReader::open(input_path)?;
+        // The image crate's own 512 MiB limit would refuse images the given limits allow
+        if options.max_input_pixels.is_some() || options.max_input_dimension.is_some() {
+            reader.no_limits();
+        }
+        animation::decode_still(reader.into_decoder()?)?
+    };
+    if options.strip_metadata {
+        metadata.exif = None;
+    }
+
+    let dimensions = frames[0].image.dimensions();
+    let mut outputs = crop_frames(frames, &metadata, output_path, caption, options, cancelled)?;
+    // Regions are reported in the source's own pixels, not the shrunk image's
+    if let Some((width, height)) = full_dimensions {
+        for output in &mut outputs {
+            if let Output::Written(file) = output {
+                file.region = scale_rect(file.region, dimensions, Rect { x: 0, y: 0, width, height });
+            }
+        }
+    }
+    Ok(Cropped { source_dimensions: full_dimensions.or(Some(dimensions)), outputs })
+}
+
+/// Fails images over the size limits in `options` from their header, so a
+/// huge image is refused before its pixels take up any memory. Formats whose
+/// size isn't in a header (RAW, SVG and PDF) aren't checked.
+fn check_input_size(input_path: &Path, options: &CropOptions) -> Result<(), CropError> {
+    if options.max_input_pixels.is_none() && options.max_input_dimension.is_none() {
+        return Ok(());
+    }
+    let Some((width, height)) = filter::read_dimensions(input_path) else {
+        return Ok(());
+    };
+    if let Some(max) = options.max_input_dimension.filter(|&max| width.max(height) > max) {
+        return Err(CropError::Other(format!(
+            "The image is {}x{}, over the largest width or height allowed ({})",
+            width, height, max
+        )));
+    }
+    if let Some(max) = options.max_input_pixels.filter(|&max| width as u64 * height as u64 > max) {
+        return Err(CropError::Other(format!(
+            "The image is {}x{}, over the most pixels allowed ({})",
+            width, height, max
+        )));
+    }
+    Ok(())
+}
+
+/// Runs the decoded frames of one image through the pipeline and saves them.
+fn crop_frames(
+    mut frames: Vec<Frame>,
+    metadata: &Metadata,
+    output_path: &Path,
+    caption: Option<&str>,
+    options: &CropOptions,
+    cancelled: &AtomicBool,
+) -> Result<Vec<Output>, Box<dyn std::error::Error>> {
+    let turn = match options.rotate {
+        Some(Rotate::Auto) => {
+            let (width, height) = frames[0].image.dimensions();
+            let turned = options.sizes.first().is_some_and(|&(to_width, to_height)| {
+                (width > height && to_width < to_height) || (width < height && to_width > to_height)
+            });
+            turned.then_some(Rotate::Rotate90)
+        }
+        turn => turn,
+    };
+    for frame in &mut frames {
+        match turn {
+            Some(Rotate::Rotate90) => frame.image = frame.image.rotate90(),
+            Some(Rotate::Rotate180) => frame.image = frame.image.rotate180(),
+            Some(Rotate::Rotate270) => frame.image = frame.image.rotate270(),
+            Some(Rotate::Auto) | None => {}
+        }
+        if options.flip_h {
+            frame.image = frame.image.fliph();
+        }
+        if options.flip_v {
+            frame.image = frame.image.flipv();
+        }
+    }
+
+    // The region is found on the first frame and cut out of every frame, so
+    // trimming can't make an animation jitter
+    let (first, region) = preprocess(std::mem::take(&mut frames[0].image), options)?;
+    frames[0].image = first;
+    for frame in &mut frames[1..] {
+        frame.image = frame.image.crop_imm(region.x, region.y, region.width, region.height);
+    }
+    // Levels come from what's left once the borders are trimmed
+    if let Some(strength) = options.auto_enhance {
+        let levels = effects::find_levels(&frames[0].image);
+        frames = animation::map(&frames, |img| effects::apply_levels(img, levels, strength));
+    }
+
+    // Without a size the preprocessed image is written as is
+    if options.sizes.is_empty() {
+        return save_output(finish(frames, caption, options)?, metadata, output_path, region, options, cancelled);
+    }
+
+    let mut written = Vec::new();
+    for &size in &options.sizes {
+        let first = &frames[0].image;
+        let native = match options.no_upscale {
+            Some(policy) if needs_upscale(first.dimensions(), size, options.mode) => Some(policy),
+            _ => None,
+        };
+        // The part of the source each output shows, for the caller's records
+        let mut shown = region;
+        let output_frames = match (options.mode, native) {
+            (_, Some(NoUpscale::Skip)) => continue,
+            (Mode::SeamCarve, _) if frames.len() > 1 => {
+                return Err("Seam carving animated images is not supported".into());
+            }
+            (Mode::Crop, Some(NoUpscale::Native)) => {
+                let window = ratio_window(first, size.0 as f64 / size.1 as f64, options);
+                shown = scale_rect(window, first.dimensions(), region);
+                animation::map(&frames, |img| crop_to(img, window))
+            }
+            (Mode::Fit, Some(NoUpscale::Native)) => frames.clone(),
+            (Mode::SeamCarve, Some(NoUpscale::Native)) => animation::map(&frames, |img| {
+                seam_carve::retarget(img, ratio_size(img.dimensions(), size.0 as f64 / size.1 as f64))
+            }),
+            (Mode::Crop, None) => {
+                let resized = animation::map(&frames, |img| resize_to_cover(img, size, options));
+                let window = find_window(&resized[0].image, size, options);
+                shown = scale_rect(window, resized[0].image.dimensions(), region);
+                animation::map(&resized, |img| crop_to(img, window))
+            }
+            (Mode::Pad, _) => animation::map(&frames, |img| pad_to_fit(img, size, options)),
+            (Mode::Fit, None) => animation::map(&frames, |img| resize(img, size.0, size.1, options)),
+            (Mode::SeamCarve, None) => animation::map(&frames, |img| {
+                seam_carve::retarget(&resize_to_cover(img, size, options), size)
+            }),
+        };
+
+        let output_frames = match options.sharpen {
+            Some(settings) => animation::map(&output_frames, |img| effects::sharpen(img, settings)),
+            None => output_frames,
+        };
+
+        let size_path = size_output_path(output_path, size, options);
+        let output_frames = finish(output_frames, caption, options)?;
+        written.extend(save_output(output_frames, metadata, &size_path, shown, options, cancelled)?);
+    }
+
+    Ok(written)
+}
+
+/// Applies the effects that every output gets last, whatever its size.
+/// `caption` is the input's caption text, still missing the dimensions.
+fn finish(
+    mut frames: Vec<Frame>,
+    caption: Option<&str>,
+    options: &CropOptions,
+) -> Result<Vec<Frame>, Box<dyn std::error::Error>> {
+    if let Some(adjust) = options.adjust {
+        frames = animation::map(&frames, |img| effects::adjust(img, adjust));
+    }
+    if let Some(border) = options.border {
+        frames = animation::map(&frames, |img| effects::border(img, border));
+    }
+    if let Some(watermark) = &options.watermark {
+        frames = animation::map(&frames, |img| effects::watermark(img, watermark));
+    }
+    if let (Some(text), Some(settings)) = (caption, &options.caption) {
+        let (width, height) = frames[0].image.dimensions();
+        let text = text.replace("{width}", &width.to_string()).replace("{height}", &height.to_string());
+        let strip = settings.render(&text, (width, height))?;
+        frames = animation::map(&frames, |img| effects::watermark(img, &strip));
+    }
+    if let Some(mask) = options.mask {
+        frames = animation::map(&frames, |img| effects::mask(img, mask));
+    }
+    if options.grayscale {
+        frames = animation::map(&frames, effects::grayscale);
+    }
+    Ok(frames)
+}
+
+/// Keeps outputs of different sizes apart: `name.ext` -> `name_WxH.ext`.
+fn size_output_path(output_path: &Path, size: (u32, u32), options: &CropOptions) -> PathBuf {
+    let named_by_size = options.name_template.as_deref().is_some_and(naming::has_dimensions);
+    if options.sizes.len() > 1 && !named_by_size {
+        suffixed_path(output_path, &format!("{}x{}", size.0, size.1))
+    } else {
+        output_path.to_path_buf()
+    }
+}
+
+/// Where [`crop_image`] would write the outputs for `output_path`, as far as
+/// that's known without decoding the image: tiles and PDF pages get their
+/// suffixes, and fitted images their dimensions, only once they're cropped.
+pub(crate) fn planned_outputs(output_path: &Path, options: &CropOptions) -> Vec<PathBuf> {
+    if options.sizes.is_empty() {
+        return vec![output_path.to_path_buf()];
+    }
+    options
+        .sizes
+        .iter()
+        .map(|&size| {
+            let path = size_output_path(output_path, size, options);
+            match (&options.name_template, options.mode) {
+                (Some(_), Mode::Crop | Mode::Pad | Mode::SeamCarve) => naming::fill_dimensions(&path, size),
+                _ => path,
+            }
+        })
+        .collect()
+}
+
+/// Applies the region options (rectangle, trimming, margins and ratio) to
+/// `img`, returning the result and the region it was cut from.
+fn preprocess(
+    mut img: DynamicImage,
+    options: &CropOptions,
+) -> Result<(DynamicImage, Rect), Box<dyn std::error::Error>> {
+    let (width, height) = img.dimensions();
+    let mut region = Rect { x: 0, y: 0, width, height };
+    let mut narrow = |img: &mut DynamicImage, bounds: Rect| {
+        *img = crop_to(img, bounds);
+        region.x += bounds.x;
+        region.y += bounds.y;
+        region.width = bounds.width;
+        region.height = bounds.height;
+    };
+
+    if let Some(rect) = options.rect {
+        let bounds = check_rect(&img, rect)?;
+        narrow(&mut img, bounds);
+    }
+    if let Some(tolerance) = options.trim_tolerance {
+        if let Some(bounds) = trim::find_content_bounds(&img, tolerance) {
+            narrow(&mut img, bounds);
+        }
+    }
+    if let Some(threshold) = options.bar_threshold {
+        if let Some(bounds) = trim::find_letterbox_bounds(&img, threshold) {
+            narrow(&mut img, bounds);
+        }
+    }
+    if let Some(margins) = options.margins {
+        let bounds = margin_bounds(&img, margins);
+        narrow(&mut img, bounds);
+    }
+
+    if let Some(ratio) = options.ratio {
+        let bounds = ratio_window(&img, ratio, options);
+        narrow(&mut img, bounds);
+    }
+
+    Ok((img, region))
+}
+
+/// Saves `frames`, showing `region` of the source, to `output_path`, or as
+/// tiles next to it when tiling is on.
+fn save_output(
+    frames: Vec<Frame>,
+    metadata: &Metadata,
+    output_path: &Path,
+    region: Rect,
+    options: &CropOptions,
+    cancelled: &AtomicBool,
+) -> Result<Vec<Output>, Box<dyn std::error::Error>> {
+    let Some(tiles) = options.tiles else {
+        return Ok(vec![save_frames(frames, metadata, output_path, region, options, cancelled)?]);
+    };
+
+    let (width, height) = frames[0].image.dimensions();
+    let (tile_width, tile_height) = match tiles {
+        Tiles::Grid(columns, rows) => (width / columns, height / rows),
+        Tiles::Size(tile_width, tile_height) => (tile_width, tile_height),
+    };
+    if tile_width == 0 || tile_height == 0 || tile_width > width || tile_height > height {
+        return Err(format!("A {}x{} image is too small to split into tiles", width, height).into());
+    }
+
+    let mut written = Vec::new();
+    for row in 0..height / tile_height {
+        for column in 0..width / tile_width {
+            let bounds = Rect {
+                x: column * tile_width,
+                y: row * tile_height,
+                width: tile_width,
+                height: tile_height,
+            };
+            let tile = animation::map(&frames, |img| crop_to(img, bounds));
+            let tile_path = suffixed_path(output_path, &format!("{}_{}", row, column));
+            let tile_region = scale_rect(bounds, (width, height), region);
+            written.push(save_frames(tile, metadata, &tile_path, tile_region, options, cancelled)?);
+        }
+    }
+
+    Ok(written)
+}
+
+/// Writes a single frame as a still image and several as an animation,
+/// unless --if-exists says to leave an existing file alone.
+fn save_frames(
+    frames: Vec<Frame>,
+    metadata: &Metadata,
+    output_path: &Path,
+    region: Rect,
+    options: &CropOptions,
+    cancelled: &AtomicBool,
+) -> Result<Output, Box<dyn std::error::Error>> {
+    let dimensions = frames[0].image.dimensions();
+    write_output(output_path, dimensions, region, options, cancelled, |path| {
+        write_frames(frames, metadata, path, options)
+    })
+}
+
+/// Settles the final path of a `dimensions`-sized output by the naming and
+/// --if-exists options and has `write` create the file there, unless
+/// `cancelled` is set by then.
+fn write_output(
+    output_path: &Path,
+    dimensions: (u32, u32),
+    region: Rect,
+    options: &CropOptions,
+    cancelled: &AtomicBool,
+    write: impl FnOnce(&Path) -> Result<(), Box<dyn std::error::Error>>,
+) -> Result<Output, Box<dyn std::error::Error>> {
+    let mut output_path = match &options.name_template {
+        Some(_) => naming::fill_dimensions(output_path, dimensions),
+        None => output_path.to_path_buf(),
+    };
+    let mut reserved = false;
+    if output_path.exists() {
+        match options.if_exists {
+            IfExists::Overwrite => {}
+            IfExists::Skip => return Ok(Output::Kept(output_path)),
+            IfExists::Error => return Err(format!("{:?} already exists", output_path).into()),
+            IfExists::Rename => {
+                output_path = reserve_free_name(&output_path)?;
+                reserved = true;
+            }
+        }
+    }
+
+    // Outputs only appear under their own name once written in full, so an
+    // interrupted run never leaves a truncated image that a later one skips
+    let temp_path = temp_path(&output_path);
+    let give_up = |e: Box<dyn std::error::Error>| {
+        let _ = fs::remove_file(&temp_path);
+        if reserved {
+            let _ = fs::remove_file(&output_path);
+        }
+        Err(e)
+    };
+    if let Err(e) = write(&temp_path) {
+        return give_up(e);
+    }
+    // An image given up on, such as one that timed out, was already reported
+    if cancelled.load(Ordering::Relaxed) {
+        return give_up("Cropping the image was cancelled".into());
+    }
+    // A reserved name holds nothing worth backing up
+    let replaced = options.in_place && !reserved && output_path.exists();
+    if let Some(suffix) = options.backup_suffix.as_ref().filter(|_| replaced) {
+        let mut backup_path = output_path.clone().into_os_string();
+        backup_path.push(suffix);
+        fs::rename(&output_path, backup_path)?;
+    }
+    if let Err(e) = fs::rename(&temp_path, &output_path) {
+        return give_up(e.into());
+    }
+    let bytes = fs::metadata(&output_path)?.len();
+    Ok(Output::Written(OutputFile { path: output_path, dimensions, region, bytes }))
+}
+
+fn write_frames(
+    mut frames: Vec<Frame>,
+    metadata: &Metadata,
+    output_path: &Path,
+    options: &CropOptions,
+) -> Result<(), Box<dyn std::error::Error>> {
+    if frames.len() > 1 {
+        animation::save(frames, output_path, &options.encode, metadata)
+    } else {
+        let frame = frames.pop().ok_or("No frames to save")?;
+        encode::save_image(frame.image, output_path, &options.encode, metadata)
+    }
+}
+
+/// A hidden file next to `path` with the same extension, so it's encoded
+/// in the same format: `dir/name.ext` -> `dir/.name.tmp.ext`.
+fn temp_path(path: &Path) -> PathBuf {
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let mut file_name = format!(".{}.tmp", stem);
+    if let Some(extension) = path.extension() {
+        file_name.push('.');
+        file_name.push_str(&extension.to_string_lossy());
+    }
+    path.with_file_name(file_name)
+}
+
+/// Whether `path` is one of [`temp_path`]'s, left behind by a run that was
+/// interrupted.
+pub(crate) fn is_temp_path(path: &Path) -> bool {
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    stem.starts_with('.') && stem.ends_with(".tmp")
+}
+
+/// Gives `output_path` the extension of the output format: the --format one,
+/// or else the input's own. HEIC and HEIF become JPEGs, and JPEGs that need
+/// to be `transparent` (see [`CropOptions::mask`]) become PNGs.
+pub fn output_file_path(
+    input_path: &Path,
+    output_path: &Path,
+    format: Option<OutputFormat>,
+    transparent: bool,
+) -> PathBuf {
+    let ext = lowercase_extension(input_path);
+    let mut output_path = output_path.to_path_buf();
+    if let Some(format) = format {
+        output_path.set_extension(format.extension());
+    } else if raw::is_raw_extension(&ext) || ext == "svg" || ext == "pdf" {
+        // RAW, SVG and PDF can't be written back, so these become
+        // PNGs, which keep RAW's full bit depth and SVG's transparency
+        output_path.set_extension("png");
+    } else if matches!(ext.as_str(), "heic" | "heif") {
+        // There's no HEIC encoder; photos lose little as JPEGs
+        output_path.set_extension(if transparent { "png" } else { "jpg" });
+    } else if transparent && matches!(ext.as_str(), "jpg" | "jpeg") {
+        output_path.set_extension("png");
+    }
+    output_path
+}
+
+/// The file's extension in lowercase, or an empty string if it has none.
+pub fn lowercase_extension(path: &Path) -> String {
+    path.extension()
+        .and_then(|ext| ext.to_str())
+        .map(|ext| ext.to_ascii_lowercase())
+        .unwrap_or_default()
+}
+
+/// Claims the first free `<stem>_<n>` name beside `output_path` by creating
+/// it empty, so workers writing next to each other never pick the same one.
+fn reserve_free_name(output_path: &Path) -> io::Result<PathBuf> {
+    for counter in 2..u32::MAX {
+        let candidate = suffixed_path(output_path, &counter.to_string());
+        match fs::OpenOptions::new().write(true).create_new(true).open(&candidate) {
+            Ok(_) => return Ok(candidate),
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists => continue,
+            Err(e) => return Err(e),
+        }
+    }
+    Err(io::Error::other("Ran out of names for the output"))
+}
+
+/// Appends `suffix` to the file stem: `name.ext` -> `name_suffix.ext`.
+pub(crate) fn suffixed_path(output_path: &Path, suffix: &str) -> PathBuf {
+    let stem = output_path.file_stem().unwrap_or_default().to_string_lossy();
+    let mut file_name = format!("{}_{}", stem, suffix);
+    if let Some(ext) = output_path.extension() {
+        file_name.push('.');
+        file_name.push_str(&ext.to_string_lossy());
+    }
+    output_path.with_file_name(file_name)
+}
+
+/// Checks that `rect` lies inside `img`.
+fn check_rect(img: &DynamicImage, rect: Rect) -> Result<Rect, String> {
+    let (original_width, original_height) = img.dimensions();
+    if rect.x as u64 + rect.width as u64 > original_width as u64
+        || rect.y as u64 + rect.height as u64 > original_height as u64
+    {
+        return Err(format!(
+            "Crop rectangle {}x{} at {},{} does not fit inside the {}x{} image",
+            rect.width, rect.height, rect.x, rect.y, original_width, original_height
+        ));
+    }
+
+    Ok(rect)
+}
+
+/// Maps `rect` of a `size`-sized image showing `region` of the source back
+/// onto the source.
+fn scale_rect(rect: Rect, size: (u32, u32), region: Rect) -> Rect {
+    let scale_x = region.width as f64 / size.0.max(1) as f64;
+    let scale_y = region.height as f64 / size.1.max(1) as f64;
+    Rect {
+        x: region.x + (rect.x as f64 * scale_x).round() as u32,
+        y: region.y + (rect.y as f64 * scale_y).round() as u32,
+        width: (rect.width as f64 * scale_x).round() as u32,
+        height: (rect.height as f64 * scale_y).round() as u32,
+    }
+}
+
+fn crop_to(img: &DynamicImage, rect: Rect) -> DynamicImage {
+    img.crop_imm(rect.x, rect.y, rect.width, rect.height)
+}
+
+/// Region left after trimming `margins` off each edge of `img`.
+fn margin_bounds(img: &DynamicImage, margins: Margins) -> Rect {
+    let (width, height) = img.dimensions();
+    let left = (width as f64 * margins.left).round() as u32;
+    let right = (width as f64 * margins.right).round() as u32;
+    let top = (height as f64 * margins.top).round() as u32;
+    let bottom = (height as f64 * margins.bottom).round() as u32;
+
+    // Always keep at least one pixel, even for tiny images
+    let trimmed_width = width.saturating_sub(left + right).max(1);
+    let trimmed_height = height.saturating_sub(top + bottom).max(1);
+
+    Rect {
+        x: left.min(width - 1),
+        y: top.min(height - 1),
+        width: trimmed_width,
+        height: trimmed_height,
+    }
+}
+
+/// Converts `img` to the given color type, e.g. back to the decoded image's
+/// type after processing it in floating point.
+pub(crate) fn convert_color(img: DynamicImage, color: ColorType) -> DynamicImage {
+    match color {
+        ColorType::L8 => DynamicImage::ImageLuma8(img.to_luma8()),
+        ColorType::La8 => DynamicImage::ImageLumaA8(img.to_luma_alpha8()),
+        ColorType::Rgb8 => DynamicImage::ImageRgb8(img.to_rgb8()),
+        ColorType::Rgba8 => DynamicImage::ImageRgba8(img.to_rgba8()),
+        ColorType::L16 => DynamicImage::ImageLuma16(img.to_luma16()),
+        ColorType::La16 => DynamicImage::ImageLumaA16(img.to_luma_alpha16()),
+        ColorType::Rgb16 => DynamicImage::ImageRgb16(img.to_rgb16()),
+        ColorType::Rgba16 => DynamicImage::ImageRgba16(img.to_rgba16()),
+        ColorType::Rgb32F => DynamicImage::ImageRgb32F(img.to_rgb32f()),
+        _ => DynamicImage::ImageRgba32F(img.into_rgba32f()),
+    }
+}
+
+/// Resizes `img` to fit inside `width`x`height` while keeping its aspect
+/// ratio, with the filter and color space chosen in `options`.
+fn resize(img: &DynamicImage, width: u32, height: u32, options: &CropOptions) -> DynamicImage {
+    if options.linear {
+        linear::resize(img, width, height, options.filter)
+    } else {
+        img.resize(width, height, options.filter)
+    }
+}
+
+/// Resizes `img` to the smallest size that covers `target` while keeping its
+/// aspect ratio.
+fn resize_to_cover(img: &DynamicImage, target: (u32, u32), options: &CropOptions) -> DynamicImage {
+    let (target_width, target_height) = (target.0 as u64, target.1 as u64);
+    let (original_width, original_height) = (img.width() as u64, img.height() as u64);
+
+    // In u64, as a gigapixel side times a target side overflows u32, and
+    // rounded up so the resized image still covers the target
+    if original_width * target_height > original_height * target_width {
+        // Original image is wider than the target aspect ratio,
+        // so we resize based on height and then crop width
+        resize(
+            img,
+            (original_width * target_height).div_ceil(original_height) as u32,
+            target.1,
+            options,
+        )
+    } else {
+        // Original image is taller than or has the same aspect ratio as the target,
+        // so we resize based on width and then crop height
+        resize(
+            img,
+            target.0,
+            (original_height * target_width).div_ceil(original_width) as u32,
+            options,
+        )
+    }
+}
+
+/// Whether `input_path` can be copied to `output_path` as it is: it has the
+/// single target size and the output format, and no option would change it.
+pub(crate) fn is_already_sized(input_path: &Path, output_path: &Path, options: &CropOptions) -> bool {
+    let [size] = options.sizes[..] else {
+        return false;
+    };
+    let unchanged = options.rotate.is_none()
+        && !options.flip_h
+        && !options.flip_v
+        && options.auto_enhance.is_none()
+        && options.adjust.is_none()
+        && options.border.is_none()
+        && options.mask.is_none()
+        && options.watermark.is_none()
+        && options.caption.is_none()
+        && !options.grayscale
+        && options.rect.is_none()
+        && options.trim_tolerance.is_none()
+        && options.bar_threshold.is_none()
+        && options.margins.is_none()
+        && options.ratio.is_none()
+        && options.tiles.is_none()
+        && options.sharpen.is_none()
+        && options.encode.colors.is_none()
+        && !options.strip_metadata;
+    let same_format = matches!(
+        (image::ImageFormat::from_path(input_path), image::ImageFormat::from_path(output_path)),
+        (Ok(input_format), Ok(output_format)) if input_format == output_format
+    );
+    unchanged && same_format && filter::read_dimensions(input_path) == Some(size)
+}
+
+/// Whether `mode` would have to enlarge an image of `dimensions` to reach `target`.
+fn needs_upscale(dimensions: (u32, u32), target: (u32, u32), mode: Mode) -> bool {
+    let (too_narrow, too_short) = (dimensions.0 < target.0, dimensions.1 < target.1);
+    match mode {
+        // Covering the target enlarges the image if either side falls short
+        Mode::Crop | Mode::SeamCarve => too_narrow || too_short,
+        // Fitting inside the target only enlarges it if both sides fall short
+        Mode::Pad | Mode::Fit => too_narrow && too_short,
+    }
+}
+
+/// Largest region with the given aspect ratio, placed by the focus settings.
+fn ratio_window(img: &DynamicImage, ratio: f64, options: &CropOptions) -> Rect {
+    find_window(img, ratio_size(img.dimensions(), ratio), options)
+}
+
+/// Largest size with the given aspect ratio that fits inside `dimensions`.
+fn ratio_size((width, height): (u32, u32), ratio: f64) -> (u32, u32) {
+    if width as f64 / height as f64 > ratio {
+        ((height as f64 * ratio).round().max(1.0) as u32, height)
+    } else {
+        (width, (width as f64 / ratio).round().max(1.0) as u32)
+    }
+}
+
+/// Places a `target`-sized window within `img` by the crop strategy, kept
+/// inside the image whatever the strategy returns.
+fn find_window(img: &DynamicImage, target: (u32, u32), options: &CropOptions) -> Rect {
+    let region = match &options.strategy {
+        Some(strategy) => strategy.select_region(img, target),
+        None => strategy::FocalPoint(options.focus.0, options.focus.1).select_region(img, target),
+    };
+    let (width, height) = img.dimensions();
+    Rect {
+        x: region.x.min(width.saturating_sub(target.0)),
+        y: region.y.min(height.saturating_sub(target.1)),
+        width: target.0,
+        height: target.1,
+    }
+}
+
+/// A heavily blurred copy of `img` covering `target`. It's blurred at an
+/// eighth of the size, which looks the same and is much faster.
+fn blurred_fill(img: &DynamicImage, (target_width, target_height): (u32, u32)) -> RgbaImage {
+    let small = img.resize_to_fill((target_width / 8).max(1), (target_height / 8).max(1), FilterType::Triangle);
+    let sigma = small.width().max(small.height()) as f32 / 10.0;
+    let blurred = small.blur(sigma.max(1.0));
+    blurred.resize_exact(target_width, target_height, FilterType::Triangle).to_rgba8()
+}
+
+fn pad_to_fit(img: &DynamicImage, target: (u32, u32), options: &CropOptions) -> DynamicImage {
+    let (target_width, target_height) = target;
+
+    // Resize so the whole image fits inside the target box, unless it
+    // already does and upscaling is disabled
+    let fits = img.width() <= target_width && img.height() <= target_height;
+    let img_resized = if fits && options.no_upscale.is_some() {
+        img.clone()
+    } else {
+        resize(img, target_width, target_height, options)
+    };
+    let (resized_width, resized_height) = img_resized.dimensions();
+
+    // Place the image within the box according to the focal point
+    let pad_x = (target_width - resized_width) as f64 * options.focus.0;
+    let pad_y = (target_height - resized_height) as f64 * options.focus.1;
+
+    let background = DynamicImage::ImageRgba8(if options.blur_background {
+        blurred_fill(img, target)
+    } else {
+        RgbaImage::from_pixel(target_width, target_height, options.background)
+    });
+    // Placed at the source's own bit depth, so 16-bit and float images keep it
+    let (x, y) = (pad_x.round() as i64, pad_y.round() as i64);
+    let canvas = match img.color() {
+        ColorType::Rgb32F | ColorType::Rgba32F => {
+            let mut canvas = background.into_rgba32f();
+            image::imageops::overlay(&mut canvas, &img_resized.to_rgba32f(), x, y);
+            DynamicImage::ImageRgba32F(canvas)
+        }
+        ColorType::L16 | ColorType::La16 | ColorType::Rgb16 | ColorType::Rgba16 => {
+            let mut canvas = background.into_rgba16();
+            image::imageops::overlay(&mut canvas, &img_resized.to_rgba16(), x, y);
+            DynamicImage::ImageRgba16(canvas)
+        }
+        _ => {
+            let mut canvas = background.into_rgba8();
+            image::imageops::overlay(&mut canvas, &img_resized.to_rgba8(), x, y);
+            DynamicImage::ImageRgba8(canvas)
+        }
+    };
+
+    // The alpha channel is dropped when nothing in the output is transparent
+    if options.background[3] < 255 || img.color().has_alpha() {
+        return canvas;
+    }
+    match canvas {
+        DynamicImage::ImageRgba32F(_) => DynamicImage::ImageRgb32F(canvas.to_rgb32f()),
+        DynamicImage::ImageRgba16(_) => DynamicImage::ImageRgb16(canvas.to_rgb16()),
+        _ => DynamicImage::ImageRgb8(canvas.to_rgb8()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{ImageBuffer, Rgb};
+
+    #[test]
+    fn padding_keeps_the_bit_depth() {
+        let options = CropOptions { mode: Mode::Pad, no_upscale: Some(NoUpscale::Native), ..CropOptions::default() };
+        let deep = DynamicImage::ImageRgb16(ImageBuffer::from_pixel(4, 2, Rgb([1000u16, 30001, 65535])));
+        let DynamicImage::ImageRgb16(padded) = pad_to_fit(&deep, (4, 4), &options) else {
+            panic!("16-bit RGB wasn't padded to 16-bit RGB");
+        };
+        assert_eq!(*padded.get_pixel(2, 0), Rgb([0, 0, 0]));
+        assert_eq!(*padded.get_pixel(2, 1), Rgb([1000, 30001, 65535]));
+
+        let float = DynamicImage::ImageRgb32F(ImageBuffer::from_pixel(4, 2, Rgb([0.25f32, 0.123_456, 1.0])));
+        let DynamicImage::ImageRgb32F(padded) = pad_to_fit(&float, (4, 4), &options) else {
+            panic!("float RGB wasn't padded to float RGB");
+        };
+        assert_eq!(*padded.get_pixel(2, 2), Rgb([0.25, 0.123_456, 1.0]));
+    }
+
+    #[test]
+    fn only_xml_with_an_svg_root_is_sniffed_as_svg() {
+        let svg = b"\xef\xbb\xbf<?xml version=\"1.0\"?>\n<!-- drawn by hand -->\n\
+            <!DOCTYPE svg [<!ENTITY size \"1\">]>\n<svg width=\"&size;\"/>";
+        assert_eq!(sniff_extension(svg).as_deref(), Some("svg"));
+        assert_eq!(sniff_extension(b"<svg>").as_deref(), Some("svg"));
+        assert_eq!(sniff_extension(b"<?xml version=\"1.0\"?><rss version=\"2.0\"/>"), None);
+        assert_eq!(sniff_extension(b"<svgx/>"), None);
+    }
+
+    #[test]
+    fn renamed_outputs_each_reserve_their_own_name() {
+        let dir = std::env::temp_dir().join(format!("img-cropper-rename-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let taken = dir.join("photo.jpg");
+        fs::write(&taken, b"").unwrap();
+        let first = reserve_free_name(&taken).unwrap();
+        let second = reserve_free_name(&taken).unwrap();
+        fs::remove_dir_all(&dir).unwrap();
+        assert_eq!((first, second), (dir.join("photo_2.jpg"), dir.join("photo_3.jpg")));
+    }
+}
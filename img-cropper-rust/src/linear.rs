@@ -0,0 +1,42 @@
+//! Resizing in linear light, so averaging pixels doesn't darken detail.
+
+use crate::convert_color;
+use image::{imageops::FilterType, DynamicImage, Rgba32FImage};
+
+/// Same as [`DynamicImage::resize`], but resamples linear RGB instead of the
+/// gamma-encoded sRGB values. The result keeps `img`'s color type.
+pub fn resize(img: &DynamicImage, width: u32, height: u32, filter: FilterType) -> DynamicImage {
+    let mut linear = img.to_rgba32f();
+    map_color_channels(&mut linear, srgb_to_linear);
+
+    let mut resized = DynamicImage::ImageRgba32F(linear)
+        .resize(width, height, filter)
+        .into_rgba32f();
+    map_color_channels(&mut resized, linear_to_srgb);
+
+    convert_color(DynamicImage::ImageRgba32F(resized), img.color())
+}
+
+fn map_color_channels(img: &mut Rgba32FImage, f: fn(f32) -> f32) {
+    for pixel in img.pixels_mut() {
+        for channel in &mut pixel.0[..3] {
+            *channel = f(channel.clamp(0.0, 1.0));
+        }
+    }
+}
+
+fn srgb_to_linear(value: f32) -> f32 {
+    if value <= 0.04045 {
+        value / 12.92
+    } else {
+        ((value + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+pub(crate) fn linear_to_srgb(value: f32) -> f32 {
+    if value <= 0.0031308 {
+        value * 12.92
+    } else {
+        1.055 * value.powf(1.0 / 2.4) - 0.055
+    }
+}
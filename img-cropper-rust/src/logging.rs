@@ -0,0 +1,70 @@
+//! Where the command line tool's messages go: plain lines on the console,
+//! or JSON lines for log aggregators.
+
+use clap::ValueEnum;
+use std::{fmt, io};
+use tracing::{field::Field, Level, Subscriber};
+use tracing_subscriber::{
+    field::Visit,
+    fmt::{format::Writer, writer::MakeWriterExt, FmtContext, FormatEvent, FormatFields},
+    registry::LookupSpan,
+    EnvFilter,
+};
+
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LogFormat {
+    /// Plain messages, with warnings and errors on stderr
+    Pretty,
+    /// One JSON object per line on stderr, with the fields and the file being cropped
+    Json,
+}
+
+/// Installs the global subscriber. `RUST_LOG` overrides `level`, which
+/// comes from -q and -v.
+pub fn init(format: LogFormat, level: Level) {
+    let filter = EnvFilter::try_from_default_env().unwrap_or_else(|_| EnvFilter::new(level.as_str()));
+    let builder = tracing_subscriber::fmt().with_env_filter(filter);
+    match format {
+        LogFormat::Pretty => builder
+            .event_format(Message)
+            .with_writer(io::stderr.with_max_level(Level::WARN).or_else(io::stdout))
+            .init(),
+        LogFormat::Json => builder
+            .json()
+            .with_current_span(true)
+            .with_span_list(false)
+            .with_writer(io::stderr)
+            .init(),
+    }
+}
+
+/// Writes just the message of an event, like the tool printed before it
+/// logged; the other fields are for the JSON output.
+struct Message;
+
+impl<S, N> FormatEvent<S, N> for Message
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+    N: for<'a> FormatFields<'a> + 'static,
+{
+    fn format_event(
+        &self,
+        _: &FmtContext<'_, S, N>,
+        mut writer: Writer<'_>,
+        event: &tracing::Event<'_>,
+    ) -> fmt::Result {
+        let mut message = MessageVisitor(String::new());
+        event.record(&mut message);
+        writeln!(writer, "{}", message.0)
+    }
+}
+
+struct MessageVisitor(String);
+
+impl Visit for MessageVisitor {
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        if field.name() == "message" {
+            self.0 = format!("{:?}", value);
+        }
+    }
+}
@@ -1,182 +1,2118 @@
-use clap::Parser;
-use image::ImageFormat;
-use rayon::prelude::*;
+mod config;
+mod logging;
+
+use clap::{error::ErrorKind, CommandFactory, FromArgMatches, Parser, Subcommand};
+use globset::{Glob, GlobBuilder};
+use image::Rgba;
+use indicatif::{ProgressBar, ProgressState, ProgressStyle};
+use img_cropper_rust::{
+    archive::ArchiveWriter,
+    cache::Cache,
+    caption::Caption,
+    checkpoint::Checkpoint,
+    crop_image, download, effects, encode,
+    failures::FailedList,
+    filter::{DimensionFilter, PathFilter},
+    is_supported_image_extension, lowercase_extension,
+    manifest::Manifest,
+    memory::MemoryBudget,
+    naming, output_file_path, parse_size,
+    report::Report,
+    serve, sniff_extension, strategy, watch, AlreadySized, BatchProcessor, BatchSummary, BorderPlacement, Collision,
+    CropOptions, CropStrategy, Event, Filter, Gravity, IfExists, JpegSubsampling, Margins, Mode, NoUpscale, Output,
+    OutputFile, OutputFormat, PngCompression, PngFilter, Rect, Rotate, Tiles,
+};
+#[cfg(feature = "faces")]
+use img_cropper_rust::faces;
+#[cfg(feature = "remote")]
+use img_cropper_rust::remote::{self, RemoteFolder};
+#[cfg(feature = "s3")]
+use img_cropper_rust::s3::{self, S3Folder};
+use logging::LogFormat;
+use regex::Regex;
+use sha2::{Digest, Sha256};
 use std::{
-    fs,
+    collections::BTreeMap,
+    fmt,
+    fs::{self, File},
+    io::{self, Read},
     path::{Path, PathBuf},
-    sync::atomic::{AtomicUsize, Ordering},
-    time::Instant,
+    process::ExitCode,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
 };
-use walkdir::WalkDir;
+use tracing::{debug, error, info, warn, Level};
 
-#[derive(Parser, Debug)]
-#[clap(author, version, about, long_about = None)]
+#[derive(Parser, Clone, Debug)]
+#[clap(
+    author,
+    version,
+    about,
+    long_about = None,
+    after_help = "Exit codes: 0 done, 1 error, 2 bad arguments, 3 no images found, 4 more images failed than --max-failures",
+    subcommand_negates_reqs = true,
+    args_override_self = true
+)]
 struct Args {
-    /// Input directory containing images
-    #[clap(short = 'i', long)]
-    input_dir: PathBuf,
+    #[clap(subcommand)]
+    command: Option<Command>,
+
+    /// Read options from this TOML file, keyed by their long names (e.g., output-dir = "out");
+    /// options given on the command line win. Defaults to img-cropper.toml in the working
+    /// directory, if there is one
+    #[clap(long)]
+    config: Option<PathBuf>,
+
+    /// Also use the options of this [preset.<name>] table of the config file; options given on
+    /// the command line win over the preset's, and the preset's over the rest of the file's
+    #[clap(long)]
+    preset: Option<String>,
+
+    /// Image files to crop, instead of or as well as the images in --input-dir. A single -
+    /// reads the image from stdin and, without --output-dir, writes the result to stdout;
+    /// it's named stdin.<ext>, with the extension its contents suggest
+    files: Vec<PathBuf>,
+
+    /// Input directory containing images, or a .zip archive of them; repeatable. With several,
+    /// each one's images are written into a subfolder of the output directory named after it.
+    /// s3://bucket/prefix reads from S3 in builds with the s3 feature
+    #[clap(short = 'i', long, required_unless_present_any = ["files", "files_from", "urls"])]
+    input_dir: Vec<PathBuf>,
+
+    /// Also crop the files listed in this file, one path per line, skipping lines starting with #;
+    /// - reads the list from stdin (e.g., find . -name "IMG_*" | img-cropper-rust --files-from - ...)
+    #[clap(long)]
+    files_from: Option<PathBuf>,
+
+    /// Also download and crop the images at the HTTP(S) URLs listed in this file, one per line,
+    /// skipping lines starting with #; - reads the list from stdin
+    #[clap(long, conflicts_with = "in_place")]
+    urls: Option<PathBuf>,
+
+    /// How many folder levels to search for images; 1 means only the input directory itself
+    #[clap(long, value_parser = clap::value_parser!(u64).range(1..), conflicts_with = "no_recursive")]
+    max_depth: Option<u64>,
+
+    /// Only process images directly inside the input directory (same as --max-depth 1)
+    #[clap(long)]
+    no_recursive: bool,
+
+    /// Follow symbolic links to files and folders inside the input directory. Links that
+    /// loop back to a parent folder are reported and skipped
+    #[clap(long)]
+    follow_links: bool,
+
+    /// Only process files whose name or relative path matches this glob (e.g., "*.png"); repeatable
+    #[clap(long, value_parser = parse_glob)]
+    include: Vec<Glob>,
+
+    /// Only process files whose name or relative path matches this regular expression (e.g., ^IMG_)
+    #[clap(long)]
+    include_regex: Option<Regex>,
+
+    /// Skip files and folders whose name or relative path matches this glob (e.g., node_modules
+    /// or "*_edited.*"); repeatable
+    #[clap(long, value_parser = parse_glob)]
+    exclude: Vec<Glob>,
+
+    /// Skip images narrower than this many pixels (read from the file header)
+    #[clap(long)]
+    min_width: Option<u32>,
+
+    /// Skip images shorter than this many pixels
+    #[clap(long)]
+    min_height: Option<u32>,
+
+    /// Skip images wider than this many pixels
+    #[clap(long)]
+    max_width: Option<u32>,
+
+    /// Skip images taller than this many pixels
+    #[clap(long)]
+    max_height: Option<u32>,
+
+    /// Skip files smaller than this, in bytes or with a K, M or G suffix (e.g., 20K)
+    #[clap(long, value_parser = parse_byte_size)]
+    min_bytes: Option<u64>,
+
+    /// Skip files larger than this, in bytes or with a K, M or G suffix (e.g., 100M)
+    #[clap(long, value_parser = parse_byte_size)]
+    max_bytes: Option<u64>,
+
+    /// Fail images with more pixels than this, optionally with a K, M or G suffix (e.g., 100M),
+    /// before decoding them
+    #[clap(long, value_parser = parse_pixel_count)]
+    max_input_pixels: Option<u64>,
+
+    /// Fail images wider or taller than this many pixels before decoding them
+    #[clap(long, value_parser = clap::value_parser!(u32).range(1..))]
+    max_input_dimension: Option<u32>,
+
+    /// Output directory for cropped images. Subfolders of the input directory
+    /// are recreated inside it. - writes a single image to stdout, s3://bucket/prefix uploads to S3
+    /// in builds with the s3 feature, and sftp://user@host/path or ftp://host/path uploads
+    /// to a web host in builds with the remote feature
+    #[clap(
+        short = 'o',
+        long,
+        required_unless_present_any = ["in_place", "files", "output_zip"],
+        conflicts_with = "in_place"
+    )]
+    output_dir: Option<PathBuf>,
+
+    /// Write the cropped images into this .zip archive instead of an output directory
+    #[clap(long, conflicts_with_all = ["output_dir", "in_place", "dry_run", "watch"])]
+    output_zip: Option<PathBuf>,
+
+    /// Replace the original images with the cropped ones. Outputs with another
+    /// name (e.g. from --format or several sizes) are written next to the original
+    #[clap(long, conflicts_with_all = ["flatten", "if_exists"])]
+    in_place: bool,
+
+    /// With --in-place, keep each original under its name plus this suffix (e.g., .bak)
+    #[clap(long, requires = "in_place")]
+    backup_suffix: Option<String>,
+
+    /// Write every output directly into the output directory instead of
+    /// recreating the input's subfolders
+    #[clap(long)]
+    flatten: bool,
+
+    /// What to do when several inputs would be written to the same output path
+    #[clap(long, value_enum, default_value_t = Collision::Suffix)]
+    on_collision: Collision,
+
+    /// What to do when an output file already exists
+    #[clap(long, value_enum, default_value_t = IfExists::Overwrite)]
+    if_exists: IfExists,
+
+    /// Output file name template (e.g., {stem}_{width}x{height}.webp). Placeholders: {stem},
+    /// {ext}, {width}, {height}, {parent}, {hash} (of the source file), {exif_date}. An image
+    /// read from stdin has the stem "stdin"
+    #[clap(long, value_parser = parse_name_template)]
+    name_template: Option<String>,
+
+    /// Crop size in WxH format (e.g., 400x300), or a comma-separated list of sizes
+    #[clap(
+        short = 's',
+        long,
+        required_unless_present_any = [
+            "rect",
+            "ratio",
+            "margins",
+            "trim_percent",
+            "trim_borders",
+            "remove_bars",
+            "tiles",
+            "tile_size"
+        ]
+    )]
+    size: Option<String>,
+
+    /// Crop to this aspect ratio at the image's native resolution, as W:H (e.g., 16:9)
+    #[clap(long, conflicts_with = "size")]
+    ratio: Option<String>,
+
+    /// Turn every image clockwise by this many degrees before cropping; auto turns
+    /// portrait images for landscape sizes and the other way around
+    #[clap(long, value_enum)]
+    rotate: Option<Rotate>,
+
+    /// Mirror every image left to right (e.g., for webcam captures), after --rotate
+    #[clap(long)]
+    flip_h: bool,
+
+    /// Mirror every image top to bottom, after --rotate
+    #[clap(long)]
+    flip_v: bool,
+
+    /// Convert the outputs to grayscale, keeping any transparency
+    #[clap(long)]
+    grayscale: bool,
+
+    /// Draw a solid frame this many pixels thick around every output
+    #[clap(long)]
+    border: Option<u32>,
+
+    /// Color of --border, as #RRGGBB, #RRGGBBAA or "transparent"
+    #[clap(long, default_value = "#ffffff", requires = "border")]
+    border_color: String,
+
+    /// Whether --border is drawn over the output's edges, keeping the target size, or around them
+    #[clap(long, value_enum, default_value_t = BorderPlacement::Inside, requires = "border")]
+    border_placement: BorderPlacement,
+
+    /// Round the corners of every output with this radius in pixels, leaving them
+    /// transparent; JPEGs become PNGs unless --format says otherwise
+    #[clap(long)]
+    radius: Option<u32>,
+
+    /// Cut every output to the largest circle that fits, like --radius
+    #[clap(long, conflicts_with = "radius")]
+    circle: bool,
+
+    /// Draw this image (e.g., a logo with transparency) onto every output, shrunk to fit
+    #[clap(long)]
+    watermark: Option<PathBuf>,
+
+    /// Which corner or edge of the output the watermark is placed in
+    #[clap(long, value_enum, default_value_t = Gravity::Southeast, requires = "watermark")]
+    watermark_position: Gravity,
+
+    /// Opacity of the watermark, from 0 (invisible) to 1
+    #[clap(long, default_value_t = 1.0, value_parser = parse_opacity, requires = "watermark")]
+    watermark_opacity: f32,
+
+    /// Write this text onto every output, with the --name-template placeholders plus
+    /// {filename} (e.g., "{filename}"); {ext} is the source's extension, and an image read
+    /// from stdin is stdin.<ext>
+    #[clap(long, value_parser = parse_caption)]
+    caption: Option<String>,
+
+    /// TrueType or OpenType font for --caption, instead of the bundled DejaVu Sans
+    #[clap(long, requires = "caption")]
+    caption_font: Option<PathBuf>,
+
+    /// Font size of --caption in pixels (default: a sixteenth of the output height)
+    #[clap(long, requires = "caption")]
+    caption_size: Option<f32>,
+
+    /// Which corner or edge of the output the caption is placed in
+    #[clap(long, value_enum, default_value_t = Gravity::Southwest, requires = "caption")]
+    caption_position: Gravity,
+
+    /// Crop this exact region of every image without resizing, as X,Y,WxH (e.g., 0,40,800x600)
+    #[clap(long, conflicts_with_all = ["size", "mode"])]
+    rect: Option<String>,
+
+    /// Remove solid-color borders (e.g., scanner margins, black frames) before resizing
+    #[clap(long)]
+    trim_borders: bool,
+
+    /// Maximum per-channel color difference still treated as border by --trim-borders
+    #[clap(long, default_value_t = 10, requires = "trim_borders")]
+    trim_tolerance: u8,
+
+    /// Remove black letterbox/pillarbox bars (e.g., in video stills) before resizing
+    #[clap(long)]
+    remove_bars: bool,
+
+    /// Maximum luma (0-255) of pixels treated as part of a bar by --remove-bars
+    #[clap(long, default_value_t = 24, requires = "remove_bars")]
+    bar_threshold: u8,
+
+    /// Trim a border of this percentage of the image from every side before resizing
+    #[clap(long, conflicts_with = "margins")]
+    trim_percent: Option<f64>,
 
-    /// Output directory for cropped images
-    #[clap(short = 'o', long)]
-    output_dir: PathBuf,
+    /// Trim margins as percentages before resizing, as T,R,B,L (or V,H or a single value)
+    #[clap(long)]
+    margins: Option<String>,
 
-    /// Crop size in WxH format (e.g., 400x300)
-    #[clap(short = 's', long)]
-    size: String,
+    /// How to fit images to the target size
+    #[clap(short = 'm', long, value_enum, default_value_t = Mode::Crop)]
+    mode: Mode,
+
+    /// Background for pad mode: a color as #RRGGBB, #RRGGBBAA or "transparent", or
+    /// "blur" for a blurred copy of the image
+    #[clap(short = 'b', long, default_value = "#000000")]
+    background: String,
+
+    /// Resampling filter used when resizing; faster filters trade away sharpness
+    #[clap(long, value_enum, default_value_t = Filter::Lanczos3)]
+    filter: Filter,
+
+    /// Resize in linear light instead of sRGB for more accurate colors and detail
+    #[clap(long)]
+    linear: bool,
+
+    /// Sharpen resized images with an unsharp mask of this strength (default 0.5)
+    #[clap(long, num_args = 0..=1, default_missing_value = "0.5")]
+    sharpen: Option<f32>,
+
+    /// Blur radius of the unsharp mask used by --sharpen, in pixels
+    #[clap(long, default_value_t = 1.0, requires = "sharpen")]
+    sharpen_radius: f32,
+
+    /// Minimum brightness difference (0-255) that --sharpen enhances, to avoid amplifying noise
+    #[clap(long, default_value_t = 0, requires = "sharpen")]
+    sharpen_threshold: u8,
+
+    /// Stretch each image's histogram and neutralize color casts before resizing, at this
+    /// strength from 0 to 1 (default 1)
+    #[clap(long, num_args = 0..=1, default_missing_value = "1", value_parser = parse_strength)]
+    auto_enhance: Option<f32>,
+
+    /// Brighten or darken every output by this factor (e.g., 1.2 for 20% brighter)
+    #[clap(long, value_parser = parse_factor)]
+    brightness: Option<f32>,
+
+    /// Raise or lower the contrast of every output by this factor (e.g., 0.9)
+    #[clap(long, value_parser = parse_factor)]
+    contrast: Option<f32>,
+
+    /// Raise or lower the color saturation of every output by this factor; 0 is grayscale
+    #[clap(long, value_parser = parse_factor)]
+    saturation: Option<f32>,
+
+    /// Never enlarge images smaller than the target size; either skip them or
+    /// keep them at their native resolution (the default)
+    #[clap(long, value_enum, num_args = 0..=1, default_missing_value = "native")]
+    no_upscale: Option<NoUpscale>,
+
+    /// Don't re-encode images that already have the target size and format; either
+    /// copy them to the output unchanged (the default) or skip them
+    #[clap(long, value_enum, num_args = 0..=1, default_missing_value = "copy")]
+    skip_already_sized: Option<AlreadySized>,
+
+    /// Which part of the image to keep when cropping
+    #[clap(short = 'g', long, value_enum, default_value_t = Gravity::Center)]
+    gravity: Gravity,
+
+    /// Focal point to center the crop on, as X%,Y% of the image (e.g., 30,70)
+    #[clap(long, conflicts_with = "gravity")]
+    focus: Option<String>,
+
+    /// Pick the crop window containing the most detail instead of using gravity
+    #[clap(long, conflicts_with_all = ["gravity", "focus"])]
+    smart: bool,
+
+    /// Keep detected faces inside the crop window
+    #[cfg(feature = "faces")]
+    #[clap(long, requires = "face_model")]
+    faces: bool,
+
+    /// SeetaFace detection model used by --faces (e.g., seeta_fd_frontal_v1.0.bin)
+    #[cfg(feature = "faces")]
+    #[clap(long)]
+    face_model: Option<PathBuf>,
+
+    /// Split each output into a grid of COLSxROWS equally sized tiles (e.g., 3x2)
+    #[clap(long, conflicts_with = "tile_size")]
+    tiles: Option<String>,
+
+    /// Split each output into as many WxH tiles as fit (e.g., 256x256)
+    #[clap(long)]
+    tile_size: Option<String>,
+
+    /// Convert every image to this format instead of keeping the input format. Without it, HEIC
+    /// and HEIF become JPEG, and RAW, SVG and PDF become PNG
+    #[clap(long, value_enum)]
+    format: Option<OutputFormat>,
+
+    /// AVIF output quality from 1 (smallest files) to 100 (best quality)
+    #[clap(long, default_value_t = 80, value_parser = clap::value_parser!(u8).range(1..=100))]
+    avif_quality: u8,
+
+    /// AVIF encoding speed from 1 (slowest, smallest files) to 10 (fastest)
+    #[clap(long, default_value_t = 4, value_parser = clap::value_parser!(u8).range(1..=10))]
+    avif_speed: u8,
+
+    /// JPEG output quality from 1 (smallest files) to 100 (best quality)
+    #[clap(long, default_value_t = 75, value_parser = clap::value_parser!(u8).range(1..=100))]
+    jpeg_quality: u8,
+
+    /// JPEG chroma subsampling; 444 keeps text and sharp colored edges crisp, 420 makes the smallest files
+    #[clap(long, value_enum, default_value_t = JpegSubsampling::Yuv420)]
+    jpeg_subsampling: JpegSubsampling,
+
+    /// Write progressive JPEGs, which web pages can show at low detail before they finish loading
+    #[clap(long)]
+    jpeg_progressive: bool,
+
+    /// Write lossy WebP at this quality from 0 (smallest files) to 100 (best quality)
+    #[clap(long, value_parser = parse_webp_quality)]
+    webp_quality: Option<f32>,
+
+    /// Write lossless WebP, the default unless --webp-quality is given; on the command line it
+    /// wins over a webp-quality from the config file or preset
+    #[clap(long, conflicts_with = "webp_quality")]
+    webp_lossless: bool,
+
+    /// PNG compression effort; better compression means smaller files but slower encoding
+    #[clap(long, value_enum, default_value_t = PngCompression::Fast)]
+    png_compression: PngCompression,
+
+    /// PNG row filter strategy
+    #[clap(long, value_enum, default_value_t = PngFilter::Adaptive)]
+    png_filter: PngFilter,
+
+    /// Reduce PNG and GIF outputs to a palette of this many colors (2-256), for much
+    /// smaller files; PNGs are written with indexed color
+    #[clap(long, value_parser = clap::value_parser!(u16).range(2..=256))]
+    colors: Option<u16>,
+
+    /// Dither the palette colors of --colors, trading banding for noise
+    #[clap(long, requires = "colors")]
+    dither: bool,
+
+    /// Don't copy EXIF metadata (capture date, camera, GPS, serial numbers) into the
+    /// outputs. Color profiles are still kept
+    #[clap(long)]
+    strip_metadata: bool,
+
+    /// Write a JSON report of every image (paths, sizes, timing, status and errors) to this file
+    #[clap(long)]
+    report: Option<PathBuf>,
+
+    /// Write a CSV manifest with a row per output (sizes, crop region and file sizes) to this file
+    #[clap(long)]
+    manifest: Option<PathBuf>,
+
+    /// Give outputs the source file's modification time (and creation time where the platform allows)
+    #[clap(long)]
+    preserve_times: bool,
+
+    /// Write the paths of the images that failed, each after a # comment with its error, to this
+    /// file; pass it to --files-from to retry them
+    #[clap(long)]
+    failed_list: Option<PathBuf>,
+
+    /// Remember the images cropped in this file, and skip those that haven't changed since, with
+    /// outputs still in place, when the same options are used again
+    #[clap(long)]
+    cache: Option<PathBuf>,
+
+    /// Skip the images an interrupted run with the same options already finished. Every run notes
+    /// its finished images in a checkpoint file in the output directory (the input directory for
+    /// --in-place, the archive's folder for --output-zip, or ~/.local/state/img-cropper for remote
+    /// folders), removed once the run completes; running again without --resume starts it over
+    #[clap(long, conflicts_with = "dry_run")]
+    resume: bool,
+
+    /// Stop starting new images after the first one fails; images already being cropped finish
+    #[clap(long)]
+    fail_fast: bool,
+
+    /// Try an image up to this many more times when reading or writing files fails, waiting
+    /// 0.25s before the first retry and twice as long before each one after
+    #[clap(long, default_value_t = 0)]
+    retries: u32,
+
+    /// Count an image as failed once it has taken this many seconds, moving on to the next
+    #[clap(long, value_parser = parse_seconds)]
+    timeout: Option<Duration>,
+
+    /// Number of images that may fail before the exit code reports the run as failed
+    #[clap(long, default_value_t = 0)]
+    max_failures: usize,
+
+    /// Keep running after the first pass, cropping images as they're added to or changed in the
+    /// input directories
+    #[clap(long, requires = "input_dir", conflicts_with_all = ["in_place", "dry_run"])]
+    watch: bool,
+
+    /// Only print what would be processed and where each output would land, without decoding or
+    /// writing anything
+    #[clap(long, conflicts_with_all = ["report", "manifest", "failed_list"])]
+    dry_run: bool,
+
+    /// Print a line for every image instead of showing a progress bar; -vv adds
+    /// each image's timing and decoded format and size
+    #[clap(short = 'v', long, action = clap::ArgAction::Count)]
+    verbose: u8,
+
+    /// Print nothing but errors, and the summary only if an image failed
+    #[clap(short = 'q', long, conflicts_with = "verbose")]
+    quiet: bool,
+
+    /// How messages are written
+    #[clap(long, value_enum, default_value_t = LogFormat::Pretty)]
+    log_format: LogFormat,
 
     /// Number of parallel instances
     #[clap(short = 'c', long, default_value_t = 4, value_parser = clap::value_parser!(u8).range(1..=64))]
     instances: u8,
+
+    /// Hold images back while those being cropped would need more than this much memory between
+    /// them, estimated from their pixel size, e.g. 4G; larger images are cropped one at a time
+    #[clap(long, value_parser = parse_byte_size)]
+    max_memory: Option<u64>,
+}
+
+#[derive(Subcommand, Clone, Debug)]
+enum Command {
+    /// Crop images uploaded over HTTP instead of files: POST an image to /crop, optionally with
+    /// ?size=WxH&mode=...&gravity=...&format=..., to get the result back. The other options given
+    /// before `serve` apply to every request
+    Serve {
+        /// Address to listen on
+        #[clap(long, default_value = "127.0.0.1:8080")]
+        listen: String,
+    },
+    /// Print the completion script for a shell (e.g., img-cropper-rust completions bash >
+    /// /etc/bash_completion.d/img-cropper-rust)
+    #[clap(hide = true)]
+    Completions { shell: clap_complete::Shell },
+}
+
+/// Exit code when the run couldn't be done, such as an unreadable input folder
+const EXIT_ERROR: u8 = 1;
+/// Exit code for arguments that don't parse or name missing inputs, as clap
+/// uses for its own errors
+const EXIT_USAGE: u8 = 2;
+/// Exit code when no image was found to process
+const EXIT_NO_IMAGES: u8 = 3;
+/// Exit code when more images failed than --max-failures allows
+const EXIT_FAILURES: u8 = 4;
+
+/// How much is printed while images are processed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Verbosity {
+    /// Only errors
+    Quiet,
+    /// A progress bar
+    Normal,
+    /// A line per image
+    Verbose,
+    /// A line per image with its timing and decoded format
+    Debug,
 }
 
-fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let args = Args::parse();
+impl Verbosity {
+    /// The verbosity for this many -v flags.
+    fn from_count(count: u8) -> Self {
+        match count {
+            0 => Verbosity::Normal,
+            1 => Verbosity::Verbose,
+            _ => Verbosity::Debug,
+        }
+    }
+
+    /// The most detailed messages shown. A line per image is logged at debug
+    /// level, so the progress bar can take its place.
+    fn level(self) -> Level {
+        match self {
+            Verbosity::Quiet => Level::WARN,
+            Verbosity::Normal => Level::INFO,
+            Verbosity::Verbose | Verbosity::Debug => Level::DEBUG,
+        }
+    }
+}
+
+fn main() -> ExitCode {
+    let mut command = command();
+    let argv = match config::with_config(&command, std::env::args_os().collect()) {
+        Ok(argv) => argv,
+        Err(e) => command.error(ErrorKind::InvalidValue, e).exit(),
+    };
+    let args = command
+        .try_get_matches_from_mut(argv)
+        .and_then(|matches| Args::from_arg_matches(&matches))
+        .unwrap_or_else(|e| e.format(&mut command).exit());
+    match run(args) {
+        Ok(code) => code,
+        Err(e) => {
+            error!("Error: {}", e);
+            ExitCode::from(EXIT_ERROR)
+        }
+    }
+}
+
+/// The command line interface, where every option can also be given as an
+/// `IMG_CROPPER_*` environment variable named after it, such as
+/// `IMG_CROPPER_OUTPUT_DIR` for `--output-dir`. Given options win over the
+/// variables.
+fn command() -> clap::Command {
+    Args::command()
+        .mut_args(|arg| {
+            if arg.is_positional() || matches!(arg.get_id().as_str(), "help" | "version") {
+                return arg;
+            }
+            let name = format!("IMG_CROPPER_{}", arg.get_id().as_str().to_ascii_uppercase());
+            arg.env(name)
+        })
+}
+
+/// Crops the images `args` asks for, returning the exit code for how it went.
+fn run(mut args: Args) -> Result<ExitCode, Box<dyn std::error::Error>> {
+    if let Some(Command::Completions { shell }) = args.command {
+        let mut command = command();
+        let name = command.get_name().to_string();
+        clap_complete::generate(shell, &mut command, name, &mut io::stdout());
+        return Ok(ExitCode::SUCCESS);
+    }
+    let verbosity = match Verbosity::from_count(args.verbose) {
+        _ if args.quiet => Verbosity::Quiet,
+        // A dry run is only useful for its line per image
+        Verbosity::Normal if args.dry_run => Verbosity::Verbose,
+        verbosity => verbosity,
+    };
+    logging::init(args.log_format, verbosity.level());
+    jxl_oxide::integration::register_image_decoding_hook();
+    #[cfg(feature = "heic")]
+    {
+        libheif_rs::integration::image::register_heif_decoding_hook();
+        libheif_rs::integration::image::register_heic_decoding_hook();
+    }
     let start_time = Instant::now();
 
-    let (width, height) = parse_size(&args.size)?;
+    let options = match crop_options(&args) {
+        Ok(options) => options,
+        Err(e) => return Ok(usage_error(e)),
+    };
 
-    if !args.input_dir.exists() {
-        eprintln!("Error: Input directory not found: {:?}", args.input_dir);
-        std::process::exit(1);
+    if let Some(Command::Serve { listen }) = &args.command {
+        rayon::ThreadPoolBuilder::new()
+            .num_threads(args.instances as usize)
+            .build_global()?;
+        info!("Serving on http://{}/crop with {} parallel instances.", listen, args.instances);
+        serve::serve(listen, options, args.format)?;
+        return Ok(ExitCode::SUCCESS);
     }
 
-    if !args.output_dir.exists() {
-        fs::create_dir_all(&args.output_dir)?;
-        println!("Created output directory: {:?}", args.output_dir);
+    // Streaming keeps stdout free for the image, so it skips the usual report
+    let dash = Path::new("-");
+    if args.files.iter().any(|file| file == dash) || args.output_dir.as_deref() == Some(dash) {
+        if args.dry_run {
+            return Ok(usage_error("--dry-run can't be combined with streaming through stdin or stdout"));
+        }
+        crop_stream(&args, &options)?;
+        return Ok(ExitCode::SUCCESS);
+    }
+    // From the arguments as given, before inputs are downloaded and outputs staged locally
+    let run_key = run_key(&args);
+    let checkpoint_path = checkpoint_path(&args, &run_key);
+    let zip_output = match &args.output_zip {
+        Some(zip_path) => {
+            let zip_output = ZipOutput::new(zip_path)
+                .map_err(|e| format!("Failed to create the archive {:?}: {}", zip_path, e))?;
+            args.output_dir = Some(zip_output.dir.0.clone());
+            Some(zip_output)
+        }
+        None => None,
+    };
+    if args.output_dir.is_none() && !args.in_place {
+        return Ok(usage_error("--output-dir is required unless streaming to stdout or using --in-place"));
     }
 
     rayon::ThreadPoolBuilder::new()
         .num_threads(args.instances as usize)
         .build_global()?;
 
-    println!("Processing images from: {:?}", args.input_dir);
-    println!("Cropping to size: {}x{}", width, height);
-    println!("Using {} parallel instances.", args.instances);
-    println!("Saving to: {:?}", args.output_dir);
+    #[cfg(feature = "s3")]
+    let s3_output = {
+        let uses_s3 = args.input_dir.iter().chain(&args.output_dir).any(|dir| s3::is_s3_path(dir));
+        if uses_s3 && args.watch {
+            return Ok(usage_error("--watch can't watch S3 folders"));
+        }
+        if args.in_place && args.input_dir.iter().any(|dir| s3::is_s3_path(dir)) {
+            return Ok(usage_error("--in-place can't replace images in S3; use --output-dir"));
+        }
+        S3Output::new(&mut args)?
+    };
+    #[cfg(not(feature = "s3"))]
+    if args.input_dir.iter().chain(&args.output_dir).any(|dir| dir.starts_with("s3:")) {
+        return Ok(usage_error("S3 folders need a build with the s3 feature"));
+    }
+    if args.input_dir.iter().any(|dir| dir.starts_with("sftp:") || dir.starts_with("ftp:")) {
+        return Ok(usage_error("sftp:// and ftp:// folders can only be output directories"));
+    }
+    #[cfg(feature = "remote")]
+    let remote_output = {
+        if args.watch && args.output_dir.as_deref().is_some_and(remote::is_remote_path) {
+            return Ok(usage_error("--watch needs a local or S3 output directory"));
+        }
+        RemoteOutput::new(&mut args)?
+    };
+    #[cfg(not(feature = "remote"))]
+    if args.output_dir.as_deref().is_some_and(|dir| dir.starts_with("sftp:") || dir.starts_with("ftp:")) {
+        return Ok(usage_error("sftp:// and ftp:// folders need a build with the remote feature"));
+    }
+
+    // S3 folders are checked as they're listed
+    for input_dir in args.input_dir.iter().filter(|dir| !dir.starts_with("s3:")) {
+        if !input_dir.exists() {
+            return Ok(usage_error(format!("Input directory not found: {:?}", input_dir)));
+        }
+        if input_dir.is_file() && lowercase_extension(input_dir) == "zip" && (args.in_place || args.watch) {
+            return Ok(usage_error("--in-place and --watch need input directories, not archives"));
+        }
+        let same_dir = match (input_dir.canonicalize(), args.output_dir.as_deref().map(Path::canonicalize)) {
+            (Ok(input_dir), Some(Ok(output_dir))) => input_dir == output_dir,
+            _ => false,
+        };
+        if same_dir && !args.in_place {
+            return Ok(usage_error(format!(
+                "The output directory is the input directory {:?}; use --in-place to replace the originals",
+                input_dir
+            )));
+        }
+    }
+    if let Some(list_path) = &args.files_from {
+        // Lists usually come from other tools, so non-images in them are ignored
+        let listed = read_list(list_path)
+            .map_err(|e| format!("Failed to read the file list {:?}: {}", list_path, e))?;
+        args.files.extend(
+            listed
+                .into_iter()
+                .map(PathBuf::from)
+                .filter(|file| is_supported_image_extension(&lowercase_extension(file))),
+        );
+    }
+    let mut download_failures = 0;
+    let _downloads = match &args.urls {
+        Some(list_path) => {
+            let urls = read_list(list_path)
+                .map_err(|e| format!("Failed to read the URL list {:?}: {}", list_path, e))?;
+            // Named after the run, so a resumed run downloads to the paths its checkpoint has
+            let downloads = TempDir::for_run("urls", &run_key);
+            let download_start = Instant::now();
+            for (url, result) in urls.iter().zip(download::download_all(&urls, &downloads.0)) {
+                match result {
+                    Ok(path) => {
+                        debug!("Downloaded: {} -> {:?}", url, path);
+                        args.files.push(path);
+                    }
+                    Err(e) => {
+                        error!("Failed to download {}: {}", url, e);
+                        download_failures += 1;
+                    }
+                }
+            }
+            info!(
+                "Downloaded {} of {} images in {:.2?}.",
+                urls.len() - download_failures,
+                urls.len(),
+                download_start.elapsed()
+            );
+            Some(downloads)
+        }
+        None => None,
+    };
+    for file in &args.files {
+        if !file.is_file() {
+            return Ok(usage_error(format!("Input file not found: {:?}", file)));
+        }
+        if !is_supported_image_extension(&lowercase_extension(file)) {
+            return Ok(usage_error(format!("Unsupported image format: {:?}", file)));
+        }
+    }
 
-    let processed_count = AtomicUsize::new(0);
-    let failed_count = AtomicUsize::new(0);
+    // --in-place writes next to each input instead
+    let output_dir = args.output_dir.clone().unwrap_or_default();
+    if !args.in_place && !args.dry_run && !output_dir.exists() {
+        fs::create_dir_all(&output_dir)?;
+        if args.output_zip.is_none() {
+            info!("Created output directory: {:?}", output_dir);
+        }
+    }
 
-    let image_paths: Vec<_> = WalkDir::new(&args.input_dir)
-        .into_iter()
-        .filter_map(|e| e.ok())
-        .filter(|e| e.file_type().is_file())
-        .filter_map(|entry| {
-            let input_file_path = entry.path();
-            let extension = input_file_path
-                .extension()
-                .and_then(|s| s.to_str())
-                .map(|s| s.to_ascii_lowercase());
-
-            if let Some(ext) = extension {
-                if is_supported_image_extension(&ext) {
-                    let output_file_name = input_file_path.file_name()?;
-                    let output_file_path = args.output_dir.join(output_file_name);
-                    return Some((input_file_path.to_path_buf(), output_file_path));
+    let dimension_filter = DimensionFilter {
+        min_width: args.min_width,
+        min_height: args.min_height,
+        max_width: args.max_width,
+        max_height: args.max_height,
+    };
+    let path_filter = match PathFilter::new(&args.include, args.include_regex.clone(), &args.exclude) {
+        Ok(path_filter) => path_filter,
+        Err(e) => return Ok(usage_error(e)),
+    };
+    print_settings(&args, &options, &dimension_filter, &output_dir);
+
+    let mut batch = BatchProcessor {
+        input_dirs: args.input_dir.clone(),
+        files: args.files.clone(),
+        output_dir,
+        max_depth: match args.max_depth {
+            _ if args.no_recursive => 1,
+            Some(depth) => depth as usize,
+            None => usize::MAX,
+        },
+        follow_links: args.follow_links,
+        path_filter,
+        dimension_filter,
+        min_bytes: args.min_bytes,
+        max_bytes: args.max_bytes,
+        flatten: args.flatten,
+        format: args.format,
+        on_collision: args.on_collision,
+        preserve_times: args.preserve_times,
+        dry_run: args.dry_run,
+        fail_fast: args.fail_fast,
+        retries: args.retries,
+        timeout: args.timeout,
+        cache: match &args.cache {
+            Some(cache_path) => Some(
+                Cache::open(cache_path, cache_key(&args))
+                    .map_err(|e| format!("Failed to read the cache {:?}: {}", cache_path, e))?,
+            ),
+            None => None,
+        },
+        checkpoint: match (args.dry_run, args.resume) {
+            (true, _) => None,
+            (false, resume) => {
+                let checkpoint = if resume {
+                    Checkpoint::resume(&checkpoint_path, &run_key)
+                } else {
+                    if checkpoint_path.exists() {
+                        warn!(
+                            "Starting over the interrupted run that left {:?}; use --resume to continue it",
+                            checkpoint_path
+                        );
+                    }
+                    Checkpoint::start(&checkpoint_path, &run_key)
+                };
+                let checkpoint = checkpoint
+                    .map_err(|e| format!("Failed to open the checkpoint {:?}: {}", checkpoint_path, e))?;
+                if resume {
+                    info!("Resuming an interrupted run that finished {} images.", checkpoint.done_count());
                 }
+                Some(checkpoint)
             }
-            None
+        },
+        memory_budget: args.max_memory.map(MemoryBudget::new),
+        ..BatchProcessor::new(options, PathBuf::new())
+    };
+    let progress = (verbosity == Verbosity::Normal).then(|| {
+        ProgressBar::new(0).with_style(
+            ProgressStyle::with_template("{bar:40} {pos}/{len} images, {rate}, {eta} left {msg}")
+                .expect("progress bar template is valid")
+                .with_key("rate", |state: &ProgressState, w: &mut dyn fmt::Write| {
+                    let _ = write!(w, "{:.1} images/s", state.per_sec());
+                }),
+        )
+    });
+    let failed_count = AtomicUsize::new(0);
+    let durations = Mutex::new(Vec::new());
+    let byte_totals = ByteTotals::default();
+    let report = args.report.as_ref().map(|_| Report::new());
+    let manifest = args.manifest.as_ref().map(|_| Manifest::new());
+    let failed_list = args.failed_list.as_ref().map(|_| FailedList::new());
+    let delivery_failures = AtomicUsize::new(0);
+    let summary = batch.run(|event| {
+        #[cfg(feature = "s3")]
+        if let (Some(s3_output), Event::Cropped { outputs, .. }) = (&s3_output, &event) {
+            if !s3_output.upload(outputs) {
+                delivery_failures.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        #[cfg(feature = "remote")]
+        if let (Some(remote_output), Event::Cropped { outputs, .. }) = (&remote_output, &event) {
+            if !remote_output.upload(outputs) {
+                delivery_failures.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        if let (Some(zip_output), Event::Cropped { outputs, .. }) = (&zip_output, &event) {
+            if !zip_output.add(outputs) {
+                delivery_failures.fetch_add(1, Ordering::Relaxed);
+            }
+        }
+        if let Some(report) = &report {
+            report.record(&event);
+        }
+        if let Some(manifest) = &manifest {
+            manifest.record(&event);
+        }
+        if let Some(failed_list) = &failed_list {
+            failed_list.record(&event);
+        }
+        if let Event::Cropped { input, source_bytes, outputs, elapsed, .. } = event {
+            durations.lock().unwrap_or_else(|e| e.into_inner()).push(elapsed);
+            byte_totals.add(input, source_bytes, outputs);
+        }
+        report_event(event, verbosity, progress.as_ref(), &failed_count)
+    });
+    // A run stopped by --fail-fast is left to resume; watching needs no checkpoint
+    let stopped = args.fail_fast && summary.as_ref().is_ok_and(|summary| summary.failed > 0);
+    if let (Some(checkpoint), false) = (batch.checkpoint.take(), stopped) {
+        if let Err(e) = checkpoint.finish() {
+            warn!("Failed to remove the checkpoint {:?}: {}", checkpoint_path, e);
+        }
+    }
+    let mut summary = summary?;
+    // An image whose outputs didn't reach S3 or the archive wasn't really processed
+    let delivery_failures = delivery_failures.into_inner();
+    summary.processed -= delivery_failures;
+    summary.failed += delivery_failures + download_failures;
+    if let (Some(zip_output), Some(zip_path)) = (zip_output, &args.output_zip) {
+        zip_output
+            .writer
+            .finish()
+            .map_err(|e| format!("Failed to write the archive {:?}: {}", zip_path, e))?;
+    }
+
+    if let Some(progress) = &progress {
+        progress.finish_and_clear();
+    }
+    if let (Some(report), Some(report_path)) = (report, &args.report) {
+        report
+            .write(report_path, &summary, start_time.elapsed())
+            .map_err(|e| format!("Failed to write the report {:?}: {}", report_path, e))?;
+    }
+    if let (Some(manifest), Some(manifest_path)) = (manifest, &args.manifest) {
+        manifest
+            .write(manifest_path)
+            .map_err(|e| format!("Failed to write the manifest {:?}: {}", manifest_path, e))?;
+    }
+    if let (Some(failed_list), Some(list_path)) = (failed_list, &args.failed_list) {
+        failed_list
+            .write(list_path)
+            .map_err(|e| format!("Failed to write the failed list {:?}: {}", list_path, e))?;
+    }
+    if let (Some(cache), Some(cache_path), false) = (&batch.cache, &args.cache, args.dry_run) {
+        cache
+            .save()
+            .map_err(|e| format!("Failed to write the cache {:?}: {}", cache_path, e))?;
+    }
+    let elapsed_time = start_time.elapsed();
+    if args.dry_run {
+        info!("Dry run complete in {:.2?}.", elapsed_time);
+        info!(
+            "Would process {} images, skip {} images and fail on {} images.",
+            summary.processed, summary.skipped, summary.failed
+        );
+        return Ok(exit_code(&summary, args.max_failures));
+    }
+    let totals = format!(
+        "Processed {} images, skipped {} images, failed to process {} images.",
+        summary.processed, summary.skipped, summary.failed
+    );
+    let (processed, skipped, failed) = (summary.processed, summary.skipped, summary.failed);
+    let elapsed_ms = elapsed_time.as_secs_f64() * 1000.0;
+    // Like the failures themselves, a summary with failures shows even with -q
+    if summary.failed == 0 {
+        info!("Image cropping complete in {:.2?}.", elapsed_time);
+        info!(processed, skipped, failed, elapsed_ms, "{}", totals);
+    } else {
+        warn!("Image cropping complete in {:.2?}.", elapsed_time);
+        warn!(processed, skipped, failed, elapsed_ms, "{}", totals);
+    }
+    let mut durations = durations.into_inner().unwrap_or_else(|e| e.into_inner());
+    if !durations.is_empty() {
+        durations.sort();
+        let (p50, p95) = (percentile(&durations, 0.5), percentile(&durations, 0.95));
+        info!(
+            p50_ms = p50.as_secs_f64() * 1000.0,
+            p95_ms = p95.as_secs_f64() * 1000.0,
+            "Time per image: {:.2?} median, {:.2?} at the 95th percentile; {:.1} images/s overall.",
+            p50,
+            p95,
+            summary.processed as f64 / elapsed_time.as_secs_f64()
+        );
+    }
+    byte_totals.log();
+
+    if args.watch {
+        info!("Watching for new images; press Ctrl+C to stop.");
+        watch::watch(
+            &batch,
+            |event| report_event(event, verbosity, None, &failed_count),
+            |round| {
+                if let (Some(cache), Some(cache_path)) = (&batch.cache, &args.cache) {
+                    if let Err(e) = cache.save() {
+                        error!("Failed to write the cache {:?}: {}", cache_path, e);
+                    }
+                }
+                info!(
+                    processed = round.processed,
+                    skipped = round.skipped,
+                    failed = round.failed,
+                    "Processed {} new images, skipped {} images, failed to process {} images.",
+                    round.processed,
+                    round.skipped,
+                    round.failed
+                )
+            },
+        )?;
+    }
+
+    Ok(exit_code(&summary, args.max_failures))
+}
+
+/// What decides the outputs' contents, for `--cache`: every option except
+/// those choosing the inputs and the ones about how the run goes, and the
+/// size and modification time of the files the options name, which can be
+/// replaced under the same path. Output paths are checked on their own.
+fn cache_key(args: &Args) -> String {
+    let options = Args {
+        files: Vec::new(),
+        input_dir: Vec::new(),
+        files_from: None,
+        urls: None,
+        max_depth: None,
+        no_recursive: false,
+        follow_links: false,
+        include: Vec::new(),
+        include_regex: None,
+        exclude: Vec::new(),
+        min_width: None,
+        min_height: None,
+        max_width: None,
+        max_height: None,
+        min_bytes: None,
+        max_bytes: None,
+        max_input_pixels: None,
+        max_input_dimension: None,
+        output_dir: None,
+        output_zip: None,
+        ..without_run_options(args)
+    };
+    let files = [&args.watermark, &args.caption_font].into_iter();
+    #[cfg(feature = "faces")]
+    let files = files.chain([&args.face_model]);
+    let stamps: Vec<_> = files
+        .flatten()
+        .map(|path| {
+            let metadata = fs::metadata(path).ok();
+            let modified = metadata.as_ref().and_then(|metadata| metadata.modified().ok());
+            (path, metadata.map(|metadata| metadata.len()), modified)
         })
         .collect();
+    digest(&(options, stamps))
+}
+
+/// What identifies a run, for `--resume`: every option except the ones about
+/// how the run goes.
+fn run_key(args: &Args) -> String {
+    digest(&without_run_options(args))
+}
+
+/// `args` without the options that change how a run goes but not what it
+/// writes. The config file and preset are already merged into the rest.
+fn without_run_options(args: &Args) -> Args {
+    Args {
+        config: None,
+        preset: None,
+        report: None,
+        manifest: None,
+        failed_list: None,
+        cache: None,
+        resume: false,
+        fail_fast: false,
+        retries: 0,
+        timeout: None,
+        max_failures: 0,
+        watch: false,
+        dry_run: false,
+        verbose: 0,
+        quiet: false,
+        log_format: LogFormat::Pretty,
+        instances: 1,
+        max_memory: None,
+        ..args.clone()
+    }
+}
 
-    image_paths.par_iter().for_each(|(input_file_path, output_file_path)| {
-        match crop_image(input_file_path, output_file_path, width, height) {
-            Ok(_) => {
-                println!(
-                    "Cropped: {:?} -> {:?}",
-                    input_file_path, output_file_path
-                );
-                processed_count.fetch_add(1, Ordering::Relaxed);
+fn digest(value: &impl fmt::Debug) -> String {
+    let digest = Sha256::digest(format!("{:?}", value));
+    digest.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+/// Where a run keeps its checkpoint: in its output directory, in the input
+/// directory when cropping in place, next to the archive it writes, or in
+/// the state folder when its outputs go to a remote folder. Named after the
+/// run, so runs sharing a folder each resume their own.
+fn checkpoint_path(args: &Args, run_key: &str) -> PathBuf {
+    let input_dir = args.input_dir.first().cloned();
+    let dir = match (&args.output_dir, &args.output_zip) {
+        _ if args.in_place => {
+            input_dir.or_else(|| args.files.first().and_then(|file| file.parent()).map(Path::to_path_buf))
+        }
+        (_, Some(zip_path)) => zip_path.parent().map(Path::to_path_buf),
+        (Some(dir), None) if !dir.to_string_lossy().contains("://") => Some(dir.clone()),
+        _ => Some(state_dir()),
+    };
+    dir.unwrap_or_default().join(format!(".img-cropper-checkpoint-{}", &run_key[..16]))
+}
+
+/// Where runs keep what they need to know about earlier runs when they have
+/// no local folder of their own: `$XDG_STATE_HOME/img-cropper`, or
+/// `~/.local/state/img-cropper` (`%LOCALAPPDATA%\img-cropper` on Windows).
+fn state_dir() -> PathBuf {
+    let base = std::env::var_os("XDG_STATE_HOME")
+        .or_else(|| std::env::var_os("LOCALAPPDATA"))
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| Path::new(&home).join(".local").join("state")))
+        .unwrap_or_else(std::env::temp_dir);
+    base.join("img-cropper")
+}
+
+/// A scratch folder in the system's temp folder, removed when dropped.
+struct TempDir(PathBuf);
+
+impl TempDir {
+    fn new(purpose: &str) -> TempDir {
+        TempDir(std::env::temp_dir().join(format!("img-cropper-{}-{}", purpose, std::process::id())))
+    }
+
+    /// Named after the run `run_key` identifies instead of the process, so
+    /// every run with the same options uses the same folder.
+    fn for_run(purpose: &str, run_key: &str) -> TempDir {
+        TempDir(std::env::temp_dir().join(format!("img-cropper-{}-{}", purpose, &run_key[..16])))
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = fs::remove_dir_all(&self.0);
+    }
+}
+
+/// The archive of `--output-zip`, and the scratch folder each output is
+/// written to before it's added.
+struct ZipOutput {
+    writer: ArchiveWriter,
+    dir: TempDir,
+}
+
+impl ZipOutput {
+    fn new(zip_path: &Path) -> Result<ZipOutput, img_cropper_rust::CropError> {
+        Ok(ZipOutput {
+            writer: ArchiveWriter::create(zip_path)?,
+            dir: TempDir::new("zip"),
+        })
+    }
+
+    /// Moves `outputs` into the archive. Returns whether all of them made it.
+    fn add(&self, outputs: &[OutputFile]) -> bool {
+        deliver(outputs, &self.dir.0, |file, name| {
+            self.writer
+                .add(name, file)
+                .map_err(|e| format!("Failed to add {:?} to the archive: {}", name, e).into())
+        })
+    }
+}
+
+/// The remote folder of an `sftp://` or `ftp://` --output-dir, and the
+/// scratch folder each output is written to before it's uploaded.
+#[cfg(feature = "remote")]
+struct RemoteOutput {
+    folder: RemoteFolder,
+    dir: TempDir,
+}
+
+#[cfg(feature = "remote")]
+impl RemoteOutput {
+    /// Signs in to the remote folder and points `args` at a local folder in
+    /// its place.
+    fn new(args: &mut Args) -> Result<Option<RemoteOutput>, img_cropper_rust::CropError> {
+        let Some(output_dir) = args.output_dir.as_mut().filter(|dir| remote::is_remote_path(dir)) else {
+            return Ok(None);
+        };
+        let remote_output = RemoteOutput {
+            folder: RemoteFolder::connect(output_dir)?,
+            dir: TempDir::new("remote"),
+        };
+        info!("Uploading to: {:?}", output_dir);
+        *output_dir = remote_output.dir.0.clone();
+        Ok(Some(remote_output))
+    }
+
+    /// Uploads `outputs`. Returns whether all of them made it.
+    fn upload(&self, outputs: &[OutputFile]) -> bool {
+        deliver(outputs, &self.dir.0, |file, relative| self.folder.upload(file, relative))
+    }
+}
+
+/// The S3 folder of an `s3://` --output-dir, and the scratch folder each
+/// output is written to before it's uploaded.
+#[cfg(feature = "s3")]
+struct S3Output {
+    folder: S3Folder,
+    dir: TempDir,
+}
+
+#[cfg(feature = "s3")]
+impl S3Output {
+    /// Connects to the S3 folder and points `args` at a local folder in its
+    /// place. S3 input folders are read by the batch itself.
+    fn new(args: &mut Args) -> Result<Option<S3Output>, img_cropper_rust::CropError> {
+        let Some(output_dir) = args.output_dir.as_mut().filter(|dir| s3::is_s3_path(dir)) else {
+            return Ok(None);
+        };
+        let s3_output = S3Output {
+            folder: S3Folder::connect(output_dir)?,
+            dir: TempDir::new("s3"),
+        };
+        info!("Uploading to: {:?}", output_dir);
+        *output_dir = s3_output.dir.0.clone();
+        Ok(Some(s3_output))
+    }
+
+    /// Uploads `outputs`. Returns whether all of them made it.
+    fn upload(&self, outputs: &[OutputFile]) -> bool {
+        deliver(outputs, &self.dir.0, |file, relative| self.folder.upload(file, relative))
+    }
+}
+
+/// Hands each of `outputs` to `send` along with its path below `local_dir`,
+/// removing the ones sent and logging the ones that fail. Returns whether all
+/// of them were sent.
+fn deliver(
+    outputs: &[OutputFile],
+    local_dir: &Path,
+    send: impl Fn(&Path, &Path) -> Result<(), img_cropper_rust::CropError>,
+) -> bool {
+    let mut sent = true;
+    for output in outputs {
+        let relative = output.path.strip_prefix(local_dir).unwrap_or(&output.path);
+        match send(&output.path, relative) {
+            Ok(()) => {
+                let _ = fs::remove_file(&output.path);
             }
             Err(e) => {
-                eprintln!(
-                    "Error cropping {:?}: {}",
-                    input_file_path,
-                    e.to_string()
-                );
-                failed_count.fetch_add(1, Ordering::Relaxed);
+                error!("{}", e);
+                sent = false;
             }
         }
-    });
+    }
+    sent
+}
 
-    let elapsed_time = start_time.elapsed();
-    println!("Image cropping complete in {:.2?}.", elapsed_time);
-    println!(
-        "Processed {} images, failed to process {} images.",
-        processed_count.load(Ordering::Relaxed),
-        failed_count.load(Ordering::Relaxed)
+/// Logs a problem with the arguments, for which the tool exits early.
+fn usage_error(message: impl fmt::Display) -> ExitCode {
+    error!("Error: {}", message);
+    ExitCode::from(EXIT_USAGE)
+}
+
+/// The exit code for a finished run.
+fn exit_code(summary: &BatchSummary, max_failures: usize) -> ExitCode {
+    if summary.failed > max_failures {
+        ExitCode::from(EXIT_FAILURES)
+    } else if summary.processed + summary.skipped + summary.failed == 0 {
+        ExitCode::from(EXIT_NO_IMAGES)
+    } else {
+        ExitCode::SUCCESS
+    }
+}
+
+/// Builds the crop settings from the arguments, failing on any that don't
+/// parse.
+fn crop_options(args: &Args) -> Result<CropOptions, String> {
+    let sizes = match &args.size {
+        Some(size_str) => size_str
+            .split(',')
+            .map(|size| parse_size(size.trim()))
+            .collect::<Result<Vec<_>, _>>()?,
+        None => Vec::new(),
+    };
+    let rect = args.rect.as_deref().map(parse_rect).transpose()?;
+    let margins = match (&args.margins, args.trim_percent) {
+        (Some(margins_str), _) => Some(parse_margins(margins_str)?),
+        (None, Some(percent)) => Some(parse_margins(&percent.to_string())?),
+        (None, None) => None,
+    };
+    let focus = match &args.focus {
+        Some(focus_str) => parse_focus(focus_str)?,
+        None => args.gravity.focal_point(),
+    };
+    let ratio = args.ratio.as_deref().map(parse_ratio).transpose()?;
+    let blur_background = args.background == "blur";
+    let background = if blur_background { Rgba([0, 0, 0, 255]) } else { parse_color(&args.background)? };
+    let tiles = match (&args.tiles, &args.tile_size) {
+        (Some(grid_str), _) => {
+            let (columns, rows) = parse_size(grid_str)
+                .map_err(|_| "Invalid tile grid. Please use CxR (e.g., 3x2).".to_string())?;
+            Some(Tiles::Grid(columns, rows))
+        }
+        (None, Some(size_str)) => {
+            let (width, height) = parse_size(size_str)?;
+            Some(Tiles::Size(width, height))
+        }
+        (None, None) => None,
+    };
+    let adjust = match (args.brightness, args.contrast, args.saturation) {
+        (None, None, None) => None,
+        (brightness, contrast, saturation) => Some(effects::Adjust {
+            brightness: brightness.unwrap_or(1.0),
+            contrast: contrast.unwrap_or(1.0),
+            saturation: saturation.unwrap_or(1.0),
+        }),
+    };
+    let border = match args.border {
+        Some(width) => Some(effects::Border {
+            width,
+            color: parse_color(&args.border_color)?,
+            placement: args.border_placement,
+        }),
+        None => None,
+    };
+    let mask = match (args.radius, args.circle) {
+        (_, true) => Some(effects::Mask::Circle),
+        (Some(radius), false) => Some(effects::Mask::Rounded(radius)),
+        (None, false) => None,
+    };
+    if mask.is_some() && args.format == Some(OutputFormat::Jpeg) {
+        return Err("JPEG can't be transparent; use another --format with --radius or --circle.".to_string());
+    }
+    let watermark = match &args.watermark {
+        Some(path) => Some(effects::Watermark {
+            image: Arc::new(
+                image::open(path)
+                    .map_err(|e| format!("Failed to read the watermark {:?}: {}", path, e))?
+                    .into_rgba32f(),
+            ),
+            position: args.watermark_position.focal_point(),
+            opacity: args.watermark_opacity,
+        }),
+        None => None,
+    };
+    let caption = match &args.caption {
+        Some(template) => {
+            let caption = Caption::new(template, args.caption_size, args.caption_position.focal_point());
+            match &args.caption_font {
+                Some(font) => Some(
+                    caption
+                        .with_font(font)
+                        .map_err(|e| format!("Failed to read the font {:?}: {}", font, e))?,
+                ),
+                None => Some(caption),
+            }
+        }
+        None => None,
+    };
+    Ok(CropOptions {
+        sizes: sizes.clone(),
+        mode: args.mode,
+        filter: args.filter.into(),
+        linear: args.linear,
+        sharpen: args.sharpen.map(|amount| effects::Sharpen {
+            amount,
+            radius: args.sharpen_radius,
+            threshold: args.sharpen_threshold,
+        }),
+        no_upscale: args.no_upscale,
+        already_sized: args.skip_already_sized,
+        rotate: args.rotate,
+        flip_h: args.flip_h,
+        flip_v: args.flip_v,
+        auto_enhance: args.auto_enhance,
+        adjust,
+        border,
+        mask,
+        watermark,
+        caption,
+        grayscale: args.grayscale,
+        max_input_pixels: args.max_input_pixels,
+        max_input_dimension: args.max_input_dimension,
+        rect,
+        trim_tolerance: args.trim_borders.then_some(args.trim_tolerance),
+        bar_threshold: args.remove_bars.then_some(args.bar_threshold),
+        margins,
+        ratio,
+        focus,
+        strategy: crop_strategy(args, focus)?,
+        tiles,
+        background,
+        blur_background,
+        encode: encode::EncodeSettings {
+            jpeg_quality: args.jpeg_quality,
+            jpeg_subsampling: args.jpeg_subsampling,
+            jpeg_progressive: args.jpeg_progressive,
+            webp_quality: args.webp_quality,
+            png_compression: args.png_compression.into(),
+            png_filter: args.png_filter.into(),
+            avif_quality: args.avif_quality,
+            avif_speed: args.avif_speed,
+            colors: args.colors,
+            dither: args.dither,
+        },
+        strip_metadata: args.strip_metadata,
+        name_template: args.name_template.clone(),
+        if_exists: args.if_exists,
+        in_place: args.in_place,
+        backup_suffix: args.backup_suffix.clone(),
+        svg_linked_images: true,
+    })
+}
+
+/// File sizes of the cropped images and their outputs, by input and output
+/// format.
+#[derive(Default)]
+struct ByteTotals(Mutex<BTreeMap<(String, String), (u64, u64)>>);
+
+impl ByteTotals {
+    fn add(&self, input: &Path, source_bytes: u64, outputs: &[OutputFile]) {
+        let Some(first) = outputs.first() else {
+            return;
+        };
+        let formats = (lowercase_extension(input), lowercase_extension(&first.path));
+        let mut totals = self.0.lock().unwrap_or_else(|e| e.into_inner());
+        let (bytes_in, bytes_out) = totals.entry(formats).or_default();
+        *bytes_in += source_bytes;
+        *bytes_out += outputs.iter().map(|output| output.bytes).sum::<u64>();
+    }
+
+    fn log(self) {
+        let totals = self.0.into_inner().unwrap_or_else(|e| e.into_inner());
+        if totals.is_empty() {
+            return;
+        }
+        let bytes_in: u64 = totals.values().map(|(bytes_in, _)| bytes_in).sum();
+        let bytes_out: u64 = totals.values().map(|(_, bytes_out)| bytes_out).sum();
+        if bytes_out <= bytes_in {
+            let saved = format_bytes(bytes_in - bytes_out);
+            info!(bytes_in, bytes_out, "Saved {} of {}.", saved, format_bytes(bytes_in));
+        } else {
+            let grown = format_bytes(bytes_out - bytes_in);
+            info!(bytes_in, bytes_out, "Grew by {} from {}.", grown, format_bytes(bytes_in));
+        }
+        for ((input_format, output_format), (bytes_in, bytes_out)) in &totals {
+            let change = (*bytes_out as f64 / (*bytes_in).max(1) as f64 - 1.0) * 100.0;
+            info!(
+                "  {} -> {}: {} -> {} ({:+.1}%)",
+                input_format,
+                output_format,
+                format_bytes(*bytes_in),
+                format_bytes(*bytes_out),
+                change
+            );
+        }
+    }
+}
+
+/// A byte count in binary units, like the ones --min-bytes takes.
+fn format_bytes(bytes: u64) -> String {
+    match bytes {
+        0..1024 => format!("{} bytes", bytes),
+        1024..0x10_0000 => format!("{:.1} KiB", bytes as f64 / 1024.0),
+        0x10_0000..0x4000_0000 => format!("{:.1} MiB", bytes as f64 / 0x10_0000 as f64),
+        _ => format!("{:.1} GiB", bytes as f64 / 0x4000_0000 as f64),
+    }
+}
+
+/// The duration `fraction` of the way through `sorted`, by nearest rank.
+fn percentile(sorted: &[Duration], fraction: f64) -> Duration {
+    sorted[((sorted.len() - 1) as f64 * fraction).round() as usize]
+}
+
+/// Logs the settings of the run before it starts.
+fn print_settings(args: &Args, options: &CropOptions, dimension_filter: &DimensionFilter, output_dir: &Path) {
+    for input_dir in &args.input_dir {
+        info!("Processing images from: {:?}", input_dir);
+    }
+    match args.files.len() {
+        0 => {}
+        1 => info!("Processing image: {:?}", args.files[0]),
+        count => info!("Processing {} image files.", count),
+    }
+    if !args.include.is_empty() {
+        let globs: Vec<_> = args.include.iter().map(Glob::glob).collect();
+        info!("Including files matching: {}", globs.join(", "));
+    }
+    if let Some(regex) = &args.include_regex {
+        info!("Including files matching regex: {}", regex);
+    }
+    if !args.exclude.is_empty() {
+        let globs: Vec<_> = args.exclude.iter().map(Glob::glob).collect();
+        info!("Excluding: {}", globs.join(", "));
+    }
+    if !dimension_filter.is_empty() {
+        let limits: Vec<_> = [
+            ("width >=", args.min_width),
+            ("height >=", args.min_height),
+            ("width <=", args.max_width),
+            ("height <=", args.max_height),
+        ]
+        .iter()
+        .filter_map(|(limit, value)| value.map(|value| format!("{} {}", limit, value)))
+        .collect();
+        info!("Only processing images with {}.", limits.join(", "));
+    }
+    match (args.min_bytes, args.max_bytes) {
+        (Some(min), Some(max)) => info!("Only processing files of {} to {} bytes.", min, max),
+        (Some(min), None) => info!("Only processing files of at least {} bytes.", min),
+        (None, Some(max)) => info!("Only processing files of at most {} bytes.", max),
+        (None, None) => {}
+    }
+    if let Some(rect) = &options.rect {
+        info!("Cropping rectangle: {}x{} at {},{}", rect.width, rect.height, rect.x, rect.y);
+    }
+    if args.trim_borders {
+        info!("Trimming solid borders with tolerance {}.", args.trim_tolerance);
+    }
+    if args.remove_bars {
+        info!("Removing black bars with threshold {}.", args.bar_threshold);
+    }
+    if let Some(margins) = &options.margins {
+        info!(
+            "Trimming margins: {}%,{}%,{}%,{}%",
+            margins.top * 100.0,
+            margins.right * 100.0,
+            margins.bottom * 100.0,
+            margins.left * 100.0
+        );
+    }
+    if args.filter != Filter::Lanczos3 {
+        info!("Resizing with filter: {:?}", args.filter);
+    }
+    if args.linear {
+        info!("Resizing in linear light.");
+    }
+    if let Some(amount) = args.sharpen {
+        info!(
+            "Sharpening with amount {}, radius {}, threshold {}.",
+            amount, args.sharpen_radius, args.sharpen_threshold
+        );
+    }
+    match args.no_upscale {
+        Some(NoUpscale::Skip) => info!("Skipping images smaller than the target size."),
+        Some(NoUpscale::Native) => info!("Keeping images smaller than the target size at native resolution."),
+        None => {}
+    }
+    match args.skip_already_sized {
+        Some(AlreadySized::Copy) => info!("Copying images that already have the target size."),
+        Some(AlreadySized::Skip) => info!("Skipping images that already have the target size."),
+        None => {}
+    }
+    match args.rotate {
+        Some(Rotate::Rotate90) => info!("Rotating images by 90 degrees."),
+        Some(Rotate::Rotate180) => info!("Rotating images by 180 degrees."),
+        Some(Rotate::Rotate270) => info!("Rotating images by 270 degrees."),
+        Some(Rotate::Auto) => info!("Rotating images to match the target orientation."),
+        None => {}
+    }
+    match (args.flip_h, args.flip_v) {
+        (true, true) => info!("Flipping images horizontally and vertically."),
+        (true, false) => info!("Flipping images horizontally."),
+        (false, true) => info!("Flipping images vertically."),
+        (false, false) => {}
+    }
+    if let Some(strength) = args.auto_enhance {
+        info!("Auto-enhancing with strength {}.", strength);
+    }
+    for (name, factor) in [
+        ("Brightness", args.brightness),
+        ("Contrast", args.contrast),
+        ("Saturation", args.saturation),
+    ] {
+        if let Some(factor) = factor {
+            info!("{}: {}", name, factor);
+        }
+    }
+    if let Some(width) = args.border {
+        info!("Border: {}px {} {:?}", width, args.border_color, args.border_placement);
+    }
+    match (args.radius, args.circle) {
+        (_, true) => info!("Cutting outputs to a circle."),
+        (Some(radius), false) => info!("Rounding corners with radius {}px.", radius),
+        (None, false) => {}
+    }
+    if let Some(path) = &args.watermark {
+        info!(
+            "Watermark: {:?} at {:?}, opacity {}",
+            path, args.watermark_position, args.watermark_opacity
+        );
+    }
+    if let Some(template) = &args.caption {
+        info!("Caption: {:?} at {:?}", template, args.caption_position);
+    }
+    if args.grayscale {
+        info!("Converting outputs to grayscale.");
+    }
+    if let Some(max) = args.max_input_pixels {
+        info!("Failing images over {} pixels.", max);
+    }
+    if let Some(max) = args.max_input_dimension {
+        info!("Failing images over {} pixels wide or tall.", max);
+    }
+    if let Some(ratio_str) = &args.ratio {
+        info!("Cropping to aspect ratio: {}", ratio_str);
+    }
+    let size_list = options
+        .sizes
+        .iter()
+        .map(|(width, height)| format!("{}x{}", width, height))
+        .collect::<Vec<_>>()
+        .join(", ");
+    match args.mode {
+        _ if options.sizes.is_empty() => {}
+        Mode::Crop => info!("Cropping to size: {}", size_list),
+        Mode::Pad => info!("Padding to size: {} with background {}", size_list, args.background),
+        Mode::Fit => info!("Fitting inside size: {}", size_list),
+        Mode::SeamCarve => info!("Seam carving to size: {}", size_list),
+    }
+    match &args.focus {
+        _ if args.smart => info!("Using smart crop."),
+        Some(_) => info!("Focal point: {}%,{}%", options.focus.0 * 100.0, options.focus.1 * 100.0),
+        None => info!("Gravity: {:?}", args.gravity),
+    }
+    match options.tiles {
+        Some(Tiles::Grid(columns, rows)) => info!("Splitting into {}x{} tiles.", columns, rows),
+        Some(Tiles::Size(width, height)) => info!("Splitting into {}x{} pixel tiles.", width, height),
+        None => {}
+    }
+    #[cfg(feature = "faces")]
+    if args.faces {
+        info!("Keeping detected faces inside the crop.");
+    }
+    info!("JPEG quality: {}, subsampling: {:?}", args.jpeg_quality, args.jpeg_subsampling);
+    if args.jpeg_progressive {
+        info!("Writing progressive JPEGs");
+    }
+    match args.webp_quality {
+        Some(quality) => info!("WebP quality: {}", quality),
+        None => info!("WebP quality: lossless"),
+    }
+    info!(
+        "PNG compression: {:?}, filter: {:?}",
+        args.png_compression, args.png_filter
     );
+    if let Some(colors) = args.colors {
+        info!("Palette: {} colors{}", colors, if args.dither { ", dithered" } else { "" });
+    }
+    if let Some(format) = args.format {
+        info!("Converting to format: {:?}", format);
+    }
+    if args.format == Some(OutputFormat::Avif) {
+        info!("AVIF quality: {}, speed: {}", args.avif_quality, args.avif_speed);
+    }
+    if args.strip_metadata {
+        info!("Stripping metadata.");
+    }
+    if args.preserve_times {
+        info!("Keeping source file timestamps.");
+    }
+    if args.fail_fast {
+        info!("Stopping at the first failure.");
+    }
+    if args.retries > 0 {
+        info!("Retrying file errors up to {} times.", args.retries);
+    }
+    if let Some(timeout) = args.timeout {
+        info!("Giving up on images after {:?}.", timeout);
+    }
+    if let Some(template) = &args.name_template {
+        info!("Naming outputs: {}", template);
+    }
+    info!("Using {} parallel instances.", args.instances);
+    if let Some(max_memory) = args.max_memory {
+        info!("Keeping the images being cropped under {} MiB.", max_memory >> 20);
+    }
+    if args.dry_run {
+        info!("Dry run: nothing will be decoded or written.");
+    }
+    match (&args.output_zip, &args.backup_suffix) {
+        (Some(zip_path), _) => info!("Saving to the archive: {:?}", zip_path),
+        _ if !args.in_place => info!("Saving to: {:?}", output_dir),
+        (None, Some(suffix)) => info!("Replacing originals, keeping backups with suffix {}", suffix),
+        (None, None) => info!("Replacing originals."),
+    }
+    if let Some(report) = &args.report {
+        info!("Writing report to: {:?}", report);
+    }
+    if let Some(manifest) = &args.manifest {
+        info!("Writing manifest to: {:?}", manifest);
+    }
+    if let Some(failed_list) = &args.failed_list {
+        info!("Listing failed images in: {:?}", failed_list);
+    }
+}
 
-    Ok(())
+/// Faces take priority, falling back to smart crop or the gravity/focus.
+fn crop_strategy(args: &Args, focus: (f64, f64)) -> Result<Option<Arc<dyn CropStrategy>>, String> {
+    let fallback: Box<dyn CropStrategy> = if args.smart {
+        Box::new(strategy::Smart)
+    } else {
+        Box::new(strategy::FocalPoint(focus.0, focus.1))
+    };
+    #[cfg(feature = "faces")]
+    if let (Some(model_path), true) = (&args.face_model, args.faces) {
+        let finder = faces::FaceFinder::load(model_path)
+            .map_err(|e| format!("Failed to load face model {:?}: {}", model_path, e))?;
+        return Ok(Some(Arc::new(strategy::Faces { finder, fallback })));
+    }
+    Ok(args.smart.then(|| fallback.into()))
+}
+
+/// Shows `event` on the progress bar, or logs it when there is none.
+/// Failures and warnings are logged at every verbosity.
+fn report_event(event: Event, verbosity: Verbosity, progress: Option<&ProgressBar>, failed_count: &AtomicUsize) {
+    let Some(progress) = progress else {
+        return print_event(event, verbosity);
+    };
+    match event {
+        Event::Found { total } => progress.set_length(total as u64),
+        Event::SymlinkLoop { .. } | Event::Retrying { .. } => progress.suspend(|| print_event(event, verbosity)),
+        Event::Cropped { .. } | Event::Planned { .. } | Event::Skipped { .. } => progress.inc(1),
+        Event::Failed { .. } => {
+            progress.suspend(|| print_event(event, verbosity));
+            let failed = failed_count.fetch_add(1, Ordering::Relaxed) + 1;
+            progress.set_message(format!("{} failed", failed));
+            progress.inc(1);
+        }
+    }
 }
 
-fn parse_size(size_str: &str) -> Result<(u32, u32), String> {
-    let parts: Vec<&str> = size_str.split('x').collect();
+fn print_event(event: Event, verbosity: Verbosity) {
+    match event {
+        Event::Found { .. } => {}
+        Event::SymlinkLoop { path, ancestor } => {
+            warn!(
+                path = %path.display(),
+                ancestor = %ancestor.display(),
+                "Skipping symlink loop: {:?} points back to {:?}",
+                path,
+                ancestor
+            )
+        }
+        Event::Retrying { input, attempt, error } => {
+            warn!(attempt, error = %error, "Retrying {:?} (attempt {}) after: {}", input, attempt + 1, error)
+        }
+        Event::Cropped { input, source_dimensions, outputs, elapsed, .. } if verbosity == Verbosity::Debug => {
+            let format = image::ImageFormat::from_path(input).map_or("unknown format".to_string(), |format| format!("{:?}", format));
+            let size = source_dimensions.map_or(String::new(), |(width, height)| format!(" {}x{}", width, height));
+            debug!(
+                outputs = outputs.len(),
+                elapsed_ms = elapsed.as_secs_f64() * 1000.0,
+                "Cropped: {:?} ({}{}) -> {} in {:.2?}",
+                input,
+                format,
+                size,
+                match outputs {
+                    [output] => format!("{:?}", output.path),
+                    _ => format!("{} files", outputs.len()),
+                },
+                elapsed
+            )
+        }
+        Event::Cropped { input, outputs: [output], .. } => {
+            debug!(output = %output.path.display(), "Cropped: {:?} -> {:?}", input, output.path)
+        }
+        Event::Cropped { input, outputs, .. } => {
+            debug!(outputs = outputs.len(), "Cropped: {:?} -> {} files", input, outputs.len())
+        }
+        Event::Planned { input, outputs: [output] } => {
+            debug!(output = %output.display(), "Would crop: {:?} -> {:?}", input, output)
+        }
+        Event::Planned { input, outputs } => {
+            let outputs: Vec<_> = outputs.iter().map(|output| format!("{:?}", output)).collect();
+            debug!("Would crop: {:?} -> {}", input, outputs.join(", "))
+        }
+        Event::Skipped { input, reason } => debug!(reason = %reason, "Skipped: {:?} {}", input, reason),
+        Event::Failed { input, error } => error!(error = %error, "Error cropping {:?}: {}", input, error),
+    }
+}
+
+fn parse_focus(focus_str: &str) -> Result<(f64, f64), String> {
+    let parts: Vec<&str> = focus_str.split(',').collect();
     if parts.len() != 2 {
-        return Err("Invalid size format. Please use WxH (e.g., 400x300).".to_string());
+        return Err("Invalid focus format. Please use X%,Y% (e.g., 30,70).".to_string());
+    }
+
+    let mut focus = [0.0; 2];
+    for (value, part) in focus.iter_mut().zip(&parts) {
+        let percent = part
+            .trim()
+            .trim_end_matches('%')
+            .parse::<f64>()
+            .map_err(|_| "Invalid focus. Must be a percentage between 0 and 100.".to_string())?;
+        if !(0.0..=100.0).contains(&percent) {
+            return Err("Focus percentages must be between 0 and 100.".to_string());
+        }
+        *value = percent / 100.0;
+    }
+
+    Ok((focus[0], focus[1]))
+}
+
+fn parse_ratio(ratio_str: &str) -> Result<f64, String> {
+    let parts: Vec<&str> = ratio_str.split(':').collect();
+    if parts.len() != 2 {
+        return Err("Invalid ratio format. Please use W:H (e.g., 16:9).".to_string());
     }
 
     let width = parts[0]
-        .parse::<u32>()
-        .map_err(|_| "Invalid width. Must be a positive integer.".to_string())?;
+        .trim()
+        .parse::<f64>()
+        .map_err(|_| "Invalid ratio width. Must be a positive number.".to_string())?;
     let height = parts[1]
-        .parse::<u32>()
-        .map_err(|_| "Invalid height. Must be a positive integer.".to_string())?;
+        .trim()
+        .parse::<f64>()
+        .map_err(|_| "Invalid ratio height. Must be a positive number.".to_string())?;
 
-    if width == 0 || height == 0 {
-        return Err("Width and height must be positive integers.".to_string());
+    if !(width > 0.0 && height > 0.0 && width.is_finite() && height.is_finite()) {
+        return Err("Ratio width and height must be positive numbers.".to_string());
     }
 
-    Ok((width, height))
+    Ok(width / height)
 }
 
-fn is_supported_image_extension(ext: &str) -> bool {
-    matches!(ext, "jpg" | "jpeg" | "png" | "gif" | "webp")
+fn parse_webp_quality(quality_str: &str) -> Result<f32, String> {
+    let quality = quality_str
+        .parse::<f32>()
+        .map_err(|_| "Invalid WebP quality. Must be a number between 0 and 100.".to_string())?;
+    if !(0.0..=100.0).contains(&quality) {
+        return Err("WebP quality must be between 0 and 100.".to_string());
+    }
+    Ok(quality)
 }
 
-fn crop_image(
-    input_path: &Path,
-    output_path: &Path,
-    target_width: u32,
-    target_height: u32,
-) -> Result<(), Box<dyn std::error::Error>> {
-    use image::{ImageReader, GenericImageView};
-    let img = ImageReader::open(input_path)?
-        .decode()?;
+fn parse_factor(factor_str: &str) -> Result<f32, String> {
+    let factor = factor_str
+        .parse::<f32>()
+        .map_err(|_| "Invalid factor. Must be a number, 1 leaving the image unchanged.".to_string())?;
+    if !(factor >= 0.0 && factor.is_finite()) {
+        return Err("The factor must be 0 or more.".to_string());
+    }
+    Ok(factor)
+}
 
-    let (original_width, original_height) = img.dimensions();
+fn parse_strength(strength_str: &str) -> Result<f32, String> {
+    let strength = strength_str
+        .parse::<f32>()
+        .map_err(|_| "Invalid strength. Must be a number between 0 and 1.".to_string())?;
+    if !(0.0..=1.0).contains(&strength) {
+        return Err("Strength must be between 0 and 1.".to_string());
+    }
+    Ok(strength)
+}
 
-    let img_resized = if original_width * target_height > original_height * target_width {
-        // Original image is wider than the target aspect ratio,
-        // so we resize based on height and then crop width
-        img.resize(
-            (original_width * target_height) / original_height,
-            target_height,
-            image::imageops::FilterType::Lanczos3,
-        )
-    } else {
-        // Original image is taller than or has the same aspect ratio as the target,
-        // so we resize based on width and then crop height
-        img.resize(
-            target_width,
-            (original_height * target_width) / original_width,
-            image::imageops::FilterType::Lanczos3,
+fn parse_opacity(opacity_str: &str) -> Result<f32, String> {
+    let opacity = opacity_str
+        .parse::<f32>()
+        .map_err(|_| "Invalid opacity. Must be a number between 0 and 1.".to_string())?;
+    if !(0.0..=1.0).contains(&opacity) {
+        return Err("Opacity must be between 0 and 1.".to_string());
+    }
+    Ok(opacity)
+}
+
+fn parse_name_template(template: &str) -> Result<String, String> {
+    if template.contains(['/', '\\']) {
+        return Err("The name template must be a file name, without folders.".to_string());
+    }
+    check_placeholders(template, "name template", &naming::PLACEHOLDERS)?;
+    Ok(template.to_string())
+}
+
+fn parse_caption(template: &str) -> Result<String, String> {
+    check_placeholders(template, "caption", &naming::CAPTION_PLACEHOLDERS)?;
+    Ok(template.to_string())
+}
+
+/// Checks that every `{placeholder}` in the `what` template is one of `placeholders`.
+fn check_placeholders(template: &str, what: &str, placeholders: &[&str]) -> Result<(), String> {
+    let mut rest = template;
+    while let Some(start) = rest.find('{') {
+        let end = rest[start..]
+            .find('}')
+            .map(|end| start + end)
+            .ok_or(format!("Unclosed {{ in the {}.", what))?;
+        let placeholder = &rest[start + 1..end];
+        if !placeholders.contains(&placeholder) {
+            return Err(format!(
+                "Unknown placeholder {{{}}} in the {}. Use one of: {{{}}}.",
+                placeholder,
+                what,
+                placeholders.join("}, {")
+            ));
+        }
+        rest = &rest[end + 1..];
+    }
+    Ok(())
+}
+
+fn parse_seconds(seconds_str: &str) -> Result<Duration, String> {
+    let seconds = seconds_str
+        .parse::<f64>()
+        .map_err(|_| "Invalid timeout. Must be a number of seconds.".to_string())?;
+    if !(seconds > 0.0 && seconds.is_finite()) {
+        return Err("The timeout must be a positive number of seconds.".to_string());
+    }
+    Ok(Duration::from_secs_f64(seconds))
+}
+
+/// A byte count with an optional binary K, M or G suffix: `100M` is 100 MiB.
+fn parse_byte_size(size_str: &str) -> Result<u64, String> {
+    let upper = size_str.trim().to_ascii_uppercase();
+    let digits = upper.strip_suffix('B').unwrap_or(&upper);
+    let (digits, multiplier) = match digits.char_indices().last() {
+        Some((i, 'K')) => (&digits[..i], 1 << 10),
+        Some((i, 'M')) => (&digits[..i], 1 << 20),
+        Some((i, 'G')) => (&digits[..i], 1 << 30),
+        _ => (digits, 1),
+    };
+    digits
+        .trim()
+        .parse::<u64>()
+        .ok()
+        .and_then(|count| count.checked_mul(multiplier))
+        .ok_or_else(|| "Invalid file size. Please use a number of bytes, optionally with K, M or G (e.g., 100M).".to_string())
+}
+
+/// A pixel count with an optional decimal K, M or G suffix: `100M` is 100
+/// megapixels.
+fn parse_pixel_count(count_str: &str) -> Result<u64, String> {
+    let upper = count_str.trim().to_ascii_uppercase();
+    let (digits, multiplier) = match upper.char_indices().last() {
+        Some((i, 'K')) => (&upper[..i], 1_000),
+        Some((i, 'M')) => (&upper[..i], 1_000_000),
+        Some((i, 'G')) => (&upper[..i], 1_000_000_000),
+        _ => (upper.as_str(), 1),
+    };
+    digits
+        .trim()
+        .parse::<u64>()
+        .ok()
+        .filter(|&count| count > 0)
+        .and_then(|count| count.checked_mul(multiplier))
+        .ok_or_else(|| {
+            "Invalid pixel count. Please use a positive number, optionally with K, M or G (e.g., 100M).".to_string()
+        })
+}
+
+/// `*` stays within one folder so `photos/*` doesn't match `photos/old/x.jpg`;
+/// `**` crosses folders.
+fn parse_glob(glob_str: &str) -> Result<Glob, String> {
+    GlobBuilder::new(glob_str)
+        .literal_separator(true)
+        .build()
+        .map_err(|e| format!("Invalid glob: {}", e))
+}
+
+fn parse_rect(rect_str: &str) -> Result<Rect, String> {
+    let parts: Vec<&str> = rect_str.split(',').collect();
+    if parts.len() != 3 {
+        return Err("Invalid rectangle format. Please use X,Y,WxH (e.g., 0,40,800x600).".to_string());
+    }
+
+    let x = parts[0]
+        .trim()
+        .parse::<u32>()
+        .map_err(|_| "Invalid rectangle X. Must be a non-negative integer.".to_string())?;
+    let y = parts[1]
+        .trim()
+        .parse::<u32>()
+        .map_err(|_| "Invalid rectangle Y. Must be a non-negative integer.".to_string())?;
+    let (width, height) = parse_size(parts[2].trim())?;
+
+    Ok(Rect { x, y, width, height })
+}
+
+fn parse_margins(margins_str: &str) -> Result<Margins, String> {
+    let mut percents = Vec::new();
+    for part in margins_str.split(',') {
+        let percent = part
+            .trim()
+            .trim_end_matches('%')
+            .parse::<f64>()
+            .map_err(|_| "Invalid margin. Must be a percentage between 0 and 100.".to_string())?;
+        if !(0.0..100.0).contains(&percent) {
+            return Err("Margin percentages must be between 0 and 100.".to_string());
+        }
+        percents.push(percent / 100.0);
+    }
+
+    let margins = match percents[..] {
+        [all] => Margins { top: all, right: all, bottom: all, left: all },
+        [vertical, horizontal] => Margins {
+            top: vertical,
+            right: horizontal,
+            bottom: vertical,
+            left: horizontal,
+        },
+        [top, right, bottom, left] => Margins { top, right, bottom, left },
+        _ => {
+            return Err("Invalid margins format. Please use T,R,B,L (e.g., 5,5,10,10).".to_string())
+        }
+    };
+
+    if margins.top + margins.bottom >= 1.0 || margins.left + margins.right >= 1.0 {
+        return Err("Opposite margins must add up to less than 100%.".to_string());
+    }
+
+    Ok(margins)
+}
+
+fn parse_color(color_str: &str) -> Result<Rgba<u8>, String> {
+    let invalid = || {
+        format!(
+            "Invalid color: {}. Please use #RRGGBB, #RRGGBBAA or \"transparent\".",
+            color_str
         )
     };
 
-    let (resized_width, resized_height) = img_resized.dimensions();
+    match color_str.to_ascii_lowercase().as_str() {
+        "transparent" => return Ok(Rgba([0, 0, 0, 0])),
+        "black" => return Ok(Rgba([0, 0, 0, 255])),
+        "white" => return Ok(Rgba([255, 255, 255, 255])),
+        _ => {}
+    }
+
+    let hex = color_str.strip_prefix('#').unwrap_or(color_str);
+    if (hex.len() != 6 && hex.len() != 8) || !hex.is_ascii() {
+        return Err(invalid());
+    }
+
+    let mut channels = [255u8; 4];
+    for (channel, i) in channels.iter_mut().zip((0..hex.len()).step_by(2)) {
+        *channel = u8::from_str_radix(&hex[i..i + 2], 16).map_err(|_| invalid())?;
+    }
 
-    // Calculate the coordinates for center crop
-    let crop_x = (resized_width.saturating_sub(target_width)) / 2;
-    let crop_y = (resized_height.saturating_sub(target_height)) / 2;
+    Ok(Rgba(channels))
+}
 
-    let cropped_img = img_resized.crop_imm(crop_x, crop_y, target_width, target_height);
+/// Crops one image from a file or stdin and writes it to stdout or the output
+/// directory. Encoders and several decoders need files, so stdin and stdout
+/// go through a temporary directory; an image from stdin is saved there as
+/// `stdin.<ext>`, which names its outputs and fills `{stem}` with "stdin".
+fn crop_stream(args: &Args, options: &CropOptions) -> Result<(), Box<dyn std::error::Error>> {
+    let [input] = args.files.as_slice() else {
+        return Err("Streaming works on exactly one image".into());
+    };
+    if !args.input_dir.is_empty() || args.in_place {
+        return Err("Streaming can't be combined with --input-dir or --in-place".into());
+    }
 
-    // Determine the image format based on the output file extension
-    let format = ImageFormat::from_path(output_path)
-        .unwrap_or(ImageFormat::Png); // Default to PNG if unknown
+    let temp_dir = std::env::temp_dir().join(format!("img-cropper-{}", std::process::id()));
+    fs::create_dir_all(&temp_dir)?;
+    let result = (|| -> Result<(), Box<dyn std::error::Error>> {
+        let input_path = if input == Path::new("-") {
+            let mut data = Vec::new();
+            io::stdin().read_to_end(&mut data)?;
+            let extension = sniff_extension(&data).ok_or("Unrecognized image format on stdin")?;
+            let input_path = temp_dir.join(format!("stdin.{}", extension));
+            fs::write(&input_path, data)?;
+            input_path
+        } else {
+            input.clone()
+        };
 
-    cropped_img.save_with_format(output_path, format)?;
+        let to_stdout = args.output_dir.as_deref().is_none_or(|dir| dir == Path::new("-"));
+        let output_dir = match &args.output_dir {
+            Some(output_dir) if !to_stdout => output_dir.clone(),
+            _ => temp_dir.clone(),
+        };
+        let output_path = output_dir.join(input_path.file_name().unwrap_or_default());
+        let output_path = output_file_path(&input_path, &output_path, args.format, options.mask.is_some());
+        let output_path = match &args.name_template {
+            Some(template) => naming::render(template, &input_path, &input_path, &output_path)?,
+            None => output_path,
+        };
 
-    Ok(())
+        let cropped = crop_image(&input_path, &output_path, options)?;
+        match cropped.outputs.as_slice() {
+            [Output::Written(file)] if to_stdout => {
+                io::copy(&mut File::open(&file.path)?, &mut io::stdout().lock())?;
+            }
+            [] => return Err("The image is smaller than the target size".into()),
+            _ if to_stdout => {
+                return Err("Only a single output can be written to stdout; use --output-dir for several sizes, tiles or pages".into());
+            }
+            _ => {}
+        }
+        Ok(())
+    })();
+    let _ = fs::remove_dir_all(&temp_dir);
+    result
+}
+
+/// Reads newline-separated entries from `list_path`, or from stdin for `-`.
+fn read_list(list_path: &Path) -> io::Result<Vec<String>> {
+    let list = if list_path == Path::new("-") {
+        io::read_to_string(io::stdin())?
+    } else {
+        fs::read_to_string(list_path)?
+    };
+    Ok(list
+        .lines()
+        .map(str::trim_end)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(str::to_string)
+        .collect())
 }
@@ -0,0 +1,93 @@
+//! A CSV manifest of every output written in a run, for auditing results in
+//! a spreadsheet.
+
+use crate::{Event, Rect};
+use std::{
+    fs::File,
+    io::{self, BufWriter, Write},
+    path::Path,
+    sync::Mutex,
+};
+
+const HEADER: &str = "input,output,original_width,original_height,crop_x,crop_y,crop_width,crop_height,\
+output_width,output_height,bytes_in,bytes_out";
+
+/// Collects a row per output from the events of a run.
+#[derive(Default)]
+pub struct Manifest {
+    rows: Mutex<Vec<Row>>,
+}
+
+struct Row {
+    input: String,
+    output: String,
+    original: Option<(u32, u32)>,
+    region: Rect,
+    dimensions: (u32, u32),
+    bytes_in: u64,
+    bytes_out: u64,
+}
+
+impl Manifest {
+    pub fn new() -> Self {
+        Manifest::default()
+    }
+
+    /// Adds a row for every output of a cropped image.
+    pub fn record(&self, event: &Event) {
+        let Event::Cropped { input, source_dimensions, source_bytes, outputs, .. } = event else {
+            return;
+        };
+        let mut rows = self.rows.lock().unwrap_or_else(|e| e.into_inner());
+        rows.extend(outputs.iter().map(|output| Row {
+            input: input.to_string_lossy().into_owned(),
+            output: output.path.to_string_lossy().into_owned(),
+            original: *source_dimensions,
+            region: output.region,
+            dimensions: output.dimensions,
+            bytes_in: *source_bytes,
+            bytes_out: output.bytes,
+        }));
+    }
+
+    /// Writes the manifest to `path`, sorted by input and output path.
+    pub fn write(&self, path: &Path) -> io::Result<()> {
+        let mut rows = self.rows.lock().unwrap_or_else(|e| e.into_inner());
+        rows.sort_by(|a, b| (&a.input, &a.output).cmp(&(&b.input, &b.output)));
+
+        let mut writer = BufWriter::new(File::create(path)?);
+        writeln!(writer, "{}", HEADER)?;
+        for row in rows.iter() {
+            let (original_width, original_height) = match row.original {
+                Some((width, height)) => (width.to_string(), height.to_string()),
+                None => (String::new(), String::new()),
+            };
+            writeln!(
+                writer,
+                "{},{},{},{},{},{},{},{},{},{},{},{}",
+                quote(&row.input),
+                quote(&row.output),
+                original_width,
+                original_height,
+                row.region.x,
+                row.region.y,
+                row.region.width,
+                row.region.height,
+                row.dimensions.0,
+                row.dimensions.1,
+                row.bytes_in,
+                row.bytes_out,
+            )?;
+        }
+        writer.flush()
+    }
+}
+
+/// Quotes a field if it contains a comma, quote or line break.
+fn quote(field: &str) -> String {
+    if field.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", field.replace('"', "\"\""))
+    } else {
+        field.to_string()
+    }
+}
@@ -0,0 +1,151 @@
+//! Holding back images while the ones being cropped would use more memory
+//! than a budget allows, so a few huge photos don't all decode at once.
+
+use crate::{filter, strips, CropOptions};
+use std::{
+    collections::HashMap,
+    fs,
+    path::Path,
+    sync::{Arc, Condvar, Mutex, MutexGuard},
+    thread::{self, ThreadId},
+};
+
+/// The bytes a pixel takes while an image is cropped: an 8-bit RGBA copy as
+/// it's decoded and another as it's cropped and resized.
+const BYTES_PER_PIXEL: u64 = 8;
+
+/// How many decoded bytes each byte of a file whose header can't be read,
+/// such as a camera RAW file, is guessed to become.
+const BYTES_PER_FILE_BYTE: u64 = 8;
+
+/// How many bytes the images being cropped may take between them. Clones
+/// share the same budget.
+#[derive(Clone)]
+pub struct MemoryBudget {
+    limit: u64,
+    state: Arc<(Mutex<Usage>, Condvar)>,
+}
+
+#[derive(Default)]
+struct Usage {
+    reserved: u64,
+    /// How many reservations each thread holds. A holder may be handed
+    /// another image while it waits on work it split off
+    holders: HashMap<ThreadId, usize>,
+}
+
+/// Part of a [`MemoryBudget`] set aside for one image, given back when
+/// dropped.
+pub struct Reservation {
+    budget: MemoryBudget,
+    bytes: u64,
+    holder: ThreadId,
+}
+
+impl MemoryBudget {
+    pub fn new(limit: u64) -> MemoryBudget {
+        MemoryBudget { limit, state: Arc::new((Mutex::new(Usage::default()), Condvar::new())) }
+    }
+
+    /// Waits until `bytes` fit beside what's already reserved and sets them
+    /// aside. An image that needs more than the whole budget waits until
+    /// nothing else is running. A thread already holding a reservation
+    /// doesn't wait, since what it waits on may be its own.
+    pub fn reserve(&self, bytes: u64) -> Reservation {
+        let bytes = bytes.min(self.limit);
+        let holder = thread::current().id();
+        let (_, freed) = &*self.state;
+        let mut usage = self.usage();
+        if !usage.holders.contains_key(&holder) {
+            while usage.reserved > 0 && usage.reserved + bytes > self.limit {
+                usage = freed.wait(usage).unwrap_or_else(|e| e.into_inner());
+            }
+        }
+        usage.reserved += bytes;
+        *usage.holders.entry(holder).or_default() += 1;
+        Reservation { budget: self.clone(), bytes, holder }
+    }
+
+    fn usage(&self) -> MutexGuard<'_, Usage> {
+        self.state.0.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+impl Reservation {
+    /// Makes the calling thread the reservation's holder, such as a thread
+    /// an image is handed to for cropping. The one that held it until now
+    /// waits for its next reservation like any other.
+    pub fn take_over(&mut self) {
+        let holder = thread::current().id();
+        let mut usage = self.budget.usage();
+        release(&mut usage, self.holder);
+        *usage.holders.entry(holder).or_default() += 1;
+        self.holder = holder;
+    }
+}
+
+impl Drop for Reservation {
+    fn drop(&mut self) {
+        let mut usage = self.budget.usage();
+        usage.reserved -= self.bytes;
+        release(&mut usage, self.holder);
+        drop(usage);
+        self.budget.state.1.notify_all();
+    }
+}
+
+/// Counts one reservation fewer for `holder`.
+fn release(usage: &mut Usage, holder: ThreadId) {
+    if let Some(count) = usage.holders.get_mut(&holder) {
+        *count -= 1;
+        if *count == 0 {
+            usage.holders.remove(&holder);
+        }
+    }
+}
+
+/// About how many bytes cropping the image at `path` takes, from the size in
+/// its header, or its file size when the header can't be read.
+pub(crate) fn estimate(path: &Path, options: &CropOptions) -> u64 {
+    let Some((width, height)) = filter::read_dimensions(path) else {
+        return fs::metadata(path).map_or(0, |metadata| metadata.len()) * BYTES_PER_FILE_BYTE;
+    };
+    let pixels = width as u64 * height as u64;
+    // Huge images read in strips only ever hold the shrunk copy
+    let shrunk = match strips::shrink_factor(path, options) {
+        Some(factor) => pixels / (factor as u64 * factor as u64),
+        None => pixels,
+    };
+    shrunk * BYTES_PER_PIXEL
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc,
+    };
+
+    #[test]
+    fn handed_over_reservation_is_kept_until_its_new_holder_drops_it() {
+        let budget = MemoryBudget::new(100);
+        let mut first = budget.reserve(80);
+        let (taken_over, taken) = mpsc::channel();
+        let released = Arc::new(AtomicBool::new(false));
+        let cropping = {
+            let released = released.clone();
+            thread::spawn(move || {
+                first.take_over();
+                taken_over.send(()).unwrap();
+                thread::sleep(std::time::Duration::from_millis(50));
+                released.store(true, Ordering::SeqCst);
+            })
+        };
+        taken.recv().unwrap();
+        // This thread no longer holds anything, so it waits for the room
+        let _second = budget.reserve(50);
+        assert!(released.load(Ordering::SeqCst));
+        cropping.join().unwrap();
+    }
+}
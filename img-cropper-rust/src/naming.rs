@@ -0,0 +1,137 @@
+//! Output file names built from `--name-template`.
+
+use sha2::{Digest, Sha256};
+use std::{
+    fs::File,
+    io::{self, BufRead, BufReader},
+    path::{Path, PathBuf},
+};
+
+/// Every placeholder a template may use.
+pub const PLACEHOLDERS: [&str; 7] = ["stem", "ext", "width", "height", "parent", "hash", "exif_date"];
+
+/// Stands in for `{exif_date}` when the source has no capture date.
+const UNKNOWN_DATE: &str = "nodate";
+
+/// Every placeholder a `--caption` may use: the template's, and `{filename}`.
+pub const CAPTION_PLACEHOLDERS: [&str; 8] =
+    ["stem", "ext", "width", "height", "parent", "hash", "exif_date", "filename"];
+
+/// Names `output_path` after `template`, filling in everything that is known
+/// before decoding. `{width}` and `{height}` stay in the name until
+/// [`fill_dimensions`] is called with the size of each written file. A
+/// template without an extension gets the output's. `{hash}` and
+/// `{exif_date}` are read from `source`, the file the image at `input_path`
+/// is read from; they differ for images in archives and S3 folders.
+pub fn render(template: &str, input_path: &Path, source: &Path, output_path: &Path) -> io::Result<PathBuf> {
+    let ext = text(output_path.extension());
+    let mut name = fill_source(template, input_path, source, &ext)?;
+    if Path::new(&name).extension().is_none() && !ext.is_empty() {
+        name = format!("{}.{}", name, ext);
+    }
+
+    Ok(output_path.with_file_name(name))
+}
+
+/// Fills in a caption `template` for the image at `input_path`, read from
+/// `source`, with `{ext}` its own extension. `{width}` and `{height}` are
+/// left for each output.
+pub fn render_caption(template: &str, input_path: &Path, source: &Path) -> io::Result<String> {
+    let template = template.replace("{filename}", &text(input_path.file_name()));
+    fill_source(&template, input_path, source, &text(input_path.extension()))
+}
+
+/// Whether names from `template` need the contents of the source file, not
+/// just its path.
+pub fn reads_source(template: &str) -> bool {
+    template.contains("{hash}") || template.contains("{exif_date}")
+}
+
+fn fill_source(template: &str, input_path: &Path, source: &Path, ext: &str) -> io::Result<String> {
+    let mut filled = template
+        .replace("{stem}", &text(input_path.file_stem()))
+        .replace("{ext}", ext)
+        .replace("{parent}", &text(input_path.parent().and_then(Path::file_name)));
+    if filled.contains("{hash}") {
+        filled = filled.replace("{hash}", &content_hash(source)?);
+    }
+    if filled.contains("{exif_date}") {
+        filled = filled.replace("{exif_date}", &exif_date(source).unwrap_or_else(|| UNKNOWN_DATE.to_string()));
+    }
+    Ok(filled)
+}
+
+fn text(value: Option<&std::ffi::OsStr>) -> String {
+    value.unwrap_or_default().to_string_lossy().into_owned()
+}
+
+/// Whether names from `template` already tell different sizes apart.
+pub fn has_dimensions(template: &str) -> bool {
+    template.contains("{width}") && template.contains("{height}")
+}
+
+/// Fills `{width}` and `{height}` into the file name of `path`.
+pub fn fill_dimensions(path: &Path, (width, height): (u32, u32)) -> PathBuf {
+    let name = path
+        .file_name()
+        .unwrap_or_default()
+        .to_string_lossy()
+        .replace("{width}", &width.to_string())
+        .replace("{height}", &height.to_string());
+    path.with_file_name(name)
+}
+
+/// The first 8 hex digits of the SHA-256 of the file's contents, read a
+/// piece at a time.
+fn content_hash(path: &Path) -> io::Result<String> {
+    let mut reader = BufReader::new(File::open(path)?);
+    let mut hasher = Sha256::new();
+    loop {
+        let piece = reader.fill_buf()?;
+        if piece.is_empty() {
+            break;
+        }
+        hasher.update(piece);
+        let read = piece.len();
+        reader.consume(read);
+    }
+    let digest = hasher.finalize();
+    Ok(digest[..4].iter().map(|byte| format!("{:02x}", byte)).collect())
+}
+
+/// The capture date from the file's EXIF as `YYYYMMDD_HHMMSS`.
+fn exif_date(path: &Path) -> Option<String> {
+    let exif = exif::Reader::new()
+        .read_from_container(&mut BufReader::new(File::open(path).ok()?))
+        .ok()?;
+    let field = exif
+        .get_field(exif::Tag::DateTimeOriginal, exif::In::PRIMARY)
+        .or_else(|| exif.get_field(exif::Tag::DateTime, exif::In::PRIMARY))?;
+    let exif::Value::Ascii(values) = &field.value else {
+        return None;
+    };
+    let date = exif::DateTime::from_ascii(values.first()?).ok()?;
+    Some(format!(
+        "{:04}{:02}{:02}_{:02}{:02}{:02}",
+        date.year, date.month, date.day, date.hour, date.minute, date.second
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn hash_comes_from_the_source_and_the_rest_from_the_input() {
+        let source = std::env::temp_dir().join(format!("img-cropper-naming-{}.jpg", std::process::id()));
+        fs::write(&source, b"abc").unwrap();
+        let input = Path::new("photos.zip/holiday/beach.jpg");
+        let rendered = render("{parent}_{stem}_{hash}", input, &source, Path::new("out/holiday/beach.jpg"));
+        let caption = render_caption("{filename} {exif_date}", input, &source);
+        fs::remove_file(&source).unwrap();
+        // The SHA-256 of "abc" starts with ba7816bf
+        assert_eq!(rendered.unwrap(), Path::new("out/holiday/holiday_beach_ba7816bf.jpg"));
+        assert_eq!(caption.unwrap(), "beach.jpg nodate");
+    }
+}
@@ -0,0 +1,49 @@
+//! PDF page rasterization through pdfium, which is loaded at runtime from
+//! the working directory or the system library path.
+
+use image::DynamicImage;
+use pdfium_render::prelude::*;
+use std::{path::Path, sync::OnceLock};
+
+/// Resolution pages render at unless a target size needs more.
+const MIN_DPI: f32 = 150.0;
+
+/// PDF points per inch.
+const POINTS_PER_INCH: f32 = 72.0;
+
+/// Renders the pages of the PDF at `path` one at a time, each large enough
+/// to cover every target size, and hands each to `on_page` with its index
+/// before rendering the next, so only one page is held at once.
+pub fn rasterize(
+    path: &Path,
+    sizes: &[(u32, u32)],
+    mut on_page: impl FnMut(usize, DynamicImage) -> Result<(), Box<dyn std::error::Error>>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    let document = pdfium()?.load_pdf_from_file(path, None)?;
+    for (index, page) in document.pages().iter().enumerate() {
+        let (width, height) = (page.width().value, page.height().value);
+        let scale = sizes
+            .iter()
+            .map(|&(target_width, target_height)| {
+                (target_width as f32 / width).max(target_height as f32 / height)
+            })
+            .fold(MIN_DPI / POINTS_PER_INCH, f32::max);
+        let config = PdfRenderConfig::new().scale_page_by_factor(scale);
+        on_page(index, page.render_with_config(&config)?.as_image()?)?;
+    }
+    Ok(())
+}
+
+/// pdfium can only be bound once per process, so the binding is shared.
+fn pdfium() -> Result<&'static Pdfium, String> {
+    static PDFIUM: OnceLock<Result<Pdfium, String>> = OnceLock::new();
+    PDFIUM
+        .get_or_init(|| {
+            Pdfium::bind_to_library(Pdfium::pdfium_platform_library_name_at_path("./"))
+                .or_else(|_| Pdfium::bind_to_system_library())
+                .map(Pdfium::new)
+                .map_err(|e| format!("Failed to load the pdfium library: {}", e))
+        })
+        .as_ref()
+        .map_err(Clone::clone)
+}
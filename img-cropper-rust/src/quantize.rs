@@ -0,0 +1,69 @@
+//! Reducing outputs to a palette for `--colors`.
+
+use color_quant::NeuQuant;
+use image::RgbaImage;
+
+/// Trades palette quality for speed; 10 is NeuQuant's usual setting.
+const SAMPLE_FACTOR: i32 = 10;
+
+/// A palette of RGBA colors and each pixel's index into it, row by row.
+pub(crate) struct Indexed {
+    pub palette: Vec<[u8; 4]>,
+    pub indices: Vec<u8>,
+}
+
+/// Picks a palette of `colors` for `img` and maps every pixel to it. Dithering
+/// spreads each pixel's error to its neighbors (Floyd-Steinberg), trading
+/// banding for noise.
+pub(crate) fn quantize(img: &RgbaImage, colors: u16, dither: bool) -> Indexed {
+    // Invisible pixels all look the same, whatever color they were given
+    let mut img = img.clone();
+    for pixel in img.pixels_mut().filter(|pixel| pixel.0[3] == 0) {
+        pixel.0 = [0; 4];
+    }
+    let quant = NeuQuant::new(SAMPLE_FACTOR, colors as usize, img.as_raw());
+    let palette = quant
+        .color_map_rgba()
+        .chunks_exact(4)
+        .map(|color| [color[0], color[1], color[2], color[3]])
+        .collect();
+    let (width, height) = (img.width() as usize, img.height() as usize);
+    let mut indices = Vec::with_capacity(width * height);
+    if !dither {
+        indices.extend(img.pixels().map(|pixel| quant.index_of(&pixel.0) as u8));
+        return Indexed { palette, indices };
+    }
+
+    // The error carried into this row and the next
+    let mut errors = vec![[0.0f32; 4]; width + 2];
+    let mut next_errors = vec![[0.0f32; 4]; width + 2];
+    for y in 0..height {
+        for x in 0..width {
+            let pixel = img.get_pixel(x as u32, y as u32).0;
+            let wanted: [f32; 4] = std::array::from_fn(|c| (pixel[c] as f32 + errors[x + 1][c]).clamp(0.0, 255.0));
+            let index = quant.index_of(&wanted.map(|value| value.round() as u8));
+            indices.push(index as u8);
+            let chosen = quant.lookup(index).unwrap_or_default();
+            for c in 0..4 {
+                let error = wanted[c] - chosen[c] as f32;
+                errors[x + 2][c] += error * 7.0 / 16.0;
+                next_errors[x][c] += error * 3.0 / 16.0;
+                next_errors[x + 1][c] += error * 5.0 / 16.0;
+                next_errors[x + 2][c] += error / 16.0;
+            }
+        }
+        errors = std::mem::replace(&mut next_errors, vec![[0.0; 4]; width + 2]);
+    }
+    Indexed { palette, indices }
+}
+
+/// `img` with every pixel replaced by its palette color, for encoders that
+/// build their own palette from the colors they're given.
+pub(crate) fn reduce(img: &RgbaImage, colors: u16, dither: bool) -> RgbaImage {
+    let Indexed { palette, indices } = quantize(img, colors, dither);
+    let mut reduced = img.clone();
+    for (pixel, index) in reduced.pixels_mut().zip(indices) {
+        pixel.0 = palette[index as usize];
+    }
+    reduced
+}
@@ -0,0 +1,139 @@
+//! Camera RAW decoding with a basic bilinear demosaic.
+
+use crate::linear::linear_to_srgb;
+use image::{metadata::Orientation, DynamicImage, Rgb32FImage};
+use rawloader::{RawImage, RawImageData};
+use rayon::prelude::*;
+use std::path::Path;
+
+/// Linear sRGB to XYZ (D65).
+const SRGB_TO_XYZ: [[f32; 3]; 3] = [
+    [0.412453, 0.357580, 0.180423],
+    [0.212671, 0.715160, 0.072169],
+    [0.019334, 0.119193, 0.950227],
+];
+
+pub fn is_raw_extension(ext: &str) -> bool {
+    matches!(ext, "cr2" | "nef" | "arw" | "dng")
+}
+
+/// Decodes a RAW file into an upright sRGB image, cropped to the sensor's
+/// usable area.
+pub fn decode(path: &Path) -> Result<DynamicImage, Box<dyn std::error::Error>> {
+    let raw = rawloader::decode_file(path)?;
+    let [top, right, bottom, left] = raw.crops;
+    let width = raw.width.saturating_sub(left + right);
+    let height = raw.height.saturating_sub(top + bottom);
+    if width == 0 || height == 0 {
+        return Err(format!("{:?} has no usable image area", path).into());
+    }
+
+    let samples: Vec<f32> = match &raw.data {
+        RawImageData::Integer(data) => data.iter().map(|&v| v as f32).collect(),
+        RawImageData::Float(data) => data.clone(),
+    };
+    let cfa = raw.cropped_cfa();
+    let white_balance = white_balance(&raw);
+    let camera_to_srgb = camera_to_srgb(&raw);
+
+    // Sample value for channel `c` at cropped pixel (x, y), scaled to 0-1
+    let sample = |x: usize, y: usize, c: usize| {
+        let index = ((y + top) * raw.width + x + left) * raw.cpp + c.min(raw.cpp - 1);
+        let color = if raw.cpp == 1 { cfa.color_at(y, x) } else { c };
+        let black = raw.blacklevels[color] as f32;
+        let white = (raw.whitelevels[color] as f32).max(black + 1.0);
+        ((samples[index] - black) / (white - black)).clamp(0.0, 1.0)
+    };
+
+    let mut img = Rgb32FImage::new(width as u32, height as u32);
+    img.par_chunks_mut(width * 3).enumerate().for_each(|(y, row)| {
+        for (x, pixel) in row.chunks_exact_mut(3).enumerate() {
+            let mut camera = if raw.is_monochrome() {
+                let value = sample(x, y, 0);
+                [value; 4]
+            } else if raw.cpp == 1 {
+                demosaic(x, y, width, height, &cfa, &sample)
+            } else {
+                [sample(x, y, 0), sample(x, y, 1), sample(x, y, 2), 0.0]
+            };
+            for (value, wb) in camera.iter_mut().zip(white_balance) {
+                *value = (*value * wb).min(1.0);
+            }
+
+            let rgb = match camera_to_srgb {
+                Some(matrix) if !raw.is_monochrome() => matrix.map(|row| {
+                    row.iter().zip(camera).map(|(m, v)| m * v).sum::<f32>()
+                }),
+                _ => [camera[0], camera[1], camera[2]],
+            };
+            for (out, value) in pixel.iter_mut().zip(rgb) {
+                *out = linear_to_srgb(value.clamp(0.0, 1.0));
+            }
+        }
+    });
+
+    let mut img = DynamicImage::ImageRgb16(DynamicImage::ImageRgb32F(img).into_rgb16());
+    if let Some(orientation) = Orientation::from_exif(raw.orientation.to_u16() as u8) {
+        img.apply_orientation(orientation);
+    }
+    Ok(img)
+}
+
+/// Averages the 3x3 neighborhood of (x, y) per CFA color. A fourth, non-RGB
+/// filter color (e.g. emerald) is kept separate so the color matrix can use it.
+fn demosaic(
+    x: usize,
+    y: usize,
+    width: usize,
+    height: usize,
+    cfa: &rawloader::CFA,
+    sample: &impl Fn(usize, usize, usize) -> f32,
+) -> [f32; 4] {
+    let mut sums = [0.0f32; 4];
+    let mut counts = [0u32; 4];
+    for ny in y.saturating_sub(1)..(y + 2).min(height) {
+        for nx in x.saturating_sub(1)..(x + 2).min(width) {
+            let color = cfa.color_at(ny, nx);
+            sums[color] += sample(nx, ny, 0);
+            counts[color] += 1;
+        }
+    }
+
+    let mut camera = [0.0f32; 4];
+    for color in 0..4 {
+        if counts[color] > 0 {
+            camera[color] = sums[color] / counts[color] as f32;
+        }
+    }
+    // Three-color sensors have no fourth sample; fill it from green
+    if counts[3] == 0 {
+        camera[3] = camera[1];
+    }
+    camera
+}
+
+/// White balance multipliers from the file, normalized so green is 1.
+fn white_balance(raw: &RawImage) -> [f32; 4] {
+    let coeffs = raw.wb_coeffs;
+    if coeffs[..3].iter().any(|c| !c.is_finite() || *c <= 0.0) {
+        return [1.0; 4];
+    }
+    let green = coeffs[1];
+    let fourth = if coeffs[3].is_finite() && coeffs[3] > 0.0 { coeffs[3] } else { green };
+    [coeffs[0] / green, 1.0, coeffs[2] / green, fourth / green]
+}
+
+/// Inverts the camera's sRGB to camera matrix (normalized so white stays
+/// white, like dcraw), or `None` when rawloader has no matrix for the camera.
+fn camera_to_srgb(raw: &RawImage) -> Option<[[f32; 4]; 3]> {
+    if raw.xyz_to_cam.iter().flatten().all(|&v| v == 0.0) {
+        return None;
+    }
+    let mut srgb_to_cam = [[0.0f32; 3]; 4];
+    for (i, row) in srgb_to_cam.iter_mut().enumerate() {
+        for (j, value) in row.iter_mut().enumerate() {
+            *value = (0..3).map(|k| raw.xyz_to_cam[i][k] * SRGB_TO_XYZ[k][j]).sum();
+        }
+    }
+    Some(RawImage::normalized_pseudoinverse(srgb_to_cam))
+}
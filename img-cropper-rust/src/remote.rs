@@ -0,0 +1,232 @@
+//! `sftp://` and `ftp://` output folders, given as
+//! `scheme://[user[:password]@]host[:port]/path`.
+//!
+//! SFTP hosts must be in `~/.ssh/known_hosts`. Without a password in the
+//! URL, SFTP signs in with the SSH agent or the default keys in `~/.ssh`,
+//! and FTP signs in anonymously.
+
+use crate::CropError;
+use percent_encoding::percent_decode_str;
+use ssh2::{CheckResult, KnownHostFileKind, Session, Sftp};
+use std::{
+    fs::File,
+    io,
+    net::TcpStream,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+use suppaftp::{types::FileType, FtpStream};
+
+/// Keys tried in order when the SSH agent can't sign in.
+const DEFAULT_KEYS: [&str; 3] = ["id_ed25519", "id_ecdsa", "id_rsa"];
+
+#[derive(Clone, Copy, PartialEq)]
+enum Protocol {
+    Sftp,
+    Ftp,
+}
+
+/// A folder on a remote host. Connections are opened as uploads need them
+/// and reused, so parallel uploads each have one of their own.
+pub struct RemoteFolder {
+    protocol: Protocol,
+    host: String,
+    port: u16,
+    user: Option<String>,
+    password: Option<String>,
+    /// Relative to the home folder on the host, like `scp`, unless the URL
+    /// has a second `/` before it
+    path: PathBuf,
+    idle: Mutex<Vec<Connection>>,
+}
+
+enum Connection {
+    Sftp(Sftp),
+    Ftp(FtpStream),
+}
+
+/// Whether `path` names a remote folder rather than a local one.
+pub fn is_remote_path(path: &Path) -> bool {
+    path.to_str()
+        .is_some_and(|path| path.starts_with("sftp://") || path.starts_with("ftp://"))
+}
+
+impl RemoteFolder {
+    /// Parses `url` and checks that the host can be signed in to.
+    pub fn connect(url: &Path) -> Result<RemoteFolder, CropError> {
+        let invalid = || CropError::Other(format!("Not a valid sftp:// or ftp:// URL: {:?}", url));
+        let url_str = url.to_str().ok_or_else(invalid)?;
+        let (protocol, rest) = match url_str.split_once("://") {
+            Some(("sftp", rest)) => (Protocol::Sftp, rest),
+            Some(("ftp", rest)) => (Protocol::Ftp, rest),
+            _ => return Err(invalid()),
+        };
+        let (authority, path) = rest.split_once('/').unwrap_or((rest, ""));
+        let (user_info, address) = match authority.rsplit_once('@') {
+            Some((user_info, address)) => (Some(user_info), address),
+            None => (None, authority),
+        };
+        let (user, password) = match user_info.map(|user_info| user_info.split_once(':')) {
+            Some(Some((user, password))) => (Some(decode(user)), Some(decode(password))),
+            Some(None) => (user_info.map(decode), None),
+            None => (None, None),
+        };
+        let (host, port) = match address.rsplit_once(':') {
+            Some((host, port)) => (host, port.parse().map_err(|_| invalid())?),
+            None => (address, if protocol == Protocol::Sftp { 22 } else { 21 }),
+        };
+        if host.is_empty() {
+            return Err(invalid());
+        }
+        let folder = RemoteFolder {
+            protocol,
+            host: host.to_string(),
+            port,
+            user,
+            password,
+            path: PathBuf::from(decode(path)),
+            idle: Mutex::new(Vec::new()),
+        };
+        // Fails on bad credentials before anything is cropped
+        let mut connection = folder.open()?;
+        let base_folders: Vec<_> = folder
+            .path
+            .ancestors()
+            .filter(|ancestor| !ancestor.as_os_str().is_empty() && ancestor.parent().is_some())
+            .collect();
+        connection.make_folders(base_folders.into_iter().rev());
+        folder.idle.lock().unwrap_or_else(|e| e.into_inner()).push(connection);
+        Ok(folder)
+    }
+
+    /// Uploads the file at `local` to `relative` below the folder, creating
+    /// any subfolders it needs.
+    pub fn upload(&self, local: &Path, relative: &Path) -> Result<(), CropError> {
+        let connection = self.idle.lock().unwrap_or_else(|e| e.into_inner()).pop();
+        let mut connection = match connection {
+            Some(connection) => connection,
+            None => self.open()?,
+        };
+        let remote_path = self.path.join(relative);
+        let folders: Vec<_> = remote_path
+            .ancestors()
+            .skip(1)
+            .take_while(|folder| *folder != self.path)
+            .collect();
+        connection.make_folders(folders.into_iter().rev());
+        let mut source = File::open(local)?;
+        let result = match &mut connection {
+            Connection::Sftp(sftp) => sftp
+                .create(&remote_path)
+                .map_err(io::Error::from)
+                .and_then(|mut remote| io::copy(&mut source, &mut remote))
+                .map_err(|e| self.error(&remote_path, e)),
+            Connection::Ftp(ftp) => ftp
+                .put_file(remote_path.to_string_lossy(), &mut source)
+                .map_err(|e| self.error(&remote_path, e)),
+        };
+        // A connection that failed may be broken, so only working ones are kept
+        if result.is_ok() {
+            self.idle.lock().unwrap_or_else(|e| e.into_inner()).push(connection);
+        }
+        result.map(|_| ())
+    }
+
+    fn open(&self) -> Result<Connection, CropError> {
+        let address = (self.host.as_str(), self.port);
+        match self.protocol {
+            Protocol::Sftp => {
+                let mut session = Session::new().map_err(io::Error::from)?;
+                session.set_tcp_stream(TcpStream::connect(address).map_err(|e| self.sign_in_error(e))?);
+                session.handshake().map_err(|e| self.sign_in_error(e))?;
+                self.check_host_key(&session)?;
+                let user = self.user.clone().or_else(|| std::env::var("USER").ok()).unwrap_or_default();
+                match &self.password {
+                    Some(password) => session.userauth_password(&user, password).map_err(|e| self.sign_in_error(e))?,
+                    None => sign_in_with_keys(&session, &user),
+                }
+                if !session.authenticated() {
+                    return Err(self.sign_in_error("no SSH key was accepted"));
+                }
+                let sftp = session.sftp().map_err(io::Error::from)?;
+                Ok(Connection::Sftp(sftp))
+            }
+            Protocol::Ftp => {
+                let mut ftp = FtpStream::connect(address).map_err(|e| self.sign_in_error(e))?;
+                let user = self.user.as_deref().unwrap_or("anonymous");
+                ftp.login(user, self.password.as_deref().unwrap_or("anonymous"))
+                    .map_err(|e| self.sign_in_error(e))?;
+                ftp.transfer_type(FileType::Binary).map_err(|e| self.sign_in_error(e))?;
+                Ok(Connection::Ftp(ftp))
+            }
+        }
+    }
+
+    /// Refuses hosts whose key isn't known, as `ssh` would.
+    fn check_host_key(&self, session: &Session) -> Result<(), CropError> {
+        let (key, _) = session
+            .host_key()
+            .ok_or_else(|| self.sign_in_error("the host sent no key"))?;
+        let mut known_hosts = session.known_hosts().map_err(io::Error::from)?;
+        if let Some(home) = std::env::var_os("HOME") {
+            let _ = known_hosts.read_file(&Path::new(&home).join(".ssh/known_hosts"), KnownHostFileKind::OpenSSH);
+        }
+        match known_hosts.check_port(&self.host, self.port, key) {
+            CheckResult::Match => Ok(()),
+            CheckResult::Mismatch => Err(self.sign_in_error("the host key doesn't match ~/.ssh/known_hosts")),
+            _ => Err(self.sign_in_error("the host isn't in ~/.ssh/known_hosts")),
+        }
+    }
+
+    fn sign_in_error(&self, error: impl std::fmt::Display) -> CropError {
+        CropError::Other(format!("Failed to sign in to {}:{}: {}", self.host, self.port, error))
+    }
+
+    fn error(&self, remote_path: &Path, error: impl std::fmt::Display) -> CropError {
+        CropError::Other(format!("Failed to upload {:?} to {}: {}", remote_path, self.host, error))
+    }
+}
+
+impl Connection {
+    /// Creates each of `folders`, outermost first so each one's parent
+    /// exists by the time it's created.
+    fn make_folders<'a>(&mut self, folders: impl Iterator<Item = &'a Path>) {
+        for folder in folders {
+            // Fails when it already exists; a missing folder fails the upload anyway
+            let _ = match self {
+                Connection::Sftp(sftp) => sftp.mkdir(folder, 0o755).map_err(|_| ()),
+                Connection::Ftp(ftp) => ftp.mkdir(folder.to_string_lossy()).map_err(|_| ()),
+            };
+        }
+    }
+}
+
+impl Drop for RemoteFolder {
+    fn drop(&mut self) {
+        for connection in self.idle.get_mut().unwrap_or_else(|e| e.into_inner()).iter_mut() {
+            if let Connection::Ftp(ftp) = connection {
+                let _ = ftp.quit();
+            }
+        }
+    }
+}
+
+/// Tries the SSH agent, then each of the default keys.
+fn sign_in_with_keys(session: &Session, user: &str) {
+    if session.userauth_agent(user).is_ok() {
+        return;
+    }
+    let Some(home) = std::env::var_os("HOME") else {
+        return;
+    };
+    for key in DEFAULT_KEYS {
+        let key_path = Path::new(&home).join(".ssh").join(key);
+        if key_path.exists() && session.userauth_pubkey_file(user, None, &key_path, None).is_ok() {
+            return;
+        }
+    }
+}
+
+fn decode(text: &str) -> String {
+    percent_decode_str(text).decode_utf8_lossy().into_owned()
+}
@@ -0,0 +1,133 @@
+//! A JSON report of every image in a run, for build systems and scripts.
+
+use crate::{BatchSummary, Event};
+use serde::Serialize;
+use std::{
+    fs::File,
+    io::{self, BufWriter, Write},
+    path::Path,
+    sync::Mutex,
+    time::Duration,
+};
+
+/// Collects the outcome of every image from the events of a run.
+#[derive(Default)]
+pub struct Report {
+    files: Mutex<Vec<FileEntry>>,
+}
+
+#[derive(Serialize)]
+struct ReportFile<'a> {
+    processed: usize,
+    skipped: usize,
+    failed: usize,
+    duration_ms: f64,
+    files: &'a [FileEntry],
+}
+
+#[derive(Serialize)]
+struct FileEntry {
+    input: String,
+    status: Status,
+    /// Pixel size of the source image
+    original: Option<Dimensions>,
+    outputs: Vec<OutputEntry>,
+    duration_ms: Option<f64>,
+    /// Why the image was skipped
+    reason: Option<String>,
+    error: Option<String>,
+}
+
+#[derive(Serialize)]
+#[serde(rename_all = "lowercase")]
+enum Status {
+    Cropped,
+    Skipped,
+    Failed,
+}
+
+#[derive(Serialize)]
+struct OutputEntry {
+    path: String,
+    #[serde(flatten)]
+    dimensions: Dimensions,
+}
+
+#[derive(Serialize)]
+struct Dimensions {
+    width: u32,
+    height: u32,
+}
+
+impl From<(u32, u32)> for Dimensions {
+    fn from((width, height): (u32, u32)) -> Self {
+        Dimensions { width, height }
+    }
+}
+
+impl Report {
+    pub fn new() -> Self {
+        Report::default()
+    }
+
+    /// Adds the image `event` is about, if it's about the outcome of one.
+    pub fn record(&self, event: &Event) {
+        let entry = |input: &Path, status| FileEntry {
+            input: input.to_string_lossy().into_owned(),
+            status,
+            original: None,
+            outputs: Vec::new(),
+            duration_ms: None,
+            reason: None,
+            error: None,
+        };
+        let file = match event {
+            Event::Found { .. } | Event::SymlinkLoop { .. } | Event::Retrying { .. } | Event::Planned { .. } => {
+                return
+            }
+            Event::Cropped { input, source_dimensions, outputs, elapsed, .. } => FileEntry {
+                original: source_dimensions.map(Dimensions::from),
+                outputs: outputs
+                    .iter()
+                    .map(|output| OutputEntry {
+                        path: output.path.to_string_lossy().into_owned(),
+                        dimensions: output.dimensions.into(),
+                    })
+                    .collect(),
+                duration_ms: Some(milliseconds(*elapsed)),
+                ..entry(input, Status::Cropped)
+            },
+            Event::Skipped { input, reason } => FileEntry {
+                reason: Some(reason.to_string()),
+                ..entry(input, Status::Skipped)
+            },
+            Event::Failed { input, error } => FileEntry {
+                error: Some(error.to_string()),
+                ..entry(input, Status::Failed)
+            },
+        };
+        self.files.lock().unwrap_or_else(|e| e.into_inner()).push(file);
+    }
+
+    /// Writes the report to `path`, with the files sorted by input path.
+    pub fn write(&self, path: &Path, summary: &BatchSummary, elapsed: Duration) -> io::Result<()> {
+        let mut files = self.files.lock().unwrap_or_else(|e| e.into_inner());
+        files.sort_by(|a, b| a.input.cmp(&b.input));
+        let report = ReportFile {
+            processed: summary.processed,
+            skipped: summary.skipped,
+            failed: summary.failed,
+            duration_ms: milliseconds(elapsed),
+            files: &files,
+        };
+
+        let mut writer = BufWriter::new(File::create(path)?);
+        serde_json::to_writer_pretty(&mut writer, &report)?;
+        writeln!(writer)?;
+        writer.flush()
+    }
+}
+
+fn milliseconds(duration: Duration) -> f64 {
+    duration.as_secs_f64() * 1000.0
+}
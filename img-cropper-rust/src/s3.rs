@@ -0,0 +1,129 @@
+//! `s3://bucket/prefix` input and output folders.
+//!
+//! Input folders are listed up front and each object is downloaded as its
+//! image is cropped, and each output is uploaded as soon as it's written.
+//! The region and credentials come from the usual `AWS_*` environment
+//! variables and profile files; `AWS_ENDPOINT` points at S3-compatible
+//! services instead of AWS.
+
+use crate::{
+    archive::{Listed, LocalFile},
+    is_supported_image_extension, lowercase_extension, CropError,
+};
+use ::s3::{creds::Credentials, Bucket, Region};
+use std::{
+    cell::RefCell,
+    collections::{hash_map::Entry, HashMap},
+    fs,
+    path::{Path, PathBuf},
+};
+
+/// Region used when `AWS_REGION` isn't set.
+const DEFAULT_REGION: &str = "us-east-1";
+
+/// A prefix in a bucket.
+pub struct S3Folder {
+    bucket: Box<Bucket>,
+    prefix: String,
+}
+
+/// Whether `path` names an S3 folder rather than a local one.
+pub fn is_s3_path(path: &Path) -> bool {
+    path.to_str().is_some_and(|path| path.starts_with("s3://"))
+}
+
+impl S3Folder {
+    /// Connects to the folder at `url`, given as `s3://bucket/prefix`.
+    pub fn connect(url: &Path) -> Result<S3Folder, CropError> {
+        let location = url
+            .to_str()
+            .and_then(|url| url.strip_prefix("s3://"))
+            .ok_or_else(|| CropError::Other(format!("Not an S3 path: {:?}", url)))?;
+        let (name, prefix) = location.split_once('/').unwrap_or((location, ""));
+        if name.is_empty() {
+            return Err(CropError::Other(format!("The S3 path {:?} has no bucket", url)));
+        }
+        let region = match Region::from_default_env() {
+            Ok(region) => region,
+            Err(_) => DEFAULT_REGION.parse().map_err(s3_error)?,
+        };
+        let endpoint_set = std::env::var_os("AWS_ENDPOINT").is_some();
+        let credentials = Credentials::default().map_err(s3_error)?;
+        let mut bucket = Bucket::new(name, region, credentials).map_err(s3_error)?;
+        // Self-hosted services rarely have a DNS name per bucket
+        if endpoint_set {
+            bucket = bucket.with_path_style();
+        }
+        let prefix = prefix.trim_end_matches('/');
+        Ok(S3Folder {
+            bucket,
+            prefix: if prefix.is_empty() { String::new() } else { format!("{}/", prefix) },
+        })
+    }
+
+    /// The images under the prefix, relative to it and sorted by name. Keys
+    /// with `..` parts, which would point outside the folder, are left out.
+    pub(crate) fn list_images(&self) -> Result<Vec<(PathBuf, Listed)>, CropError> {
+        let mut images = Vec::new();
+        for page in self.bucket.list(self.prefix.clone(), None).map_err(s3_error)? {
+            images.extend(page.contents.into_iter().filter_map(|object| {
+                let relative = object.key.strip_prefix(&self.prefix)?;
+                let safe = relative.split('/').all(|part| !part.is_empty() && part != "." && part != "..");
+                let relative = PathBuf::from(relative);
+                let checksum = object.e_tag.map(|e_tag| format!("etag:{}", e_tag));
+                let listed = Listed { size: object.size, checksum };
+                (safe && is_supported_image_extension(&lowercase_extension(&relative))).then_some((relative, listed))
+            }));
+        }
+        images.sort_by(|(a, _), (b, _)| a.cmp(b));
+        Ok(images)
+    }
+
+    /// Downloads the object at `relative` below the prefix into a scratch
+    /// folder of its own.
+    pub(crate) fn fetch(&self, relative: &Path) -> Result<LocalFile, CropError> {
+        let key = self.key(relative);
+        let (local, mut file) = LocalFile::create(relative.file_name().unwrap_or_default())?;
+        self.bucket
+            .get_object_to_writer(&key, &mut file)
+            .map_err(|e| CropError::Other(format!("Failed to download s3://{}/{}: {}", self.bucket.name, key, e)))?;
+        Ok(local)
+    }
+
+    /// Uploads the file at `local` to `relative` below the prefix, with the
+    /// content type of its extension.
+    pub fn upload(&self, local: &Path, relative: &Path) -> Result<(), CropError> {
+        let key = self.key(relative);
+        let content_type = image::ImageFormat::from_path(local)
+            .map_or("application/octet-stream", |format| format.to_mime_type());
+        let data = fs::read(local)?;
+        self.bucket
+            .put_object_with_content_type(&key, &data, content_type)
+            .map_err(|e| CropError::Other(format!("Failed to upload s3://{}/{}: {}", self.bucket.name, key, e)))?;
+        Ok(())
+    }
+
+    fn key(&self, relative: &Path) -> String {
+        let parts: Vec<_> = relative.iter().map(|part| part.to_string_lossy()).collect();
+        format!("{}{}", self.prefix, parts.join("/"))
+    }
+}
+
+/// Downloads the image at `relative` in the S3 folder `url`, connecting to
+/// it once per thread.
+pub(crate) fn fetch(url: &Path, relative: &Path) -> Result<LocalFile, CropError> {
+    thread_local! {
+        static FOLDERS: RefCell<HashMap<PathBuf, S3Folder>> = RefCell::new(HashMap::new());
+    }
+    FOLDERS.with_borrow_mut(|folders| {
+        let folder = match folders.entry(url.to_path_buf()) {
+            Entry::Occupied(entry) => entry.into_mut(),
+            Entry::Vacant(entry) => entry.insert(S3Folder::connect(url)?),
+        };
+        folder.fetch(relative)
+    })
+}
+
+fn s3_error(e: impl std::fmt::Display) -> CropError {
+    CropError::Other(format!("S3: {}", e))
+}
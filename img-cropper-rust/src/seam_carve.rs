@@ -0,0 +1,188 @@
+//! Content-aware retargeting by seam carving.
+
+use image::{DynamicImage, GenericImageView, ImageBuffer, Pixel};
+
+/// Shrinks `img` to `target` by repeatedly removing the connected path of
+/// pixels (seam) with the least edge energy, instead of cropping the edges.
+/// `img` must be at least as large as `target` in both dimensions. The
+/// result keeps the source's color type and bit depth.
+pub fn retarget(img: &DynamicImage, target: (u32, u32)) -> DynamicImage {
+    let (width, height) = img.dimensions();
+    let samples = img.to_rgba32f().into_raw();
+    let mut grid = Grid::new(&samples, width as usize, height as usize);
+
+    while grid.width > target.0 as usize {
+        grid.remove_vertical_seam();
+    }
+    if grid.height > target.1 as usize {
+        // Horizontal seams are vertical seams of the transposed image
+        grid = grid.transposed();
+        while grid.width > target.1 as usize {
+            grid.remove_vertical_seam();
+        }
+        grid = grid.transposed();
+    }
+
+    match img {
+        DynamicImage::ImageLuma8(img) => DynamicImage::ImageLuma8(grid.gather(img)),
+        DynamicImage::ImageLumaA8(img) => DynamicImage::ImageLumaA8(grid.gather(img)),
+        DynamicImage::ImageRgb8(img) => DynamicImage::ImageRgb8(grid.gather(img)),
+        DynamicImage::ImageRgba8(img) => DynamicImage::ImageRgba8(grid.gather(img)),
+        DynamicImage::ImageLuma16(img) => DynamicImage::ImageLuma16(grid.gather(img)),
+        DynamicImage::ImageLumaA16(img) => DynamicImage::ImageLumaA16(grid.gather(img)),
+        DynamicImage::ImageRgb16(img) => DynamicImage::ImageRgb16(grid.gather(img)),
+        DynamicImage::ImageRgba16(img) => DynamicImage::ImageRgba16(grid.gather(img)),
+        DynamicImage::ImageRgb32F(img) => DynamicImage::ImageRgb32F(grid.gather(img)),
+        DynamicImage::ImageRgba32F(img) => DynamicImage::ImageRgba32F(grid.gather(img)),
+        img => DynamicImage::ImageRgba8(grid.gather(&img.to_rgba8())),
+    }
+}
+
+/// Row-major grid of the source pixels still left, which can shrink one
+/// column at a time.
+struct Grid<'a> {
+    width: usize,
+    height: usize,
+    /// Where each pixel is in the source, as an index into its rows
+    origin: Vec<u32>,
+    /// Dual-gradient energy of each pixel, kept up to date as seams go
+    energy: Vec<f32>,
+    /// The source as RGBA floats, which energy is measured on
+    samples: &'a [f32],
+}
+
+impl<'a> Grid<'a> {
+    fn new(samples: &'a [f32], width: usize, height: usize) -> Grid<'a> {
+        let origin = (0..(width * height) as u32).collect();
+        Grid::with_origin(samples, width, height, origin)
+    }
+
+    fn with_origin(samples: &'a [f32], width: usize, height: usize, origin: Vec<u32>) -> Grid<'a> {
+        let mut grid = Grid { width, height, origin, energy: vec![0.0; width * height], samples };
+        for y in 0..height {
+            for x in 0..width {
+                grid.energy[y * width + x] = grid.pixel_energy(x, y);
+            }
+        }
+        grid
+    }
+
+    fn sample(&self, x: usize, y: usize) -> &[f32] {
+        let start = self.origin[y * self.width + x] as usize * 4;
+        &self.samples[start..start + 4]
+    }
+
+    fn pixel_energy(&self, x: usize, y: usize) -> f32 {
+        let diff = |a: &[f32], b: &[f32]| a.iter().zip(b).map(|(a, b)| (a - b).abs()).sum::<f32>();
+        let left = self.sample(x.saturating_sub(1), y);
+        let right = self.sample((x + 1).min(self.width - 1), y);
+        let up = self.sample(x, y.saturating_sub(1));
+        let down = self.sample(x, (y + 1).min(self.height - 1));
+        diff(left, right) + diff(up, down)
+    }
+
+    fn remove_vertical_seam(&mut self) {
+        let (width, height) = (self.width, self.height);
+
+        // Cumulative minimum energy of any seam ending at each pixel
+        let mut cost = self.energy.clone();
+        for y in 1..height {
+            for x in 0..width {
+                let above = (x.saturating_sub(1)..=(x + 1).min(width - 1))
+                    .map(|px| cost[(y - 1) * width + px])
+                    .fold(f32::INFINITY, f32::min);
+                cost[y * width + x] += above;
+            }
+        }
+
+        // Walk back up from the cheapest pixel in the bottom row
+        let cheapest = |y: usize, columns: std::ops::RangeInclusive<usize>| {
+            columns.min_by(|&a, &b| cost[y * width + a].total_cmp(&cost[y * width + b])).unwrap_or(0)
+        };
+        let mut seam = vec![0; height];
+        seam[height - 1] = cheapest(height - 1, 0..=width - 1);
+        for y in (0..height - 1).rev() {
+            let below = seam[y + 1];
+            seam[y] = cheapest(y, below.saturating_sub(1)..=(below + 1).min(width - 1));
+        }
+
+        let mut kept = 0;
+        for (y, &seam_x) in seam.iter().enumerate() {
+            let row = y * width;
+            for part in [row..row + seam_x, row + seam_x + 1..row + width] {
+                let len = part.len();
+                self.origin.copy_within(part.clone(), kept);
+                self.energy.copy_within(part, kept);
+                kept += len;
+            }
+        }
+        self.origin.truncate(kept);
+        self.energy.truncate(kept);
+        self.width -= 1;
+
+        // Only the pixels beside the seam, in its row or the ones above and
+        // below, have different neighbours now
+        for y in 0..height {
+            let near = &seam[y.saturating_sub(1)..(y + 2).min(height)];
+            let from = near.iter().min().map_or(0, |&x| x.saturating_sub(1));
+            let to = near.iter().max().map_or(0, |&x| x.min(self.width - 1));
+            for x in from..=to {
+                self.energy[y * self.width + x] = self.pixel_energy(x, y);
+            }
+        }
+    }
+
+    fn transposed(&self) -> Grid<'a> {
+        let mut origin = Vec::with_capacity(self.origin.len());
+        for x in 0..self.width {
+            for y in 0..self.height {
+                origin.push(self.origin[y * self.width + x]);
+            }
+        }
+        Grid::with_origin(self.samples, self.height, self.width, origin)
+    }
+
+    /// The pixels of `source` still in the grid.
+    fn gather<P: Pixel>(&self, source: &ImageBuffer<P, Vec<P::Subpixel>>) -> ImageBuffer<P, Vec<P::Subpixel>> {
+        let source_width = source.width() as usize;
+        ImageBuffer::from_fn(self.width as u32, self.height as u32, |x, y| {
+            let origin = self.origin[y as usize * self.width + x as usize] as usize;
+            *source.get_pixel((origin % source_width) as u32, (origin / source_width) as u32)
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{Rgb, RgbImage};
+
+    fn noise(width: u32, height: u32) -> RgbImage {
+        RgbImage::from_fn(width, height, |x, y| {
+            let value = (x * 7919 + y * 104_729) ^ (x * y * 31);
+            Rgb([value as u8, (value >> 8) as u8, (x * 4 + y) as u8])
+        })
+    }
+
+    #[test]
+    fn energy_kept_up_to_date_matches_energy_measured_again() {
+        let samples = DynamicImage::ImageRgb8(noise(23, 17)).to_rgba32f().into_raw();
+        let mut grid = Grid::new(&samples, 23, 17);
+        for _ in 0..9 {
+            grid.remove_vertical_seam();
+            let fresh = Grid::with_origin(&samples, grid.width, grid.height, grid.origin.clone());
+            assert_eq!(grid.energy, fresh.energy);
+        }
+    }
+
+    #[test]
+    fn keeps_the_bit_depth() {
+        let deep = DynamicImage::ImageRgb8(noise(12, 10)).into_rgb16();
+        let DynamicImage::ImageRgb16(carved) = retarget(&DynamicImage::ImageRgb16(deep.clone()), (8, 7)) else {
+            panic!("16-bit RGB wasn't carved to 16-bit RGB");
+        };
+        assert_eq!(carved.dimensions(), (8, 7));
+        // Every pixel left is one of the source's, untouched
+        assert!(carved.pixels().all(|pixel| deep.pixels().any(|source| source == pixel)));
+    }
+}
@@ -0,0 +1,163 @@
+//! Cropping uploaded images over HTTP, for use as a thumbnailing service.
+
+use crate::{
+    crop_image, output_file_path, parse_size, sniff_extension, CropError, CropOptions, Gravity, Mode, Output,
+    OutputFormat,
+};
+use clap::ValueEnum;
+use std::{
+    fs,
+    io::{Cursor, Read},
+    path::Path,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+};
+use tiny_http::{Header, Method, Request, Response, Server};
+
+/// Largest upload accepted, so a single request can't use up the memory
+const MAX_UPLOAD_BYTES: u64 = 64 << 20;
+
+/// Numbers the temporary folders of requests being handled at once
+static REQUEST_COUNT: AtomicUsize = AtomicUsize::new(0);
+
+type HttpResponse = Response<Cursor<Vec<u8>>>;
+
+/// Serves `POST /crop` on `address` until the process is stopped, handling
+/// requests on the global rayon thread pool. The request body is the image;
+/// the query parameters `size` (`WxH`), `mode`, `gravity` and `format`
+/// override `options` and `format`, and the response is the cropped image.
+pub fn serve(address: &str, options: CropOptions, format: Option<OutputFormat>) -> Result<(), CropError> {
+    let server = Server::http(address).map_err(|e| format!("Failed to listen on {}: {}", address, e))?;
+    // Uploaded SVGs draw nothing from the server's own files
+    let options = Arc::new(CropOptions { svg_linked_images: false, ..options });
+    for request in server.incoming_requests() {
+        let options = Arc::clone(&options);
+        rayon::spawn(move || {
+            let (method, url) = (request.method().clone(), request.url().to_string());
+            let mut request = request;
+            let response = match handle(&mut request, &options, format) {
+                Ok(response) => {
+                    tracing::info!(status = 200, "{} {} -> 200", method, url);
+                    response
+                }
+                Err((status, message)) => {
+                    tracing::warn!(status, "{} {} -> {}: {}", method, url, status, message);
+                    Response::from_string(message + "\n").with_status_code(status)
+                }
+            };
+            let _ = request.respond(response);
+        });
+    }
+    Ok(())
+}
+
+/// Crops the image uploaded with `request`, or gives the status code and
+/// message to respond with instead.
+fn handle(
+    request: &mut Request,
+    options: &CropOptions,
+    format: Option<OutputFormat>,
+) -> Result<HttpResponse, (u16, String)> {
+    let (path, query) = request.url().split_once('?').unwrap_or((request.url(), ""));
+    if path != "/crop" {
+        return Err((404, "Not found; POST images to /crop".to_string()));
+    }
+    if *request.method() != Method::Post {
+        return Err((405, "Use POST to upload the image".to_string()));
+    }
+    let mut options = options.clone();
+    let mut format = format;
+    for (key, value) in query.split('&').filter(|pair| !pair.is_empty()).map(query_pair) {
+        let invalid = |e: String| (400, format!("Invalid {}: {}", key, e));
+        match key.as_str() {
+            "size" => options.sizes = vec![parse_size(&value).map_err(invalid)?],
+            "mode" => options.mode = Mode::from_str(&value, true).map_err(invalid)?,
+            "gravity" => {
+                options.focus = Gravity::from_str(&value, true).map_err(invalid)?.focal_point();
+                options.strategy = None;
+            }
+            "format" => format = Some(OutputFormat::from_str(&value, true).map_err(invalid)?),
+            _ => return Err((400, format!("Unknown parameter {}", key))),
+        }
+    }
+
+    let mut data = Vec::new();
+    let mut upload = request.as_reader().take(MAX_UPLOAD_BYTES + 1);
+    upload.read_to_end(&mut data).map_err(|e| (400, e.to_string()))?;
+    if data.len() as u64 > MAX_UPLOAD_BYTES {
+        return Err((413, format!("Images can be at most {} bytes", MAX_UPLOAD_BYTES)));
+    }
+    let extension = sniff_extension(&data).ok_or((415, "Unrecognized image format".to_string()))?;
+
+    // Encoders and several decoders need files, so each request gets a folder
+    let request_number = REQUEST_COUNT.fetch_add(1, Ordering::Relaxed);
+    let temp_dir = std::env::temp_dir().join(format!("img-cropper-{}-{}", std::process::id(), request_number));
+    let result = crop_upload(&temp_dir, &data, &extension, &options, format);
+    let _ = fs::remove_dir_all(&temp_dir);
+    let (output, extension) = result?;
+
+    let mime_type = image::ImageFormat::from_extension(&extension)
+        .map_or("application/octet-stream", |format| format.to_mime_type());
+    let content_type = Header::from_bytes("Content-Type", mime_type).expect("MIME types are valid header values");
+    Ok(Response::from_data(output).with_header(content_type))
+}
+
+/// Crops `data` in `temp_dir`, returning the output and its extension.
+fn crop_upload(
+    temp_dir: &Path,
+    data: &[u8],
+    extension: &str,
+    options: &CropOptions,
+    format: Option<OutputFormat>,
+) -> Result<(Vec<u8>, String), (u16, String)> {
+    let server_error = |e: std::io::Error| (500, e.to_string());
+    fs::create_dir_all(temp_dir).map_err(server_error)?;
+    let input_path = temp_dir.join(format!("upload.{}", extension));
+    fs::write(&input_path, data).map_err(server_error)?;
+    let output_path = temp_dir.join("output").join(format!("upload.{}", extension));
+    let output_path = output_file_path(&input_path, &output_path, format, options.mask.is_some());
+
+    let cropped = crop_image(&input_path, &output_path, options).map_err(|e| match e {
+        CropError::Io(e) => server_error(e),
+        e => (422, e.to_string()),
+    })?;
+    match cropped.outputs.as_slice() {
+        [Output::Written(file)] => {
+            let output = fs::read(&file.path).map_err(server_error)?;
+            let extension = file.path.extension().unwrap_or_default().to_string_lossy().into_owned();
+            Ok((output, extension))
+        }
+        [] => Err((422, "The image is smaller than the target size".to_string())),
+        _ => Err((422, "The options produce several images; only one can be returned".to_string())),
+    }
+}
+
+/// Splits `key=value`, decoding `+` and `%XX` escapes.
+fn query_pair(pair: &str) -> (String, String) {
+    let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+    (percent_decode(key), percent_decode(value))
+}
+
+fn percent_decode(text: &str) -> String {
+    let bytes = text.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let escaped = bytes
+            .get(i + 1..i + 3)
+            .and_then(|hex| u8::from_str_radix(std::str::from_utf8(hex).ok()?, 16).ok());
+        match (bytes[i], escaped) {
+            (b'%', Some(byte)) => {
+                decoded.push(byte);
+                i += 3;
+                continue;
+            }
+            (b'+', _) => decoded.push(b' '),
+            (byte, _) => decoded.push(byte),
+        }
+        i += 1;
+    }
+    String::from_utf8_lossy(&decoded).into_owned()
+}
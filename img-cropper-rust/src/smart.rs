@@ -0,0 +1,75 @@
+//! Content-aware selection of the crop window.
+
+use image::{DynamicImage, GenericImageView, GrayImage};
+
+/// Longest edge of the downscaled copy used for analysis.
+const ANALYSIS_SIZE: u32 = 256;
+
+/// Finds the focal point (as fractions of the width and height) whose
+/// `target`-sized crop window contains the most edge detail in `img`.
+pub fn find_focal_point(img: &DynamicImage, target: (u32, u32)) -> (f64, f64) {
+    let (width, height) = img.dimensions();
+    let analysis = img.thumbnail(ANALYSIS_SIZE, ANALYSIS_SIZE).to_luma8();
+    let (analysis_width, analysis_height) = analysis.dimensions();
+    let energy = edge_energy(&analysis);
+
+    // Size of the crop window in analysis pixels
+    let window_width = (target.0 as f64 * analysis_width as f64 / width as f64).round() as usize;
+    let window_height = (target.1 as f64 * analysis_height as f64 / height as f64).round() as usize;
+
+    let column_energy: Vec<u64> = (0..analysis_width as usize)
+        .map(|x| {
+            (0..analysis_height as usize)
+                .map(|y| energy[y * analysis_width as usize + x])
+                .sum()
+        })
+        .collect();
+    let row_energy: Vec<u64> = energy
+        .chunks(analysis_width as usize)
+        .map(|row| row.iter().sum())
+        .collect();
+
+    (
+        best_window_center(&column_energy, window_width),
+        best_window_center(&row_energy, window_height),
+    )
+}
+
+/// Gradient magnitude of every pixel, in row-major order.
+fn edge_energy(luma: &GrayImage) -> Vec<u64> {
+    let (width, height) = luma.dimensions();
+    let px = |x: u32, y: u32| luma.get_pixel(x.min(width - 1), y.min(height - 1))[0] as i32;
+
+    let mut energy = Vec::with_capacity((width * height) as usize);
+    for y in 0..height {
+        for x in 0..width {
+            let dx = px(x + 1, y) - px(x.saturating_sub(1), y);
+            let dy = px(x, y + 1) - px(x, y.saturating_sub(1));
+            energy.push((dx.unsigned_abs() + dy.unsigned_abs()) as u64);
+        }
+    }
+    energy
+}
+
+/// Slides a window of `window` entries over `energy` and returns the center
+/// of the window with the highest total, as a fraction of the whole length.
+/// Ties go to the window closest to the middle so flat images stay centered.
+fn best_window_center(energy: &[u64], window: usize) -> f64 {
+    let len = energy.len();
+    if window == 0 || window >= len {
+        return 0.5;
+    }
+
+    let middle = (len - window) as f64 / 2.0;
+    let mut sum: u64 = energy[..window].iter().sum();
+    let mut best = (sum, 0usize);
+    for start in 1..=len - window {
+        sum = sum + energy[start + window - 1] - energy[start - 1];
+        let closer = (start as f64 - middle).abs() < (best.1 as f64 - middle).abs();
+        if sum > best.0 || (sum == best.0 && closer) {
+            best = (sum, start);
+        }
+    }
+
+    (best.1 as f64 + window as f64 / 2.0) / len as f64
+}
@@ -0,0 +1,73 @@
+//! Ways of choosing which part of a resized image the crop keeps.
+
+#[cfg(feature = "faces")]
+use crate::faces::FaceFinder;
+use crate::{smart, Rect};
+use image::{DynamicImage, GenericImageView};
+
+/// Chooses the crop window. `img` has already been resized to cover
+/// `target`, so only one of its sides can be larger than the target. The
+/// pipeline keeps the returned region inside `img` at exactly `target` size.
+pub trait CropStrategy: Send + Sync {
+    fn select_region(&self, img: &DynamicImage, target: (u32, u32)) -> Rect;
+}
+
+/// Centers the window on a fixed point, given as fractions of the width and
+/// height: `FocalPoint(0.5, 0.5)` is a center crop. This is what gravity and
+/// `--focus` use.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct FocalPoint(pub f64, pub f64);
+
+impl CropStrategy for FocalPoint {
+    fn select_region(&self, img: &DynamicImage, target: (u32, u32)) -> Rect {
+        window_around(img, target, (self.0, self.1))
+    }
+}
+
+/// Picks the window containing the most edge detail.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Smart;
+
+impl CropStrategy for Smart {
+    fn select_region(&self, img: &DynamicImage, target: (u32, u32)) -> Rect {
+        window_around(img, target, smart::find_focal_point(img, target))
+    }
+}
+
+/// Centers the window on the detected faces, leaving images without faces
+/// to `fallback`.
+#[cfg(feature = "faces")]
+pub struct Faces {
+    pub finder: FaceFinder,
+    pub fallback: Box<dyn CropStrategy>,
+}
+
+#[cfg(feature = "faces")]
+impl CropStrategy for Faces {
+    fn select_region(&self, img: &DynamicImage, target: (u32, u32)) -> Rect {
+        match self.finder.find_focal_point(img) {
+            Some(focus) => window_around(img, target, focus),
+            None => self.fallback.select_region(img, target),
+        }
+    }
+}
+
+/// The `target`-sized window of `img` centered on `focus` (fractions of the
+/// width and height) as closely as the image bounds allow.
+pub fn window_around(img: &DynamicImage, target: (u32, u32), focus: (f64, f64)) -> Rect {
+    let (width, height) = img.dimensions();
+    Rect {
+        x: crop_offset(width, target.0, focus.0),
+        y: crop_offset(height, target.1, focus.1),
+        width: target.0,
+        height: target.1,
+    }
+}
+
+/// Start of a window of `target` pixels within `resized` pixels, centered on
+/// the fraction `focus` as closely as the image bounds allow.
+fn crop_offset(resized: u32, target: u32, focus: f64) -> u32 {
+    let max_offset = resized.saturating_sub(target);
+    let offset = (resized as f64 * focus - target as f64 / 2.0).floor();
+    offset.clamp(0.0, max_offset as f64) as u32
+}
@@ -0,0 +1,289 @@
+//! Decoding huge PNG and TIFF images a strip of rows at a time, shrinking
+//! each strip as it's read, so a gigapixel scan never has to fit in memory.
+
+use crate::{
+    animation::{self, Frame},
+    encode::Metadata,
+    filter, lowercase_extension, CropOptions,
+};
+use image::{
+    codecs::{png::PngDecoder, tiff::TiffDecoder},
+    DynamicImage, GrayAlphaImage, GrayImage, RgbImage, RgbaImage,
+};
+use std::{fs::File, io::BufReader, path::Path};
+use tiff::{
+    decoder::{Decoder, DecodingResult},
+    tags::Tag,
+};
+
+/// Images over this many pixels are decoded in strips when every output is
+/// smaller: as 8-bit RGBA they'd be over the 512 MiB the image crate allows.
+const STRIP_DECODE_PIXELS: u64 = 1 << 27;
+
+/// How many times over the image at `path` is shrunk as it's decoded, if
+/// it's huge, laid out so it can be read in strips, and every output is
+/// resized from the whole of it. The shrunk image keeps twice the pixels of
+/// the largest target size along its shorter side, so the final resize still
+/// has detail to work with whichever way it ends up turned.
+pub(crate) fn shrink_factor(path: &Path, options: &CropOptions) -> Option<u32> {
+    // A rectangle and tiles are in the source's own pixels
+    if options.sizes.is_empty() || options.rect.is_some() || options.tiles.is_some() {
+        return None;
+    }
+    let extension = lowercase_extension(path);
+    if !matches!(extension.as_str(), "png" | "tif" | "tiff") {
+        return None;
+    }
+    let (width, height) = filter::read_dimensions(path)?;
+    if width as u64 * height as u64 <= STRIP_DECODE_PIXELS {
+        return None;
+    }
+    let largest = options.sizes.iter().map(|&(width, height)| width.max(height)).max()?;
+    let factor = width.min(height) / largest.saturating_mul(2);
+    let readable = match extension.as_str() {
+        "png" => png_reader(path).is_ok_and(|reader| png_channels(&reader).is_some()),
+        _ => tiff_decoder(path).is_ok_and(|mut decoder| tiff_channels(&mut decoder).is_some()),
+    };
+    (factor >= 2 && readable).then_some(factor)
+}
+
+/// Decodes the PNG or TIFF at `path`, which [`shrink_factor`] accepted, as an
+/// upright 8-bit image shrunk `factor` times: each `factor`x`factor` block of
+/// pixels is averaged into one.
+pub(crate) fn decode(path: &Path, factor: u32) -> Result<(Vec<Frame>, Metadata), Box<dyn std::error::Error>> {
+    let (mut image, (metadata, orientation)) = if lowercase_extension(path) == "png" {
+        let image = decode_png(path, factor)?;
+        let mut decoder = PngDecoder::new(BufReader::new(File::open(path)?))?;
+        (image, animation::read_metadata(&mut decoder))
+    } else {
+        let image = decode_tiff(path, factor)?;
+        let mut decoder = TiffDecoder::new(BufReader::new(File::open(path)?))?;
+        (image, animation::read_metadata(&mut decoder))
+    };
+    image.apply_orientation(orientation);
+    Ok((vec![Frame::still(image)], metadata))
+}
+
+fn png_reader(path: &Path) -> Result<png::Reader<BufReader<File>>, Box<dyn std::error::Error>> {
+    let mut decoder = png::Decoder::new(BufReader::new(File::open(path)?));
+    decoder.set_transformations(png::Transformations::normalize_to_color8());
+    Ok(decoder.read_info()?)
+}
+
+/// The channels of each decoded row. `None` for interlaced PNGs, whose rows
+/// arrive in passes over the whole image, and animations, which need every
+/// frame.
+fn png_channels(reader: &png::Reader<BufReader<File>>) -> Option<usize> {
+    let info = reader.info();
+    (!info.interlaced && info.animation_control.is_none()).then(|| reader.output_color_type().0.samples())
+}
+
+fn decode_png(path: &Path, factor: u32) -> Result<DynamicImage, Box<dyn std::error::Error>> {
+    let mut reader = png_reader(path)?;
+    let channels = png_channels(&reader).ok_or("The PNG can't be read in strips")?;
+    let (width, height) = (reader.info().width, reader.info().height);
+    let mut shrinker = Shrinker::new(width, channels, factor);
+    while let Some(row) = reader.next_row()? {
+        shrinker.push_row(row.data());
+    }
+    shrinker.into_image(height)
+}
+
+fn tiff_decoder(path: &Path) -> Result<Decoder<BufReader<File>>, Box<dyn std::error::Error>> {
+    Ok(Decoder::new(BufReader::new(File::open(path)?))?)
+}
+
+/// The channels of each pixel, for 8 and 16-bit gray and RGB images with
+/// all of a pixel's channels stored together.
+fn tiff_channels(decoder: &mut Decoder<BufReader<File>>) -> Option<usize> {
+    let channels = match decoder.colortype().ok()? {
+        tiff::ColorType::Gray(8 | 16) => 1,
+        tiff::ColorType::GrayA(8 | 16) => 2,
+        tiff::ColorType::RGB(8 | 16) => 3,
+        tiff::ColorType::RGBA(8 | 16) => 4,
+        _ => return None,
+    };
+    let planar = decoder.find_tag_unsigned::<u16>(Tag::PlanarConfiguration).ok()? == Some(2);
+    (!planar).then_some(channels)
+}
+
+fn decode_tiff(path: &Path, factor: u32) -> Result<DynamicImage, Box<dyn std::error::Error>> {
+    let mut decoder = tiff_decoder(path)?;
+    let channels = tiff_channels(&mut decoder).ok_or("The TIFF can't be read in strips")?;
+    let (width, height) = decoder.dimensions()?;
+    let (chunk_width, chunk_height) = decoder.chunk_dimensions();
+    let (across, down) = (width.div_ceil(chunk_width), height.div_ceil(chunk_height));
+    let row_len = width as usize * channels;
+    let mut shrinker = Shrinker::new(width, channels, factor);
+    // Strips span the width already; tiles are put side by side into bands
+    for band_index in 0..down {
+        let band_height = decoder.chunk_data_dimensions(band_index * across).1 as usize;
+        let mut band = vec![0; row_len * band_height];
+        for column in 0..across {
+            let index = band_index * across + column;
+            let data_width = decoder.chunk_data_dimensions(index).0 as usize * channels;
+            let samples = match decoder.read_chunk(index)? {
+                DecodingResult::U8(samples) => samples,
+                DecodingResult::U16(samples) => samples.into_iter().map(|sample| (sample >> 8) as u8).collect(),
+                _ => return Err("The TIFF can't be read in strips".into()),
+            };
+            let left = (column * chunk_width) as usize * channels;
+            for (row, chunk_row) in band.chunks_exact_mut(row_len).zip(samples.chunks_exact(data_width)) {
+                row[left..left + data_width].copy_from_slice(chunk_row);
+            }
+        }
+        for row in band.chunks_exact(row_len) {
+            shrinker.push_row(row);
+        }
+    }
+    shrinker.into_image(height)
+}
+
+/// Averages every `factor`x`factor` block of the 8-bit rows it's given into
+/// one pixel, keeping only the sums of the block row being read.
+struct Shrinker {
+    width: usize,
+    channels: usize,
+    factor: usize,
+    sums: Vec<u64>,
+    rows: usize,
+    pixels: Vec<u8>,
+}
+
+impl Shrinker {
+    fn new(width: u32, channels: usize, factor: u32) -> Shrinker {
+        let (width, factor) = (width as usize, factor as usize);
+        Shrinker {
+            width,
+            channels,
+            factor,
+            sums: vec![0; width.div_ceil(factor) * channels],
+            rows: 0,
+            pixels: Vec::new(),
+        }
+    }
+
+    fn push_row(&mut self, row: &[u8]) {
+        for (x, pixel) in row.chunks_exact(self.channels).take(self.width).enumerate() {
+            let sums = &mut self.sums[x / self.factor * self.channels..][..self.channels];
+            for (sum, &sample) in sums.iter_mut().zip(pixel) {
+                *sum += sample as u64;
+            }
+        }
+        self.rows += 1;
+        if self.rows == self.factor {
+            self.flush();
+        }
+    }
+
+    /// Turns the sums into a row of pixels; blocks at the right and bottom
+    /// edges may be smaller.
+    fn flush(&mut self) {
+        for (column, sums) in self.sums.chunks_exact_mut(self.channels).enumerate() {
+            let columns = self.factor.min(self.width - column * self.factor);
+            let count = (columns * self.rows) as u64;
+            for sum in sums {
+                self.pixels.push(((*sum + count / 2) / count) as u8);
+                *sum = 0;
+            }
+        }
+        self.rows = 0;
+    }
+
+    fn into_image(mut self, height: u32) -> Result<DynamicImage, Box<dyn std::error::Error>> {
+        if self.rows > 0 {
+            self.flush();
+        }
+        let width = self.width.div_ceil(self.factor) as u32;
+        let height = height.div_ceil(self.factor as u32);
+        let image = match self.channels {
+            1 => GrayImage::from_raw(width, height, self.pixels).map(DynamicImage::ImageLuma8),
+            2 => GrayAlphaImage::from_raw(width, height, self.pixels).map(DynamicImage::ImageLumaA8),
+            3 => RgbImage::from_raw(width, height, self.pixels).map(DynamicImage::ImageRgb8),
+            _ => RgbaImage::from_raw(width, height, self.pixels).map(DynamicImage::ImageRgba8),
+        };
+        Ok(image.ok_or("The image ended early")?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{imageops::FilterType, Rgb};
+    use std::fs;
+    use tiff::encoder::{colortype, TiffEncoder};
+
+    /// A smooth gradient, over which a box average and a resize agree.
+    fn gradient(width: u32, height: u32) -> RgbImage {
+        RgbImage::from_fn(width, height, |x, y| {
+            Rgb([(x * 255 / (width - 1)) as u8, (y * 255 / (height - 1)) as u8, (x + y) as u8])
+        })
+    }
+
+    /// Every `factor`x`factor` block of the whole image averaged at once.
+    fn box_average(image: &RgbImage, factor: u32) -> RgbImage {
+        let (width, height) = image.dimensions();
+        RgbImage::from_fn(width.div_ceil(factor), height.div_ceil(factor), |column, row| {
+            let (mut sums, mut count) = ([0; 3], 0);
+            for y in row * factor..(row * factor + factor).min(height) {
+                for x in column * factor..(column * factor + factor).min(width) {
+                    for (sum, sample) in sums.iter_mut().zip(image.get_pixel(x, y).0) {
+                        *sum += sample as u32;
+                    }
+                    count += 1;
+                }
+            }
+            Rgb(sums.map(|sum| ((sum + count / 2) / count) as u8))
+        })
+    }
+
+    fn temp_path(extension: &str) -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("img-cropper-strips-{}.{}", std::process::id(), extension))
+    }
+
+    fn write_tiff(path: &Path, image: &RgbImage, rows_per_strip: u32) {
+        let mut encoder = TiffEncoder::new(File::create(path).unwrap()).unwrap();
+        let mut tiff = encoder.new_image::<colortype::RGB8>(image.width(), image.height()).unwrap();
+        tiff.rows_per_strip(rows_per_strip).unwrap();
+        tiff.write_data(image.as_raw()).unwrap();
+    }
+
+    #[test]
+    fn tiff_blocks_span_strips() {
+        // 7-row strips against 4-row blocks, with partial blocks at the right and bottom
+        let image = gradient(61, 46);
+        let path = temp_path("tif");
+        write_tiff(&path, &image, 7);
+        let shrunk = decode_tiff(&path, 4).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(shrunk.to_rgb8(), box_average(&image, 4));
+    }
+
+    #[test]
+    fn png_matches_whole_image() {
+        let image = gradient(61, 46);
+        let path = temp_path("png");
+        image.save(&path).unwrap();
+        let shrunk = decode_png(&path, 3).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(shrunk.to_rgb8(), box_average(&image, 3));
+    }
+
+    #[test]
+    fn matches_whole_image_resize() {
+        let image = gradient(64, 48);
+        let path = temp_path("resize.tif");
+        write_tiff(&path, &image, 5);
+        let shrunk = decode_tiff(&path, 4).unwrap().to_rgb8();
+        fs::remove_file(&path).unwrap();
+        let resized = image::imageops::resize(&image, 16, 12, FilterType::Triangle);
+        let largest_difference = shrunk
+            .as_raw()
+            .iter()
+            .zip(resized.as_raw())
+            .map(|(a, b)| a.abs_diff(*b))
+            .max();
+        // Only the triangle filter's leaning at the edges sets them apart
+        assert!(largest_difference <= Some(3), "{:?}", largest_difference);
+    }
+}
@@ -0,0 +1,74 @@
+//! SVG rasterization, sized for the crop instead of the document's own size.
+
+use image::{DynamicImage, RgbaImage};
+use resvg::{
+    tiny_skia::{Pixmap, Transform},
+    usvg::{fontdb, Options, Tree},
+};
+use std::{
+    fs,
+    path::Path,
+    sync::{Arc, OnceLock},
+};
+
+/// Renders the SVG at `path` large enough to cover every target size, so
+/// the resize afterwards only ever scales down. Small targets still render
+/// at the document size, keeping `--rect` in the SVG's own units. Without
+/// `linked_images`, `<image>` elements only draw `data:` URLs.
+pub fn rasterize(
+    path: &Path,
+    sizes: &[(u32, u32)],
+    linked_images: bool,
+) -> Result<DynamicImage, Box<dyn std::error::Error>> {
+    let mut options = Options {
+        resources_dir: path.parent().map(Path::to_path_buf),
+        fontdb: system_fonts(),
+        ..Options::default()
+    };
+    if !linked_images {
+        options.resources_dir = None;
+        options.image_href_resolver.resolve_string = Box::new(|_, _| None);
+    }
+    let tree = Tree::from_data(&fs::read(path)?, &options)?;
+
+    let size = tree.size();
+    let scale = sizes
+        .iter()
+        .map(|&(width, height)| (width as f32 / size.width()).max(height as f32 / size.height()))
+        .fold(1.0, f32::max);
+    let width = (size.width() * scale).ceil() as u32;
+    let height = (size.height() * scale).ceil() as u32;
+
+    let mut pixmap = Pixmap::new(width, height)
+        .ok_or_else(|| format!("Can't rasterize {:?} at {}x{}", path, width, height))?;
+    resvg::render(&tree, Transform::from_scale(scale, scale), &mut pixmap.as_mut());
+
+    let img = pixmap_image(&pixmap).ok_or("Rasterized SVG has the wrong size")?;
+    Ok(DynamicImage::ImageRgba8(img))
+}
+
+/// Copies a rendered pixmap into an image. tiny-skia renders premultiplied
+/// alpha; the image crate expects straight alpha.
+pub(crate) fn pixmap_image(pixmap: &Pixmap) -> Option<RgbaImage> {
+    let pixels = pixmap
+        .pixels()
+        .iter()
+        .flat_map(|pixel| {
+            let color = pixel.demultiply();
+            [color.red(), color.green(), color.blue(), color.alpha()]
+        })
+        .collect();
+    RgbaImage::from_raw(pixmap.width(), pixmap.height(), pixels)
+}
+
+/// Loading system fonts is slow, so it happens once and is shared by every SVG.
+fn system_fonts() -> Arc<fontdb::Database> {
+    static FONTS: OnceLock<Arc<fontdb::Database>> = OnceLock::new();
+    FONTS
+        .get_or_init(|| {
+            let mut fonts = fontdb::Database::new();
+            fonts.load_system_fonts();
+            Arc::new(fonts)
+        })
+        .clone()
+}
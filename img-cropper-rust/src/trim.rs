@@ -0,0 +1,100 @@
+//! Detection of solid-color borders and black bars around the image content.
+
+use crate::Rect;
+use image::{DynamicImage, GenericImageView};
+use std::{iter::StepBy, ops::Range};
+
+/// Share of a row or column that must be dark for it to count as part of a
+/// letterbox/pillarbox bar, leaving room for compression noise and logos.
+const BAR_DARK_FRACTION: f64 = 0.98;
+
+/// Finds the region left after removing rows and columns along each edge
+/// whose pixels all lie within `tolerance` of the border color, the color
+/// most of the four corners share. Returns `None` when the whole image is a
+/// single border color.
+pub fn find_content_bounds(img: &DynamicImage, tolerance: u8) -> Option<Rect> {
+    let (width, height) = img.dimensions();
+    let rgba = img.to_rgba8();
+    let pixels: &[[u8; 4]] = rgba.as_raw().as_chunks().0;
+    let last = pixels.len().checked_sub(1)?;
+    let corners = [0, width as usize - 1, last + 1 - width as usize, last].map(|corner| pixels[corner]);
+    // A stray corner, such as a logo or a compression artifact, is outvoted;
+    // ties go to the top-left, as the last of the reversed corners
+    let border = corners
+        .into_iter()
+        .rev()
+        .max_by_key(|corner| corners.iter().filter(|other| color_distance(corner, other) <= tolerance).count())
+        .unwrap_or(pixels[0]);
+
+    find_bounds((width, height), |mut line| line.all(|i| color_distance(&pixels[i], &border) <= tolerance))
+}
+
+/// Finds the region left after removing the black letterbox (top/bottom) and
+/// pillarbox (left/right) bars baked into video stills. A row or column is a
+/// bar when nearly all of its pixels have a luma at or below `threshold`.
+/// Returns `None` when the whole image is dark.
+pub fn find_letterbox_bounds(img: &DynamicImage, threshold: u8) -> Option<Rect> {
+    let luma = img.to_luma8();
+    let luma = luma.as_raw();
+
+    find_bounds(img.dimensions(), |line| {
+        let (dark, total) = line.fold((0usize, 0usize), |(dark, total), i| {
+            (dark + (luma[i] <= threshold) as usize, total + 1)
+        });
+        dark as f64 >= total as f64 * BAR_DARK_FRACTION
+    })
+}
+
+/// Shrinks the full image from each edge while `is_border` holds for the
+/// outermost row or column, given as the row-major indices of its pixels.
+fn find_bounds<F>((width, height): (u32, u32), is_border: F) -> Option<Rect>
+where
+    F: Fn(StepBy<Range<usize>>) -> bool,
+{
+    let (w, h) = (width as usize, height as usize);
+    let row = |y: usize| is_border((y * w..(y + 1) * w).step_by(1));
+    let column = |x: usize, top: usize, bottom: usize| is_border((top * w + x..bottom * w).step_by(w));
+
+    let top = (0..h).find(|&y| !row(y))?;
+    let bottom = (top..h).rev().find(|&y| !row(y))? + 1;
+    let left = (0..w).find(|&x| !column(x, top, bottom))?;
+    let right = (left..w).rev().find(|&x| !column(x, top, bottom))? + 1;
+
+    Some(Rect {
+        x: left as u32,
+        y: top as u32,
+        width: (right - left) as u32,
+        height: (bottom - top) as u32,
+    })
+}
+
+/// Largest per-channel difference between two colors.
+fn color_distance(a: &[u8; 4], b: &[u8; 4]) -> u8 {
+    a.iter().zip(b).map(|(a, b)| a.abs_diff(*b)).max().unwrap_or(0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{Rgb, RgbImage};
+
+    #[test]
+    fn stray_corner_doesnt_pick_the_border_color() {
+        // A white 2-pixel border around black content, with a red top-left pixel
+        let mut img = RgbImage::from_fn(10, 8, |x, y| {
+            let inside = (2..8).contains(&x) && (2..6).contains(&y);
+            if inside { Rgb([0, 0, 0]) } else { Rgb([255, 255, 255]) }
+        });
+        img.put_pixel(0, 0, Rgb([255, 0, 0]));
+        let bounds = find_content_bounds(&DynamicImage::ImageRgb8(img), 10).unwrap();
+        // The red pixel keeps the top row and left column, but the rest is trimmed
+        assert_eq!((bounds.x, bounds.y, bounds.width, bounds.height), (0, 0, 8, 6));
+    }
+
+    #[test]
+    fn single_color_has_no_content() {
+        let img = DynamicImage::ImageRgb8(RgbImage::from_pixel(3, 3, Rgb([9, 9, 9])));
+        assert!(find_content_bounds(&img, 0).is_none());
+        assert!(find_letterbox_bounds(&img, 16).is_none());
+    }
+}
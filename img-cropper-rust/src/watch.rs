@@ -0,0 +1,66 @@
+//! Cropping images as they appear in the input directories.
+
+use crate::{BatchProcessor, BatchSummary, CropError, Event};
+use notify::{EventKind, RecursiveMode, Watcher};
+use std::{
+    collections::BTreeSet,
+    path::PathBuf,
+    sync::mpsc::{self, RecvTimeoutError},
+    time::Duration,
+};
+
+/// How long the input directories must be quiet before the changed files
+/// are cropped, so files still being copied in aren't read half written.
+const SETTLE_TIME: Duration = Duration::from_secs(1);
+
+/// Watches the input directories of `batch`, cropping each image that's
+/// added or changed once writing to it has settled; `on_round` gets the
+/// summary of every batch of changes. Returns only if watching fails.
+pub fn watch(
+    batch: &BatchProcessor,
+    on_event: impl Fn(Event) + Sync,
+    on_round: impl Fn(BatchSummary),
+) -> Result<(), CropError> {
+    let (sender, receiver) = mpsc::channel();
+    let mut watcher = notify::recommended_watcher(sender).map_err(watch_error)?;
+    let mode = if batch.max_depth > 1 { RecursiveMode::Recursive } else { RecursiveMode::NonRecursive };
+    for input_dir in &batch.input_dirs {
+        watcher.watch(input_dir, mode).map_err(watch_error)?;
+    }
+    // Outputs written into a watched folder would otherwise be cropped again
+    let output_dir = batch.output_dir.canonicalize().ok();
+
+    loop {
+        let mut changed = BTreeSet::new();
+        let mut next = receiver.recv().map_err(|_| CropError::Other("The file watcher stopped".to_string()))?;
+        loop {
+            let event = next.map_err(watch_error)?;
+            if matches!(event.kind, EventKind::Create(_) | EventKind::Modify(_)) {
+                changed.extend(event.paths);
+            }
+            next = match receiver.recv_timeout(SETTLE_TIME) {
+                Ok(next) => next,
+                Err(RecvTimeoutError::Timeout) => break,
+                Err(RecvTimeoutError::Disconnected) => {
+                    return Err(CropError::Other("The file watcher stopped".to_string()))
+                }
+            };
+        }
+
+        let changed: Vec<PathBuf> = changed
+            .into_iter()
+            .filter(|path| path.is_file())
+            .filter(|path| match (&output_dir, path.canonicalize()) {
+                (Some(output_dir), Ok(path)) => !path.starts_with(output_dir),
+                _ => true,
+            })
+            .collect();
+        if !changed.is_empty() {
+            on_round(batch.run_on(&changed, &on_event)?);
+        }
+    }
+}
+
+fn watch_error(e: notify::Error) -> CropError {
+    CropError::Other(format!("Failed to watch the input directories: {}", e))
+}